@@ -0,0 +1,207 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Error, Result, bail};
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedUserId, UserId},
+};
+use serde::Deserialize;
+
+use crate::store::BannedUsers;
+
+/// Matches Matrix users, either by user ID (`@alice:example.org`) or by the
+/// server they're on (`example.org`). Either form may use `*` as a wildcard,
+/// as in `@*-bot:example.org` or `*.example.org`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Pattern(String);
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.chars().any(char::is_whitespace) {
+            bail!("{:?} is not a user or server pattern", s);
+        }
+
+        if s.starts_with('@') && !s.contains(':') {
+            bail!("{} is missing a server name", s);
+        }
+
+        Ok(Self(s.to_lowercase()))
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Whether `text` matches `pattern`, in which `*` stands for any run of
+/// characters.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+impl Pattern {
+    pub fn matches(&self, user: &UserId) -> bool {
+        match self.0.starts_with('@') {
+            true => glob(&self.0, &user.as_str().to_lowercase()),
+            false => glob(&self.0, &user.server_name().as_str().to_lowercase()),
+        }
+    }
+}
+
+/// Whether any of `patterns` matches `user`.
+pub fn any_match(patterns: &[Pattern], user: &UserId) -> bool {
+    patterns.iter().any(|p| p.matches(user))
+}
+
+/// The users admins have banned from the bot, kept in memory so that every
+/// message can be checked without going to the store.
+#[derive(Clone)]
+pub struct Bans {
+    client: Client,
+    users: Arc<RwLock<HashSet<OwnedUserId>>>,
+    /// Held while the bans are changed, so that two at once can't each save
+    /// the list without the other's change.
+    changing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Bans {
+    pub async fn load(client: Client) -> Result<Self> {
+        let users = BannedUsers::load(&client).await?.users;
+
+        Ok(Self {
+            client,
+            users: Arc::new(RwLock::new(users)),
+            changing: Default::default(),
+        })
+    }
+
+    pub fn contains(&self, user: &UserId) -> bool {
+        self.users.read().unwrap().contains(user)
+    }
+
+    /// Ban `user`, returning whether they weren't already. The ban is saved
+    /// before it takes effect, so it's never lost on a restart.
+    pub async fn ban(&self, user: &UserId) -> Result<bool> {
+        let _changing = self.changing.lock().await;
+        let mut users = self.users.read().unwrap().clone();
+        if !users.insert(user.to_owned()) {
+            return Ok(false);
+        }
+
+        self.save(users).await?;
+        Ok(true)
+    }
+
+    /// Lift the ban on `user`, returning whether they were banned.
+    pub async fn unban(&self, user: &UserId) -> Result<bool> {
+        let _changing = self.changing.lock().await;
+        let mut users = self.users.read().unwrap().clone();
+        if !users.remove(user) {
+            return Ok(false);
+        }
+
+        self.save(users).await?;
+        Ok(true)
+    }
+
+    /// Save `users` to the store and, once that has succeeded, replace the
+    /// bans in memory with them.
+    async fn save(&self, users: HashSet<OwnedUserId>) -> Result<()> {
+        BannedUsers {
+            users: users.clone(),
+        }
+        .save(&self.client)
+        .await?;
+
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::user_id;
+
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn wildcards_match_any_run() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "anything"));
+        assert!(glob("a*c", "ac"));
+        assert!(glob("a*c", "abbbc"));
+        assert!(glob("a*b*c", "a-b-b-c"));
+        assert!(!glob("a*b*c", "a-c-b"));
+    }
+
+    #[test]
+    fn globs_are_anchored() {
+        assert!(glob("example.org", "example.org"));
+        assert!(!glob("example.org", "example.org.evil"));
+        assert!(!glob("example.org", "notexample.org"));
+        assert!(!glob("*.example.org", "example.org"));
+        assert!(!glob("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn user_patterns_match_the_whole_id() {
+        let bots = pattern("@*-bot:example.org");
+
+        assert!(bots.matches(user_id!("@news-bot:example.org")));
+        assert!(!bots.matches(user_id!("@news-bot:example.org.evil")));
+        assert!(!bots.matches(user_id!("@alice:example.org")));
+        assert!(pattern("@Alice:Example.org").matches(user_id!("@alice:example.org")));
+    }
+
+    #[test]
+    fn server_patterns_match_the_server_part() {
+        let subdomains = pattern("*.example.org");
+
+        assert!(subdomains.matches(user_id!("@alice:chat.example.org")));
+        assert!(!subdomains.matches(user_id!("@alice:example.org")));
+        assert!(!subdomains.matches(user_id!("@chat.example.org:evil.org")));
+        assert!(pattern("example.org").matches(user_id!("@bob:example.org")));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        assert!("".parse::<Pattern>().is_err());
+        assert!("@alice".parse::<Pattern>().is_err());
+        assert!("example .org".parse::<Pattern>().is_err());
+    }
+}
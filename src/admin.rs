@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+use matrix_sdk::{
+    Client, Room,
+    ruma::{
+        OwnedRoomId, OwnedUserId, RoomId, UserId, events::room::message::RoomMessageEventContent,
+    },
+};
+
+use reqwest::Url;
+use tokio::sync::{oneshot, watch};
+
+use crate::{Bot, LlamaReq, config::Live, dm, llama, schedule, store::RoomSettings};
+
+const ADMIN_USAGE: &str = "Usage: !llamaadmin [dm <user> <message> | broadcast <message> | \
+     confirm | cancel | schedule <room|here> in|every <duration> <message> | scheduled | \
+     unschedule <id> | maintenance on [message] | maintenance off | model <model> [url] | \
+     unload [<model>] | budget <room|here> [<tokens>|none]]";
+
+const SCHEDULE_USAGE: &str = "Usage: !llamaadmin schedule <room|here> in|every <duration> <message>, \
+     where the duration is a number followed by s, m, h or d";
+
+/// Broadcasts waiting for the admin who drafted them to confirm.
+#[derive(Clone, Default)]
+pub struct Broadcasts(Arc<Mutex<HashMap<OwnedUserId, String>>>);
+
+/// Whether the bot is down for maintenance, and if so, what it tells people
+/// in the meantime.
+///
+/// While maintenance is on, the llama task stops taking requests off the
+/// queue, so anything queued beforehand waits until it is turned off again.
+#[derive(Clone)]
+pub struct Maintenance {
+    tx: Arc<watch::Sender<Option<String>>>,
+    config: Live,
+}
+
+impl Maintenance {
+    pub fn new(config: Live) -> Self {
+        Self {
+            tx: Arc::new(watch::Sender::new(None)),
+            config,
+        }
+    }
+
+    /// The message to answer prompts with, if maintenance is on.
+    pub fn message(&self) -> Option<String> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.tx.subscribe()
+    }
+
+    /// Turn maintenance on with `message`, or the default message if `None`.
+    fn start(&self, message: Option<&str>) {
+        let message = match message {
+            Some(message) => message.to_owned(),
+            None => self.config.get().maintenance_message().to_owned(),
+        };
+
+        info!("Entering maintenance mode");
+        self.tx.send_replace(Some(message));
+    }
+
+    fn stop(&self) {
+        info!("Leaving maintenance mode");
+        self.tx.send_replace(None);
+    }
+}
+
+/// Send `message` to `user` in a direct chat with the bot, starting one if
+/// needed.
+pub async fn send_dm(client: &Client, user: &UserId, message: &str, encrypt: bool) -> Result<()> {
+    let rm = dm::dm_room(client, user, encrypt).await?;
+
+    rm.send(RoomMessageEventContent::text_plain(message))
+        .await?;
+
+    info!("Sent a direct message to {}", user);
+
+    Ok(())
+}
+
+/// Send `message` to every room the bot has joined, returning how many rooms
+/// it reached along with the rooms it failed to send to.
+pub async fn broadcast(client: &Client, message: &str) -> (usize, Vec<(OwnedRoomId, String)>) {
+    let mut sent = 0;
+    let mut failed = Vec::new();
+
+    for rm in client.joined_rooms() {
+        match rm
+            .send(RoomMessageEventContent::notice_plain(message))
+            .await
+        {
+            Ok(_) => sent += 1,
+            Err(e) => {
+                warn!("Failed to broadcast to {}: {}", rm.room_id(), e);
+                failed.push((rm.room_id().to_owned(), e.to_string()));
+            }
+        }
+    }
+
+    info!("Broadcast to {} rooms, {} failed", sent, failed.len());
+
+    (sent, failed)
+}
+
+/// Run an operator command, returning the reply to post.
+pub async fn admin_command(
+    args: &str,
+    sender: &UserId,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+) -> String {
+    let (action, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+    match action {
+        "dm" => {
+            let Some((user, message)) = rest.trim().split_once(char::is_whitespace) else {
+                return ADMIN_USAGE.to_owned();
+            };
+
+            let Ok(user) = UserId::parse(user) else {
+                return format!("{} is not a valid user ID", user);
+            };
+
+            match send_dm(client, &user, message.trim(), bot.encrypt_dms).await {
+                Ok(()) => format!("Sent your message to {}", user),
+                Err(e) => {
+                    error!("Failed to send a direct message to {}: {}", user, e);
+                    format!("Failed to message {}", user)
+                }
+            }
+        }
+        "broadcast" if !rest.trim().is_empty() => {
+            bot.broadcasts
+                .0
+                .lock()
+                .unwrap()
+                .insert(sender.to_owned(), rest.trim().to_owned());
+
+            format!(
+                "This will be sent to all {} rooms I'm in. Reply \"!llamaadmin confirm\" to \
+                 send it, or \"!llamaadmin cancel\".",
+                client.joined_rooms().len()
+            )
+        }
+        "confirm" => {
+            let Some(message) = bot.broadcasts.0.lock().unwrap().remove(sender) else {
+                return "You have no broadcast waiting to be sent".to_owned();
+            };
+
+            let (sent, failed) = broadcast(client, &message).await;
+
+            let mut reply = format!("Broadcast sent to {} rooms", sent);
+
+            if !failed.is_empty() {
+                reply.push_str(&format!(", failed in {}:", failed.len()));
+
+                for (room_id, e) in failed {
+                    reply.push_str(&format!("\n{}: {}", room_id, e));
+                }
+            }
+
+            reply
+        }
+        "cancel" => match bot.broadcasts.0.lock().unwrap().remove(sender) {
+            Some(_) => "Broadcast cancelled".to_owned(),
+            None => "You have no broadcast waiting to be sent".to_owned(),
+        },
+        "schedule" => {
+            let words: Vec<&str> = rest.split_whitespace().collect();
+
+            let [room, when, duration, message @ ..] = words.as_slice() else {
+                return SCHEDULE_USAGE.to_owned();
+            };
+
+            let room_id = match *room {
+                "here" => rm.room_id().to_owned(),
+                room => match RoomId::parse(room) {
+                    Ok(room_id) => room_id,
+                    Err(_) => return format!("{} is not a valid room ID", room),
+                },
+            };
+
+            let recurring = match *when {
+                "in" => false,
+                "every" => true,
+                _ => return SCHEDULE_USAGE.to_owned(),
+            };
+
+            let Some(delay) = schedule::parse_duration(duration) else {
+                return SCHEDULE_USAGE.to_owned();
+            };
+
+            if message.is_empty() {
+                return SCHEDULE_USAGE.to_owned();
+            }
+
+            match bot
+                .scheduler
+                .add(room_id, message.join(" "), delay, recurring)
+                .await
+            {
+                Ok(id) => format!("Scheduled announcement #{}", id),
+                Err(e) => {
+                    error!("Failed to schedule an announcement: {}", e);
+                    "Failed to save the announcement".to_owned()
+                }
+            }
+        }
+        "scheduled" => bot
+            .scheduler
+            .describe()
+            .await
+            .unwrap_or_else(|| "No announcements are scheduled".to_owned()),
+        "unschedule" => {
+            let Ok(id) = rest.trim().trim_start_matches('#').parse() else {
+                return "Usage: !llamaadmin unschedule <id>".to_owned();
+            };
+
+            match bot.scheduler.remove(id).await {
+                Ok(true) => format!("Removed announcement #{}", id),
+                Ok(false) => format!("There is no announcement #{}", id),
+                Err(e) => {
+                    error!("Failed to remove announcement #{}: {}", id, e);
+                    "Failed to save the schedule".to_owned()
+                }
+            }
+        }
+        "maintenance" => {
+            let (toggle, message) = rest
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((rest.trim(), ""));
+
+            match toggle {
+                "on" => {
+                    bot.maintenance
+                        .start(Some(message.trim()).filter(|m| !m.is_empty()));
+                    "Maintenance mode is on, prompts will be turned away".to_owned()
+                }
+                "off" => {
+                    bot.maintenance.stop();
+                    "Maintenance mode is off".to_owned()
+                }
+                _ => "Usage: !llamaadmin maintenance on [message] | off".to_owned(),
+            }
+        }
+        "model" => {
+            let (model, url) = match rest.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [model] => (model.to_string(), None),
+                [model, url] => match Url::parse(url) {
+                    Ok(url) => (model.to_string(), Some(url)),
+                    Err(_) => return "That is not a valid URL".to_owned(),
+                },
+                _ => return "Usage: !llamaadmin model <model> [url]".to_owned(),
+            };
+
+            let (done, switched) = oneshot::channel();
+            let reply = format!("The default model is now {}", model);
+            let pending = format!(
+                "The default model will switch to {} once maintenance mode is off",
+                model
+            );
+
+            bot.queue
+                .send(
+                    LlamaReq::SetDefault {
+                        model: model.clone(),
+                        url: url.clone(),
+                        done,
+                    },
+                    true,
+                )
+                .await;
+
+            // Follow the switch once it's made, for !llamastatus.
+            let defaults = bot.defaults.clone();
+            let switched = tokio::spawn(async move {
+                switched.await?;
+
+                let mut defaults = defaults.write().unwrap();
+                defaults.model = model;
+
+                if let Some(url) = url {
+                    defaults.backend = defaults.backend.with_url(url);
+                }
+
+                Ok::<_, oneshot::error::RecvError>(())
+            });
+
+            // The queue is paused during maintenance, so the switch won't be
+            // made until it's over.
+            if bot.maintenance.message().is_some() {
+                return pending;
+            }
+
+            match switched.await {
+                Ok(Ok(())) => reply,
+                _ => "Failed to switch the default model".to_owned(),
+            }
+        }
+        "unload" => {
+            let defaults = bot.defaults.read().unwrap().clone();
+            let model = match rest.trim() {
+                "" => defaults.model.as_str(),
+                model => model,
+            };
+
+            match defaults.backend.unload(model).await {
+                Ok(()) => {
+                    bot.warmer.unloaded(model);
+                    format!("Unloaded {}, it'll be loaded again when next needed", model)
+                }
+                Err(e) => {
+                    error!("Failed to unload {}: {}", model, e);
+                    format!("Failed to unload {}: {}", model, llama::redact_error(&e))
+                }
+            }
+        }
+        "budget" => budget_command(rest, rm, client, bot).await,
+        _ => ADMIN_USAGE.to_owned(),
+    }
+}
+
+const BUDGET_USAGE: &str = "Usage: !llamaadmin budget <room|here> [<tokens>|none]";
+
+async fn budget_command(args: &str, rm: &Room, client: &Client, bot: &Bot) -> String {
+    let words: Vec<&str> = args.split_whitespace().collect();
+
+    let (room, budget) = match words.as_slice() {
+        [room] => (*room, None),
+        [room, budget] => (*room, Some(*budget)),
+        _ => return BUDGET_USAGE.to_owned(),
+    };
+
+    let room_id = match room {
+        "here" => rm.room_id().to_owned(),
+        room => match RoomId::parse(room) {
+            Ok(room_id) => room_id,
+            Err(_) => return format!("{} is not a valid room ID", room),
+        },
+    };
+
+    let mut settings = match RoomSettings::load(client, &room_id).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", room_id, e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    let Some(budget) = budget else {
+        let used = match bot.budgets.used(&room_id).await {
+            Ok(used) => used,
+            Err(e) => {
+                error!("Failed to load token usage of {}: {}", room_id, e);
+                return "Failed to load the room's token usage".to_owned();
+            }
+        };
+
+        return match settings.token_budget.or(bot.config.get().token_budget) {
+            Some(budget) => format!("{} has used {} of {} tokens today", room_id, used, budget),
+            None => format!("{} has used {} tokens today, with no budget", room_id, used),
+        };
+    };
+
+    settings.token_budget = match budget {
+        "none" => None,
+        n => match n.parse() {
+            Ok(n) => Some(n),
+            Err(_) => return BUDGET_USAGE.to_owned(),
+        },
+    };
+
+    if let Err(e) = settings.save(client, &room_id).await {
+        error!("Failed to save settings for {}: {}", room_id, e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    match settings.token_budget {
+        Some(budget) => format!("{} may now use {} tokens per day", room_id, budget),
+        None => format!("{} now has the default budget", room_id),
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result, bail};
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine};
+use matrix_sdk::{
+    Client, SessionMeta,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{OwnedDeviceId, OwnedUserId, ServerName, UserId},
+};
+use reqwest::{Response, Url};
+use serde::Deserialize;
+use serde_json::json;
+
+/// The login type for users in an application service's namespace.
+const LOGIN_TYPE: &str = "m.login.application_service";
+
+/// A random token for the homeserver and the application service to tell
+/// each other apart by.
+fn token() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Print a registration file, with new tokens, for an application service
+/// whose users are those on `server` whose names start with `prefix`. It
+/// goes in the homeserver's `app_service_config_files`.
+///
+/// The service has no URL, so the homeserver doesn't send it transactions:
+/// each of its users syncs for itself, as any other account does.
+pub fn print_registration(id: &str, prefix: &str, server: &ServerName) -> Result<()> {
+    // The characters allowed in user names, none of which need quoting in
+    // YAML. Only `.` means anything in a regex.
+    if prefix.is_empty()
+        || !prefix
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/'))
+    {
+        bail!("The user prefix may only have a-z, 0-9 and . _ = - /");
+    }
+
+    let escape = |s: &str| s.replace('.', "\\.");
+
+    println!("id: {}", id);
+    println!("url: null");
+    println!("as_token: {}", token());
+    println!("hs_token: {}", token());
+    println!("sender_localpart: {}", id);
+    println!("rate_limited: false");
+    println!("namespaces:");
+    println!("  users:");
+    println!("    - exclusive: true");
+    println!(
+        "      regex: '@{}.*:{}'",
+        escape(prefix),
+        escape(server.as_str())
+    );
+    println!("  aliases: []");
+    println!("  rooms: []");
+
+    eprintln!(
+        "Give llamatrix the as_token with --appservice-token-file or another \
+         --appservice-token-* flag, along with a --username starting with {}",
+        prefix
+    );
+
+    Ok(())
+}
+
+/// What `/register` and `/login` answer with.
+#[derive(Deserialize)]
+struct LoggedIn {
+    user_id: OwnedUserId,
+    access_token: String,
+    device_id: OwnedDeviceId,
+}
+
+#[derive(Deserialize)]
+struct MatrixError {
+    errcode: String,
+    error: Option<String>,
+}
+
+/// The error the homeserver gave in `response`.
+async fn error(response: Response) -> MatrixError {
+    let status = response.status();
+
+    response.json().await.unwrap_or_else(|_| MatrixError {
+        errcode: status.to_string(),
+        error: None,
+    })
+}
+
+/// Log in as `user_id`, one of the application service's users, with its
+/// `as_token`, registering the user first if it doesn't exist yet.
+///
+/// This happens before the client has a session, so the requests are made
+/// here rather than by the client, which would only send its own token.
+pub async fn login(
+    client: &Client,
+    proxy: Option<&Url>,
+    as_token: &str,
+    user_id: &UserId,
+) -> Result<MatrixSession> {
+    let mut http = reqwest::Client::builder();
+
+    if let Some(proxy) = proxy {
+        http = http.proxy(reqwest::Proxy::all(proxy.clone())?);
+    }
+
+    let http = http.build()?;
+    let endpoint = |path: &str| {
+        format!(
+            "{}/_matrix/client/v3/{}",
+            client.homeserver().as_str().trim_end_matches('/'),
+            path
+        )
+    };
+
+    let response = http
+        .post(endpoint("register"))
+        .bearer_auth(as_token)
+        .json(&json!({ "type": LOGIN_TYPE, "username": user_id.localpart() }))
+        .send()
+        .await
+        .with_context(|| format!("Failed to register {}", user_id))?;
+
+    let response = match response.status().is_success() {
+        true => response,
+        false => {
+            let e = error(response).await;
+
+            if e.errcode != "M_USER_IN_USE" {
+                bail!(
+                    "Failed to register {}: {}",
+                    user_id,
+                    e.error.unwrap_or(e.errcode)
+                );
+            }
+
+            let response = http
+                .post(endpoint("login"))
+                .bearer_auth(as_token)
+                .json(&json!({
+                    "type": LOGIN_TYPE,
+                    "identifier": { "type": "m.id.user", "user": user_id.localpart() },
+                }))
+                .send()
+                .await
+                .context("Failed to login")?;
+
+            if !response.status().is_success() {
+                let e = error(response).await;
+
+                bail!(
+                    "Failed to login as {}: {}",
+                    user_id,
+                    e.error.unwrap_or(e.errcode)
+                );
+            }
+
+            response
+        }
+    };
+
+    let logged_in: LoggedIn = response.json().await.context("Failed to login")?;
+
+    Ok(MatrixSession {
+        meta: SessionMeta {
+            user_id: logged_in.user_id,
+            device_id: logged_in.device_id,
+        },
+        tokens: MatrixSessionTokens {
+            access_token: logged_in.access_token,
+            refresh_token: None,
+        },
+    })
+}
@@ -0,0 +1,110 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use matrix_sdk::{Client, ruma::RoomId};
+use tokio::sync::Mutex;
+
+use crate::{llama::Usage, store::DailyUsage};
+
+/// Remaining budget below which users are warned, as a fraction of the whole.
+const WARN_FRACTION: f64 = 0.1;
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+/// Keeps count of what each room, and the bot as a whole, uses per day, with
+/// days starting at midnight UTC.
+#[derive(Clone)]
+pub struct Budgets {
+    client: Client,
+    /// Held while a total is updated, so that generations finishing at the
+    /// same time, with more than one worker, each add to what the other
+    /// saved.
+    recording: Arc<Mutex<()>>,
+}
+
+impl Budgets {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            recording: Arc::default(),
+        }
+    }
+
+    /// What a room has used so far today, or the whole bot if `room_id` is
+    /// `None`.
+    pub async fn today(&self, room_id: Option<&RoomId>) -> Result<DailyUsage> {
+        let usage = DailyUsage::load(&self.client, room_id).await?;
+
+        Ok(match usage.day == today() {
+            true => usage,
+            false => DailyUsage::default(),
+        })
+    }
+
+    /// The tokens used in a room so far today.
+    pub async fn used(&self, room_id: &RoomId) -> Result<u64> {
+        Ok(self.today(Some(room_id)).await?.tokens)
+    }
+
+    /// Add a response generated for a room to both its total and the bot's.
+    pub async fn record(&self, room_id: &RoomId, usage: Usage) -> Result<()> {
+        let _recording = self.recording.lock().await;
+
+        for room_id in [Some(room_id), None] {
+            let mut total = self.today(room_id).await?;
+
+            total.day = today();
+            total.tokens += usage.tokens();
+            total.generation_secs += usage.total_duration as f64 / 1e9;
+            total.save(&self.client, room_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What to tell a room about its budget having used `used` tokens of it:
+/// a refusal if it's spent, a warning if it's nearly spent, or nothing.
+pub enum Standing {
+    Exhausted,
+    Low(u64),
+    Fine,
+}
+
+pub fn standing(budget: u64, used: u64) -> Standing {
+    let remaining = budget.saturating_sub(used);
+
+    if remaining == 0 {
+        Standing::Exhausted
+    } else if (remaining as f64) < budget as f64 * WARN_FRACTION {
+        Standing::Low(remaining)
+    } else {
+        Standing::Fine
+    }
+}
+
+/// The bot's own daily limits, shared between every room.
+#[derive(Clone, Copy, Default)]
+pub struct GlobalBudget {
+    pub tokens: Option<u64>,
+    pub generation_secs: Option<u64>,
+}
+
+impl GlobalBudget {
+    /// Whether `usage` has reached either limit.
+    pub fn exhausted(&self, usage: &DailyUsage) -> bool {
+        self.tokens.is_some_and(|limit| usage.tokens >= limit)
+            || self
+                .generation_secs
+                .is_some_and(|limit| usage.generation_secs >= limit as f64)
+    }
+}
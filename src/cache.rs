@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::llama::Chat;
+
+/// What an answer depends on, besides the conversation before it, which must
+/// be empty for the answer to be cached, and images, of which there must be
+/// none.
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct Key {
+    model: String,
+    system_prompt: Option<String>,
+    format: Option<String>,
+    /// The generation options, seed included, as JSON.
+    options: String,
+    /// The tools the model may call, as declared to it.
+    tools: String,
+    /// What the model is told along with the prompt, such as excerpts
+    /// retrieved for it.
+    notes: Vec<String>,
+    prompt: String,
+}
+
+impl Key {
+    /// The key for `chat` answering `prompt`, with everything it will be
+    /// answered with already set.
+    pub fn new(chat: &Chat, prompt: &str) -> Self {
+        Self {
+            model: chat.model().to_owned(),
+            system_prompt: chat.system_prompt().map(str::to_owned),
+            format: chat.format().map(|f| f.to_string()),
+            options: serde_json::to_string(chat.options()).unwrap_or_default(),
+            tools: serde_json::Value::from(chat.tool_declarations()).to_string(),
+            notes: chat.notes().to_vec(),
+            prompt: normalize(prompt),
+        }
+    }
+}
+
+/// Reduce a prompt to what matters for telling repeats apart, ignoring case
+/// and spacing.
+fn normalize(prompt: &str) -> String {
+    prompt
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+struct Entry {
+    answer: String,
+    added: Instant,
+}
+
+/// Answers to questions asked without any prior conversation, so that asking
+/// the same question again is answered straight away.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<HashMap<Key, Entry>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            capacity,
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &Key) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(entry) if entry.added.elapsed() < self.ttl => Some(entry.answer.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache `answer`, making room by dropping expired answers, then the
+    /// oldest, if the cache is full.
+    pub fn insert(&self, key: Key, answer: String) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.added.elapsed() < self.ttl);
+        }
+
+        if entries.len() >= self.capacity
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.added)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&oldest);
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                answer,
+                added: Instant::now(),
+            },
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    future,
+    sync::{Arc, Mutex},
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use tokio::sync::watch;
+
+type Registry = HashMap<OwnedRoomId, HashMap<u64, watch::Sender<bool>>>;
+
+/// The requests in each room that could still be cancelled, whether waiting
+/// in the queue or being answered.
+#[derive(Clone, Default)]
+pub struct Cancels {
+    next_id: Arc<Mutex<u64>>,
+    rooms: Arc<Mutex<Registry>>,
+}
+
+impl Cancels {
+    /// Register a request from `room_id`, which can be cancelled until the
+    /// returned handle is dropped.
+    pub fn register(&self, room_id: &RoomId) -> Cancellable {
+        let (tx, rx) = watch::channel(false);
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(id, tx);
+
+        Cancellable {
+            rx,
+            registration: Some((self.clone(), room_id.to_owned(), id)),
+        }
+    }
+
+    /// Cancel every request from `room_id`, returning how many there were.
+    pub fn cancel(&self, room_id: &RoomId) -> usize {
+        let requests = self
+            .rooms
+            .lock()
+            .unwrap()
+            .remove(room_id)
+            .unwrap_or_default();
+
+        for tx in requests.values() {
+            let _ = tx.send(true);
+        }
+
+        requests.len()
+    }
+
+    /// Cancel every request from every room, returning how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let rooms = std::mem::take(&mut *self.rooms.lock().unwrap());
+        let requests: Vec<_> = rooms
+            .values()
+            .flat_map(|requests| requests.values())
+            .collect();
+
+        for tx in &requests {
+            let _ = tx.send(true);
+        }
+
+        requests.len()
+    }
+}
+
+/// A request's side of its entry in [`Cancels`].
+pub struct Cancellable {
+    rx: watch::Receiver<bool>,
+    registration: Option<(Cancels, OwnedRoomId, u64)>,
+}
+
+impl Cancellable {
+    /// A handle for a request that can't be cancelled.
+    pub fn never() -> Self {
+        Self {
+            rx: watch::channel(false).1,
+            registration: None,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait for the request to be cancelled, which might never happen.
+    pub async fn cancelled(&mut self) {
+        if self.rx.wait_for(|cancelled| *cancelled).await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Cancellable {
+    fn drop(&mut self) {
+        let Some((cancels, room_id, id)) = self.registration.take() else {
+            return;
+        };
+
+        let mut rooms = cancels.rooms.lock().unwrap();
+
+        if let Some(requests) = rooms.get_mut(&room_id) {
+            requests.remove(&id);
+
+            if requests.is_empty() {
+                rooms.remove(&room_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use matrix_sdk::{
+    Room,
+    room::MessagesOptions,
+    ruma::{EventId, MilliSecondsSinceUnixEpoch, UInt},
+};
+
+use crate::{
+    history::{summary_prompt, text_message, transcript},
+    llama::{Backend, Chat},
+};
+
+/// The most messages that will be read back when catching up on a room.
+const MAX_MISSED: usize = 100;
+
+/// Collect the conversation in `rm` since the bot last spoke there, as long as
+/// it has been quiet for at least `gap`.
+///
+/// The messages are returned as a chronological transcript, or `None` if
+/// there is nothing worth catching up on. `prompt` is the event that
+/// addressed the bot and is left out of the transcript.
+pub async fn missed_messages(rm: &Room, prompt: &EventId, gap: Duration) -> Result<Option<String>> {
+    let own_user = rm.own_user_id();
+    let mut missed = Vec::new();
+    let mut last_spoke = None;
+    let mut from = None;
+
+    'paginate: while missed.len() < MAX_MISSED {
+        let mut opts = MessagesOptions::backward();
+        opts.from = from;
+        opts.limit = UInt::from(50u32);
+
+        let page = rm.messages(opts).await?;
+
+        for evt in &page.chunk {
+            let Some(msg) = text_message(evt, own_user) else {
+                continue;
+            };
+
+            if msg.sender == own_user {
+                last_spoke = Some(msg.ts);
+                break 'paginate;
+            }
+
+            if msg.event_id != prompt && !msg.is_trigger() {
+                missed.push(msg);
+            }
+        }
+
+        match page.end {
+            Some(end) if !page.chunk.is_empty() => from = Some(end),
+            _ => break,
+        }
+    }
+
+    if let Some(ts) = last_spoke {
+        let now = u64::from(MilliSecondsSinceUnixEpoch::now().get());
+        let silent = Duration::from_millis(now.saturating_sub(ts.get().into()));
+
+        if silent < gap {
+            return Ok(None);
+        }
+    }
+
+    if missed.is_empty() {
+        return Ok(None);
+    }
+
+    missed.truncate(MAX_MISSED);
+    missed.reverse();
+
+    Ok(Some(transcript(&missed)))
+}
+
+/// Ask the model for a summary of a transcript from [`missed_messages`].
+///
+/// This runs in a throwaway context so it doesn't disturb the room's own.
+pub async fn summarize(backend: Backend, model: &str, transcript: &str) -> Result<String> {
+    Chat::new(model, backend)
+        .message(summary_prompt(transcript))
+        .await
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+
+use crate::html;
+
+/// The commands understood by the bot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Clear,
+    Index,
+    Tldr,
+    Search,
+    Profile,
+    Prefs,
+    Setup,
+    Alias,
+    Verify,
+    Admin,
+    Fork,
+    Switch,
+    Context,
+    Ask,
+    Json,
+    Extract,
+    Seed,
+    Ephemeral,
+    Condense,
+    Usage,
+    Set,
+    Settings,
+    System,
+    Shutdown,
+    ResetAll,
+    Ban,
+    Unban,
+    Help,
+    Models,
+    Info,
+    Status,
+    Stop,
+    Retry,
+    Image,
+    Summarize,
+}
+
+/// Who may run a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Anyone,
+    /// Room moderators and bot admins.
+    Moderator,
+    /// Anyone, to see a setting by giving no arguments, but only room
+    /// moderators and bot admins to change it.
+    ModeratorToChange,
+    /// Bot admins only.
+    Admin,
+}
+
+/// A command, invoked as `!llama<name>`. With a space after `!llama`, as in
+/// `!llama stop talking like a pirate`, the message is a prompt.
+pub struct Command {
+    pub kind: Kind,
+    pub name: &'static str,
+    pub permission: Permission,
+    /// What follows the command, for `!llamahelp`.
+    pub args: &'static str,
+    /// What the command does, for `!llamahelp`.
+    pub summary: &'static str,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        kind: Kind::Clear,
+        name: "clear",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "Forget the conversation so far",
+    },
+    Command {
+        kind: Kind::Index,
+        name: "index",
+        permission: Permission::Moderator,
+        args: "on|off",
+        summary: "Index the room's history so it can be searched",
+    },
+    Command {
+        kind: Kind::Tldr,
+        name: "tldr",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "Summarise the thread it's sent in",
+    },
+    Command {
+        kind: Kind::Search,
+        name: "search",
+        permission: Permission::Anyone,
+        args: "<query>",
+        summary: "Search the room's indexed history",
+    },
+    Command {
+        kind: Kind::Profile,
+        name: "profile",
+        permission: Permission::Anyone,
+        args: "[show | set <field> <text> | clear [<field>]]",
+        summary: "Tell the bot your name, language or standing instructions",
+    },
+    Command {
+        kind: Kind::Prefs,
+        name: "prefs",
+        permission: Permission::Anyone,
+        args: "[show | verbosity <level> | language <language> | model <model> | reset]",
+        summary: "Set your personal preferences, in a direct chat",
+    },
+    Command {
+        kind: Kind::Setup,
+        name: "setup",
+        permission: Permission::Moderator,
+        args: "",
+        summary: "Configure the bot for this room, step by step",
+    },
+    Command {
+        kind: Kind::Alias,
+        name: "alias",
+        permission: Permission::ModeratorToChange,
+        args: "[<alias> <command> | remove <alias>]",
+        summary: "Give a command another name in this room",
+    },
+    Command {
+        kind: Kind::Verify,
+        name: "verify",
+        permission: Permission::Admin,
+        args: "accept|reject|match|mismatch <request>",
+        summary: "Answer a request to verify the bot's device, or say whether its emoji match",
+    },
+    Command {
+        kind: Kind::Admin,
+        name: "admin",
+        permission: Permission::Admin,
+        args: "<action> ...",
+        summary: "Operate the bot: messages, broadcasts, schedules, maintenance, models and budgets",
+    },
+    Command {
+        kind: Kind::Fork,
+        name: "fork",
+        permission: Permission::Anyone,
+        args: "<name>",
+        summary: "Copy the conversation into a new one and switch to it",
+    },
+    Command {
+        kind: Kind::Switch,
+        name: "switch",
+        permission: Permission::Anyone,
+        args: "<name>",
+        summary: "Switch to another conversation",
+    },
+    Command {
+        kind: Kind::Context,
+        name: "context",
+        permission: Permission::Anyone,
+        args: "[list | use <name> | system <prompt> | model <model> | delete <name>]",
+        summary: "Manage this room's conversations",
+    },
+    Command {
+        kind: Kind::Ask,
+        name: "q",
+        permission: Permission::Anyone,
+        args: "<question>",
+        summary: "Ask a question without touching the conversation",
+    },
+    Command {
+        kind: Kind::Json,
+        name: "json",
+        permission: Permission::Anyone,
+        args: "[<schema>] <prompt>",
+        summary: "Get an answer as JSON, following a schema if given",
+    },
+    Command {
+        kind: Kind::Extract,
+        name: "extract",
+        permission: Permission::Anyone,
+        args: "[--json] <field>, ...",
+        summary: "Pull fields out of the message being replied to",
+    },
+    Command {
+        kind: Kind::Seed,
+        name: "seed",
+        permission: Permission::ModeratorToChange,
+        args: "[<seed> | random]",
+        summary: "Pin the seed answers are generated with",
+    },
+    Command {
+        kind: Kind::Ephemeral,
+        name: "ephemeral",
+        permission: Permission::Moderator,
+        args: "on|off",
+        summary: "Keep nothing from this room's conversation",
+    },
+    Command {
+        kind: Kind::Condense,
+        name: "condense",
+        permission: Permission::Moderator,
+        args: "on|off",
+        summary: "Summarise old messages instead of forgetting them once the context fills up",
+    },
+    Command {
+        kind: Kind::Usage,
+        name: "usage",
+        permission: Permission::ModeratorToChange,
+        args: "[on|off]",
+        summary: "Show the tokens the last answer used, or show them on every answer",
+    },
+    Command {
+        kind: Kind::Set,
+        name: "set",
+        permission: Permission::Moderator,
+        args: "<option> <value> | reset",
+        summary: "Set a sampling option, such as temperature or top_p, for this room",
+    },
+    Command {
+        kind: Kind::Settings,
+        name: "settings",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "Show the sampling options set for this room",
+    },
+    Command {
+        kind: Kind::System,
+        name: "system",
+        permission: Permission::ModeratorToChange,
+        args: "[<prompt> | persona <name> | none]",
+        summary: "Set this room's system prompt or persona",
+    },
+    Command {
+        kind: Kind::Shutdown,
+        name: "shutdown",
+        permission: Permission::Admin,
+        args: "",
+        summary: "Stop the bot",
+    },
+    Command {
+        kind: Kind::ResetAll,
+        name: "resetall",
+        permission: Permission::Admin,
+        args: "",
+        summary: "Forget every conversation in every room",
+    },
+    Command {
+        kind: Kind::Ban,
+        name: "ban",
+        permission: Permission::Admin,
+        args: "<user>",
+        summary: "Stop a user from using the bot",
+    },
+    Command {
+        kind: Kind::Unban,
+        name: "unban",
+        permission: Permission::Admin,
+        args: "<user>",
+        summary: "Let a banned user use the bot again",
+    },
+    Command {
+        kind: Kind::Help,
+        name: "help",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "List the commands",
+    },
+    Command {
+        kind: Kind::Models,
+        name: "models",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "List the models installed on the server",
+    },
+    Command {
+        kind: Kind::Info,
+        name: "info",
+        permission: Permission::Anyone,
+        args: "[<model>]",
+        summary: "Describe the model answering in this room, or another one",
+    },
+    Command {
+        kind: Kind::Status,
+        name: "status",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "Show what the bot is up to, to see why it isn't answering",
+    },
+    Command {
+        kind: Kind::Stop,
+        name: "stop",
+        permission: Permission::Anyone,
+        args: "",
+        summary: "Stop answering this room's requests",
+    },
+    Command {
+        kind: Kind::Retry,
+        name: "retry",
+        permission: Permission::Anyone,
+        args: "[seed <seed>] [temperature <temperature>]",
+        summary: "Answer the last prompt again, in place of the last answer",
+    },
+    Command {
+        kind: Kind::Image,
+        name: "image",
+        permission: Permission::Anyone,
+        args: "<prompt>",
+        summary: "Generate an image",
+    },
+    Command {
+        kind: Kind::Summarize,
+        name: "summarize",
+        permission: Permission::Anyone,
+        args: "<url> [<question>]",
+        summary: "Summarise a web page, or answer a question about it",
+    },
+];
+
+impl Kind {
+    fn command(self) -> &'static Command {
+        COMMANDS.iter().find(|cmd| cmd.kind == self).unwrap()
+    }
+
+    pub fn name(self) -> &'static str {
+        self.command().name
+    }
+
+    pub fn permission(self) -> Permission {
+        self.command().permission
+    }
+
+    /// Whether the command has a model answer a prompt of its own, or draw
+    /// one, and so is held to the same limits as prompts are.
+    pub fn generates(self) -> bool {
+        matches!(
+            self,
+            Kind::Tldr | Kind::Json | Kind::Extract | Kind::Summarize | Kind::Image
+        )
+    }
+}
+
+/// Look up a command by its canonical name.
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|cmd| cmd.name == name)
+}
+
+/// Match the text following the `!llama` trigger against the known commands,
+/// returning the command along with the rest of the text as its arguments.
+/// The name has to follow the trigger straight away, so that a prompt which
+/// starts with a command's name is still a prompt.
+///
+/// `aliases` maps a room's own (for example, translated) names for commands
+/// to their canonical names. Aliases are matched case-insensitively, but
+/// never take the place of a canonical name.
+pub fn parse<'a>(text: &'a str, aliases: &HashMap<String, String>) -> Option<(Kind, &'a str)> {
+    let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+    find(name)
+        .or_else(|| {
+            aliases
+                .get(&name.to_lowercase())
+                .and_then(|canonical| find(canonical))
+        })
+        .map(|cmd| (cmd.kind, args.trim()))
+}
+
+/// Describe every command, as plain text and as HTML.
+pub fn help() -> (String, String) {
+    let mut plain = vec!["!llama <prompt>: talk to the bot".to_owned()];
+    let mut items = vec!["<li><code>!llama &lt;prompt&gt;</code>: talk to the bot</li>".to_owned()];
+
+    for cmd in COMMANDS {
+        let usage = match cmd.args {
+            "" => format!("!llama{}", cmd.name),
+            args => format!("!llama{} {}", cmd.name, args),
+        };
+        let restriction = match cmd.permission {
+            Permission::Anyone => "",
+            Permission::Moderator => " (moderators only)",
+            Permission::ModeratorToChange => " (changed by moderators only)",
+            Permission::Admin => " (admins only)",
+        };
+
+        plain.push(format!("{}: {}{}", usage, cmd.summary, restriction));
+        items.push(format!(
+            "<li><code>{}</code>: {}{}</li>",
+            html::escape(&usage),
+            html::escape(cmd.summary),
+            restriction
+        ));
+    }
+
+    (plain.join("\n"), format!("<ul>{}</ul>", items.concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_follow_the_trigger() {
+        let aliases = HashMap::new();
+
+        assert_eq!(parse("help", &aliases), Some((Kind::Help, "")));
+        assert_eq!(parse("seed 42", &aliases), Some((Kind::Seed, "42")));
+        assert_eq!(parse("nonsense", &aliases), None);
+    }
+
+    #[test]
+    fn prompts_may_start_with_a_command_name() {
+        let aliases = HashMap::new();
+
+        assert_eq!(parse(" stop talking like a pirate", &aliases), None);
+        assert_eq!(parse(" help", &aliases), None);
+    }
+
+    #[test]
+    fn aliases_are_matched_without_case() {
+        let aliases = HashMap::from([("hilfe".to_owned(), "help".to_owned())]);
+
+        assert_eq!(parse("Hilfe", &aliases), Some((Kind::Help, "")));
+    }
+}
@@ -0,0 +1,660 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use clap::{ArgAction, CommandFactory};
+use log::{info, warn};
+use matrix_sdk::ruma::OwnedUserId;
+use serde::Deserialize;
+use tokio::time::sleep;
+
+use crate::{access::Pattern, llama::Options, mcp::ServerConfig};
+
+/// How often the config file is checked for changes.
+const POLL: Duration = Duration::from_secs(5);
+
+/// What prompts are answered with in maintenance mode, unless another message
+/// is given.
+const MAINTENANCE_MESSAGE: &str = "I'm down for maintenance at the moment, please try again later";
+
+/// How many tokens of conversation models are given, unless another number is
+/// given.
+const CONTEXT_WINDOW: usize = 4096;
+
+/// The contents of the config file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct FileConfig {
+    /// Admins in addition to those given with --admin.
+    admins: Vec<OwnedUserId>,
+    /// Models users may choose in addition to those given with --user-model.
+    user_models: Vec<String>,
+    /// Who may invite the bot, in addition to those given with
+    /// --allow-invites-from.
+    allow_invites_from: Vec<Pattern>,
+    /// Who may talk to the bot, in addition to those given with --allow-user.
+    allow_users: Vec<Pattern>,
+    /// Who may not talk to the bot, in addition to those given with
+    /// --deny-user.
+    deny_users: Vec<Pattern>,
+    refusal_message: Option<String>,
+    /// The persona used in rooms that haven't chosen their own.
+    persona: Option<String>,
+    /// The hourly prompt quota in rooms that haven't set their own.
+    quota: Option<u32>,
+    maintenance_message: Option<String>,
+    /// The daily token budget of rooms that haven't been given their own.
+    token_budget: Option<u64>,
+    /// How many tokens of conversation models are given, unless their
+    /// `num_ctx` option says otherwise.
+    context_window: Option<usize>,
+    /// Constrains every answer to JSON: either "json", or a table holding a
+    /// JSON schema answers must follow.
+    format: Option<serde_json::Value>,
+    /// Options for every request, beneath those of the model and persona.
+    options: Options,
+    /// Options for each model, applied whenever it answers.
+    models: HashMap<String, Options>,
+    /// Named personas rooms can pick by name instead of writing their own.
+    personas: HashMap<String, Persona>,
+    /// The MCP servers whose tools the model may call, by name. These are
+    /// only started once, so changes need a restart.
+    mcp_servers: HashMap<String, ServerConfig>,
+    /// The accounts to run the bot as, by name, each with the flags it sets
+    /// in place of those given for all of them. These are only read at
+    /// start, so changes need a restart.
+    accounts: BTreeMap<String, toml::Table>,
+    /// Anything else, which can't be changed while the bot is running: the
+    /// command line flags.
+    #[serde(flatten)]
+    other: toml::Table,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+
+        let file: Self = toml::from_str(&text)
+            .with_context(|| format!("Could not parse config file {}", path.display()))?;
+
+        file.check()
+            .with_context(|| format!("Invalid options in config file {}", path.display()))?;
+
+        Ok(file)
+    }
+
+    fn check(&self) -> Result<()> {
+        self.options.check()?;
+
+        for (model, options) in &self.models {
+            options
+                .check()
+                .with_context(|| format!("In the options for model {}", model))?;
+        }
+
+        for (name, persona) in &self.personas {
+            persona
+                .options
+                .check()
+                .with_context(|| format!("In persona {}", name))?;
+        }
+
+        // Account names name their data directories.
+        for name in self.accounts.keys() {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!(
+                    "Account name {:?} may only have letters, digits, - and _",
+                    name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Turn a value from the config file into the arguments that would set it on
+/// the command line.
+fn value_args(flag: &str, value: &toml::Value, args: &mut Vec<OsString>) -> Result<()> {
+    match value {
+        toml::Value::Boolean(true) => args.push(flag.into()),
+        toml::Value::Boolean(false) => {}
+        toml::Value::String(s) => args.extend([flag.into(), s.into()]),
+        toml::Value::Integer(i) => args.extend([flag.into(), i.to_string().into()]),
+        toml::Value::Float(f) => args.extend([flag.into(), f.to_string().into()]),
+        toml::Value::Array(values) => {
+            for value in values {
+                value_args(flag, value, args)?;
+            }
+        }
+        _ => bail!("{} can't be set from a table or date", flag),
+    }
+
+    Ok(())
+}
+
+/// The command line with the settings of the config file given with --config,
+/// if any, added to it. Settings given on the command line itself take
+/// precedence over those in the file, see [`with_file_args`].
+///
+/// Every command line flag can be given in the file, as a key with the
+/// flag's name, so `--read-marker-interval 60` is `read_marker_interval = 60`.
+pub fn command_line() -> Result<Vec<OsString>> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+
+    let Some(path) = path(&args) else {
+        return Ok(args);
+    };
+
+    with_file_args(args, &FileConfig::load(&path)?.other)
+}
+
+/// The config file given with --config in `args`, if any.
+pub fn path(args: &[OsString]) -> Option<PathBuf> {
+    args.iter().enumerate().find_map(|(i, arg)| {
+        let arg = arg.to_str()?;
+
+        match arg.strip_prefix("--config") {
+            Some("") => args.get(i + 1).map(PathBuf::from),
+            Some(rest) => rest.strip_prefix('=').map(PathBuf::from),
+            None => None,
+        }
+    })
+}
+
+/// The command line of each account listed in the config file at `path`, by
+/// name, if it lists any: the process's own, with the settings of the file
+/// added as [`command_line`] does. An account's own settings take the place
+/// of those given for all accounts, including where it gives one of a group
+/// of flags only one of which may be given, such as `password_file` in
+/// place of `password_keyring`.
+pub fn accounts(path: Option<&Path>) -> Result<Vec<(String, Vec<OsString>)>> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+
+    let file = FileConfig::load(path)?;
+    let command = crate::Args::command();
+    let exclusive: Vec<Vec<String>> = command
+        .get_groups()
+        .filter(|group| !clap::ArgGroup::clone(group).is_multiple())
+        .map(|group| group.get_args().map(|id| id.to_string()).collect())
+        .collect();
+
+    file.accounts
+        .iter()
+        .map(|(name, table)| {
+            let mut settings = file.other.clone();
+
+            for group in &exclusive {
+                if group.iter().any(|key| table.contains_key(key)) {
+                    settings.retain(|key, _| !group.iter().any(|member| member == key));
+                }
+            }
+
+            settings.extend(table.clone());
+
+            let args = with_file_args(std::env::args_os().collect(), &settings)
+                .with_context(|| format!("In account {}", name))?;
+
+            Ok((name.clone(), args))
+        })
+        .collect()
+}
+
+/// The long names of the flags `args` gives, short ones included.
+fn given_flags(command: &clap::Command, args: &[OsString]) -> HashSet<String> {
+    args.iter()
+        .skip(1)
+        .filter_map(|arg| arg.to_str())
+        .filter_map(|arg| match arg.strip_prefix("--") {
+            Some(long) => Some(long.split('=').next().unwrap_or(long).to_owned()),
+            None => {
+                let short = arg.strip_prefix('-')?.chars().next()?;
+
+                command
+                    .get_arguments()
+                    .find(|a| a.get_short() == Some(short))
+                    .and_then(|a| a.get_long())
+                    .map(str::to_owned)
+            }
+        })
+        .collect()
+}
+
+/// Whether `--<name>` is a switch, which takes no value.
+fn is_switch(command: &clap::Command, name: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|a| a.get_long() == Some(name) && matches!(a.get_action(), ArgAction::SetTrue))
+}
+
+/// `args` with flags for `settings` added to it. A flag given in `args`
+/// replaces the setting for it, even one that may be given more than once,
+/// and `--no-<switch>` turns off a switch the settings turn on.
+fn with_file_args(mut args: Vec<OsString>, settings: &toml::Table) -> Result<Vec<OsString>> {
+    let command = crate::Args::command();
+    let mut given = given_flags(&command, &args);
+
+    // Only there to override the file, so not passed on.
+    args.retain(
+        |arg| match arg.to_str().and_then(|arg| arg.strip_prefix("--no-")) {
+            Some(name) if is_switch(&command, name) => {
+                given.insert(name.to_owned());
+                false
+            }
+            _ => true,
+        },
+    );
+
+    let mut file_args = Vec::new();
+
+    for (key, value) in settings {
+        if given.contains(&key.replace('_', "-")) {
+            continue;
+        }
+
+        value_args(
+            &format!("--{}", key.replace('_', "-")),
+            value,
+            &mut file_args,
+        )?;
+    }
+
+    // Right after the program name, so that later flags override them.
+    args.splice(1..1, file_args);
+
+    Ok(args)
+}
+
+/// The MCP servers set up in the config file at `path`, if there is one.
+pub fn mcp_servers(path: Option<&Path>) -> Result<HashMap<String, ServerConfig>> {
+    match path {
+        Some(path) => Ok(FileConfig::load(path)?.mcp_servers),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// A persona offered by the bot.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Persona {
+    /// The system prompt the persona is made of.
+    pub prompt: String,
+    #[serde(flatten)]
+    pub options: Options,
+}
+
+/// The settings that can be changed without a restart, as they currently
+/// stand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub admins: HashSet<OwnedUserId>,
+    pub user_models: HashSet<String>,
+    /// Who may invite the bot to rooms. Anyone may if this is empty.
+    pub allow_invites_from: Vec<Pattern>,
+    /// Who may talk to the bot. Anyone may if this is empty.
+    pub allow_users: Vec<Pattern>,
+    /// Who may not talk to the bot, whether allowed or not.
+    pub deny_users: Vec<Pattern>,
+    /// What users who may not talk to the bot are told, if anything.
+    pub refusal_message: Option<String>,
+    pub persona: Option<String>,
+    pub quota: Option<u32>,
+    pub maintenance_message: Option<String>,
+    pub token_budget: Option<u64>,
+    pub context_window: Option<usize>,
+    pub format: Option<serde_json::Value>,
+    pub options: Options,
+    pub models: HashMap<String, Options>,
+    pub personas: HashMap<String, Persona>,
+}
+
+impl Settings {
+    /// Apply a config file beneath the settings given on the command line,
+    /// which take precedence, as with the flags.
+    fn merge(&self, file: &FileConfig) -> Self {
+        Self {
+            admins: self.admins.iter().chain(&file.admins).cloned().collect(),
+            user_models: self
+                .user_models
+                .iter()
+                .chain(&file.user_models)
+                .cloned()
+                .collect(),
+            allow_invites_from: self
+                .allow_invites_from
+                .iter()
+                .chain(&file.allow_invites_from)
+                .cloned()
+                .collect(),
+            allow_users: self
+                .allow_users
+                .iter()
+                .chain(&file.allow_users)
+                .cloned()
+                .collect(),
+            deny_users: self
+                .deny_users
+                .iter()
+                .chain(&file.deny_users)
+                .cloned()
+                .collect(),
+            refusal_message: self
+                .refusal_message
+                .clone()
+                .or(file.refusal_message.clone()),
+            persona: self.persona.clone().or(file.persona.clone()),
+            quota: self.quota.or(file.quota),
+            maintenance_message: self
+                .maintenance_message
+                .clone()
+                .or(file.maintenance_message.clone()),
+            token_budget: self.token_budget.or(file.token_budget),
+            context_window: self.context_window.or(file.context_window),
+            format: self.format.clone().or(file.format.clone()),
+            options: {
+                let mut options = self.options.clone();
+                options.merge(&file.options);
+                options
+            },
+            // Later entries replace earlier ones with the same name.
+            models: file
+                .models
+                .iter()
+                .chain(&self.models)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            personas: file
+                .personas
+                .iter()
+                .chain(&self.personas)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+
+    /// What prompts are answered with in maintenance mode, unless another
+    /// message is given when it is turned on.
+    pub fn maintenance_message(&self) -> &str {
+        self.maintenance_message
+            .as_deref()
+            .unwrap_or(MAINTENANCE_MESSAGE)
+    }
+
+    /// How many tokens of conversation models are given, unless their
+    /// `num_ctx` option says otherwise.
+    pub fn context_window(&self) -> usize {
+        self.context_window.unwrap_or(CONTEXT_WINDOW)
+    }
+
+    /// The system prompt and options for a room with the named persona
+    /// `named` or the system prompt `prompt`, falling back to the default
+    /// persona, which is either the name of one of the configured personas or
+    /// a system prompt of its own.
+    pub fn persona(&self, named: Option<&str>, prompt: Option<&str>) -> (Option<String>, Options) {
+        if let Some(named) = named.and_then(|n| self.personas.get(n)) {
+            return (Some(named.prompt.clone()), named.options.clone());
+        }
+
+        if let Some(prompt) = prompt {
+            return (Some(prompt.to_owned()), Options::default());
+        }
+
+        match self.persona.as_ref().and_then(|p| self.personas.get(p)) {
+            Some(named) => (Some(named.prompt.clone()), named.options.clone()),
+            None => (self.persona.clone(), Options::default()),
+        }
+    }
+
+    /// Log each setting that differs between `self` and `new`.
+    fn log_changes(&self, new: &Settings) {
+        if self.admins != new.admins {
+            info!("Reloaded admins");
+        }
+
+        if self.user_models != new.user_models {
+            info!("Reloaded user models");
+        }
+
+        if self.allow_invites_from != new.allow_invites_from {
+            info!("Reloaded who may invite the bot");
+        }
+
+        if self.allow_users != new.allow_users || self.deny_users != new.deny_users {
+            info!("Reloaded who may talk to the bot");
+        }
+
+        if self.refusal_message != new.refusal_message {
+            info!("Reloaded the refusal message");
+        }
+
+        if self.persona != new.persona {
+            info!("Reloaded the default persona");
+        }
+
+        if self.quota != new.quota {
+            info!("Reloaded the default quota: {:?}", new.quota);
+        }
+
+        if self.maintenance_message != new.maintenance_message {
+            info!("Reloaded the maintenance message");
+        }
+
+        if self.token_budget != new.token_budget {
+            info!("Reloaded the room token budget: {:?}", new.token_budget);
+        }
+
+        if self.context_window != new.context_window {
+            info!("Reloaded the context window: {}", new.context_window());
+        }
+
+        if self.format != new.format {
+            info!("Reloaded the output format");
+        }
+
+        if self.options != new.options {
+            info!("Reloaded the default options");
+        }
+
+        if self.models != new.models {
+            info!("Reloaded model options");
+        }
+
+        if self.personas != new.personas {
+            info!("Reloaded personas");
+        }
+    }
+}
+
+/// A handle to the current [`Settings`], which are replaced wholesale whenever
+/// the config file changes.
+#[derive(Clone)]
+pub struct Live(Arc<RwLock<Arc<Settings>>>);
+
+impl Live {
+    pub fn get(&self) -> Arc<Settings> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, settings: Settings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Load the settings, from `path` if given, on top of those given on the
+/// command line in `base`. With a config file, a task is started to reload
+/// it whenever it changes.
+pub fn load(base: Settings, path: Option<PathBuf>) -> Result<Live> {
+    let Some(path) = path else {
+        return Ok(Live(Arc::new(RwLock::new(Arc::new(base)))));
+    };
+
+    let file = FileConfig::load(&path)?;
+    let live = Live(Arc::new(RwLock::new(Arc::new(base.merge(&file)))));
+
+    tokio::spawn(watch(path, base, file, live.clone()));
+
+    Ok(live)
+}
+
+async fn watch(path: PathBuf, base: Settings, mut file: FileConfig, live: Live) {
+    let mut last_modified = modified(&path);
+
+    loop {
+        sleep(POLL).await;
+
+        let now_modified = modified(&path);
+
+        if now_modified == last_modified {
+            continue;
+        }
+
+        last_modified = now_modified;
+
+        let new_file = match FileConfig::load(&path) {
+            Ok(new_file) => new_file,
+            Err(e) => {
+                warn!("Keeping the previous settings: {:#}", e);
+                continue;
+            }
+        };
+
+        let keys: HashSet<&String> = file.other.keys().chain(new_file.other.keys()).collect();
+
+        for key in keys {
+            if file.other.get(key) != new_file.other.get(key) {
+                warn!("Changes to {} only take effect after a restart", key);
+            }
+        }
+
+        let old = live.get();
+        let new = base.merge(&new_file);
+
+        if *old == new {
+            info!("Config file changed, but no live settings differ");
+        } else {
+            old.log_changes(&new);
+            live.set(new);
+        }
+
+        file = new_file;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            admins: HashSet::new(),
+            user_models: HashSet::new(),
+            allow_invites_from: Vec::new(),
+            allow_users: Vec::new(),
+            deny_users: Vec::new(),
+            refusal_message: None,
+            persona: None,
+            quota: None,
+            maintenance_message: None,
+            token_budget: None,
+            context_window: None,
+            format: None,
+            options: Options::default(),
+            models: HashMap::new(),
+            personas: HashMap::new(),
+        }
+    }
+
+    fn persona(prompt: &str) -> Persona {
+        Persona {
+            prompt: prompt.to_owned(),
+            options: Options::default(),
+        }
+    }
+
+    const FILE: &str = r#"
+        refusal_message = "file"
+        persona = "file"
+        quota = 10
+        maintenance_message = "file"
+        token_budget = 1000
+        context_window = 2048
+        format = "json"
+
+        [models.llama3]
+        temperature = 0.5
+
+        [personas.pirate]
+        prompt = "file"
+    "#;
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let file: FileConfig = toml::from_str(FILE).unwrap();
+        let mut temperature = Options::default();
+        temperature.set("temperature", "0.1").unwrap();
+
+        let flags = Settings {
+            refusal_message: Some("flag".to_owned()),
+            persona: Some("flag".to_owned()),
+            quota: Some(20),
+            maintenance_message: Some("flag".to_owned()),
+            token_budget: Some(2000),
+            context_window: Some(8192),
+            format: Some(serde_json::json!({ "type": "object" })),
+            models: HashMap::from([("llama3".to_owned(), temperature.clone())]),
+            personas: HashMap::from([("pirate".to_owned(), persona("flag"))]),
+            ..settings()
+        };
+
+        let merged = flags.merge(&file);
+
+        assert_eq!(merged.refusal_message.as_deref(), Some("flag"));
+        assert_eq!(merged.persona.as_deref(), Some("flag"));
+        assert_eq!(merged.quota, Some(20));
+        assert_eq!(merged.maintenance_message(), "flag");
+        assert_eq!(merged.token_budget, Some(2000));
+        assert_eq!(merged.context_window(), 8192);
+        assert_eq!(merged.format, flags.format);
+        assert_eq!(merged.models["llama3"], temperature);
+        assert_eq!(merged.personas["pirate"], persona("flag"));
+    }
+
+    #[test]
+    fn the_file_fills_in_for_missing_flags() {
+        let file: FileConfig = toml::from_str(FILE).unwrap();
+        let merged = settings().merge(&file);
+
+        assert_eq!(merged.refusal_message.as_deref(), Some("file"));
+        assert_eq!(merged.persona.as_deref(), Some("file"));
+        assert_eq!(merged.quota, Some(10));
+        assert_eq!(merged.maintenance_message(), "file");
+        assert_eq!(merged.token_budget, Some(1000));
+        assert_eq!(merged.context_window(), 2048);
+        assert_eq!(merged.format, Some(serde_json::json!("json")));
+        assert_eq!(merged.personas["pirate"], persona("file"));
+    }
+
+    #[test]
+    fn defaults_apply_without_flags_or_file() {
+        let merged = settings().merge(&FileConfig::default());
+
+        assert_eq!(merged.maintenance_message(), MAINTENANCE_MESSAGE);
+        assert_eq!(merged.context_window(), CONTEXT_WINDOW);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use log::warn;
+use matrix_sdk::{
+    Client,
+    ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId},
+};
+
+use crate::{
+    llama::Message,
+    store::{SavedSlot, SavedSlots, ThreadSlot},
+};
+
+/// The slot every room starts on.
+pub const MAIN: &str = "main";
+
+/// Something to do to a room's slots.
+pub enum SlotAction {
+    /// Copy the current slot into a new one and move onto it.
+    Fork(String),
+    /// Move onto an existing slot.
+    Switch(String),
+    /// Move onto a slot, starting it afresh if it doesn't exist yet.
+    Use(String),
+    /// Describe the room's slots.
+    List,
+    /// Set or remove the current slot's system prompt.
+    SystemPrompt(Option<String>),
+    /// Set or remove the current slot's model.
+    Model(Option<String>),
+    Delete(String),
+    /// Delete every slot, leaving the room as if it had never spoken to the
+    /// bot.
+    Forget,
+}
+
+/// The most thread conversations kept per room. Beyond that, the least
+/// recently used are forgotten.
+const MAX_THREADS: usize = 100;
+
+fn current(slots: &SavedSlots) -> &str {
+    slots.current.as_deref().unwrap_or(MAIN)
+}
+
+/// The conversation in the thread rooted at `root`, moved to the back as the
+/// most recently used. A new thread starts with the settings of the current
+/// slot.
+fn thread<'a>(slots: &'a mut SavedSlots, root: &EventId) -> &'a mut SavedSlot {
+    let thread = match slots.threads.iter().position(|t| t.root == root) {
+        Some(pos) => slots.threads.remove(pos),
+        None => {
+            let current = slots.slots.get(current(slots)).cloned().unwrap_or_default();
+
+            ThreadSlot {
+                root: root.to_owned(),
+                slot: SavedSlot {
+                    history: Vec::new(),
+                    last_prompt: None,
+                    ..current
+                },
+            }
+        }
+    };
+
+    slots.threads.push(thread);
+
+    if slots.threads.len() > MAX_THREADS {
+        slots.threads.drain(..slots.threads.len() - MAX_THREADS);
+    }
+
+    &mut slots.threads.last_mut().unwrap().slot
+}
+
+/// Each room's conversations with the bot.
+///
+/// A room may hold several conversations at once in named slots, each with
+/// its own history and settings, and move between them. Each thread has a
+/// conversation of its own besides. Slots are kept in the store, so
+/// conversations survive restarts.
+pub struct Contexts {
+    client: Client,
+    rooms: HashMap<OwnedRoomId, SavedSlots>,
+}
+
+impl Contexts {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            rooms: HashMap::new(),
+        }
+    }
+
+    async fn room(&mut self, room_id: &RoomId) -> &mut SavedSlots {
+        if !self.rooms.contains_key(room_id) {
+            let slots = SavedSlots::load(&self.client, room_id)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Failed to load conversations of {}: {}", room_id, e);
+                    SavedSlots::default()
+                });
+
+            self.rooms.insert(room_id.to_owned(), slots);
+        }
+
+        self.rooms.get_mut(room_id).unwrap()
+    }
+
+    async fn save(&self, room_id: &RoomId) {
+        let Some(slots) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        if let Err(e) = slots.save(&self.client, room_id).await {
+            warn!("Failed to save conversations of {}: {}", room_id, e);
+        }
+    }
+
+    /// The conversation in the thread rooted at `thread`, or the room's
+    /// current slot outside of threads.
+    pub async fn current(&mut self, room_id: &RoomId, thread: Option<&EventId>) -> SavedSlot {
+        let slots = self.room(room_id).await;
+
+        if let Some(root) = thread {
+            return self::thread(slots, root).clone();
+        }
+
+        let name = current(slots).to_owned();
+
+        slots.slots.get(&name).cloned().unwrap_or_default()
+    }
+
+    /// Replace the conversation that [`Contexts::current`] returns, whose
+    /// last exchange answers the prompt event `last_prompt`, if any.
+    pub async fn set_history(
+        &mut self,
+        room_id: &RoomId,
+        thread: Option<&EventId>,
+        history: Vec<Message>,
+        last_prompt: Option<OwnedEventId>,
+    ) {
+        let slots = self.room(room_id).await;
+        let slot = match thread {
+            Some(root) => self::thread(slots, root),
+            None => {
+                let name = current(slots).to_owned();
+
+                slots.slots.entry(name).or_default()
+            }
+        };
+
+        slot.history = history;
+        slot.last_prompt = last_prompt;
+
+        self.save(room_id).await;
+    }
+
+    /// Forget the conversation that [`Contexts::current`] returns, keeping
+    /// its settings.
+    pub async fn clear(&mut self, room_id: &RoomId, thread: Option<&EventId>) {
+        self.set_history(room_id, thread, Vec::new(), None).await;
+    }
+
+    /// Carry out `action`, returning a description of the outcome.
+    pub async fn apply(&mut self, room_id: &RoomId, action: SlotAction) -> String {
+        let slots = self.room(room_id).await;
+        let exists =
+            |slots: &SavedSlots, name: &str| name == MAIN || slots.slots.contains_key(name);
+
+        let reply = match action {
+            SlotAction::Fork(name) => {
+                if exists(slots, &name) {
+                    return format!("There is already a conversation called {}", name);
+                }
+
+                let slot = slots.slots.get(current(slots)).cloned().unwrap_or_default();
+
+                slots.slots.insert(name.clone(), slot);
+                slots.current = Some(name.clone());
+
+                format!("Forked the conversation into {}", name)
+            }
+            SlotAction::Switch(name) => {
+                if !exists(slots, &name) {
+                    return format!("There is no conversation called {}", name);
+                }
+
+                slots.current = (name != MAIN).then(|| name.clone());
+
+                format!("Switched to {}", name)
+            }
+            SlotAction::Use(name) => {
+                let reply = match exists(slots, &name) {
+                    true => format!("Switched to {}", name),
+                    false => format!("Started a new conversation called {}", name),
+                };
+
+                slots.slots.entry(name.clone()).or_default();
+                slots.current = (name != MAIN).then_some(name);
+
+                reply
+            }
+            SlotAction::List => {
+                let mut names: Vec<&str> = slots.slots.keys().map(String::as_str).collect();
+
+                if !names.contains(&MAIN) {
+                    names.push(MAIN);
+                }
+
+                names.sort();
+
+                return format!(
+                    "Using {}. Conversations: {}",
+                    current(slots),
+                    names.join(", ")
+                );
+            }
+            SlotAction::SystemPrompt(prompt) => {
+                let name = current(slots).to_owned();
+                let reply = match &prompt {
+                    Some(_) => format!("Set the system prompt of {}", name),
+                    None => format!("Removed the system prompt of {}", name),
+                };
+
+                slots.slots.entry(name).or_default().system_prompt = prompt;
+
+                reply
+            }
+            SlotAction::Model(model) => {
+                let name = current(slots).to_owned();
+                let reply = match &model {
+                    Some(model) => format!("{} now uses {}", name, model),
+                    None => format!("{} now uses the room's model", name),
+                };
+
+                slots.slots.entry(name).or_default().model = model;
+
+                reply
+            }
+            SlotAction::Delete(name) => {
+                if name == current(slots) {
+                    return "Switch to another conversation before deleting this one".to_owned();
+                }
+
+                if slots.slots.remove(&name).is_none() {
+                    return format!("There is no conversation called {}", name);
+                }
+
+                format!("Deleted {}", name)
+            }
+            SlotAction::Forget => {
+                *slots = SavedSlots::default();
+
+                "Forgot every conversation".to_owned()
+            }
+        };
+
+        self.save(room_id).await;
+
+        reply
+    }
+}
@@ -0,0 +1,122 @@
+use log::{error, info};
+use matrix_sdk::{
+    Client, Room,
+    ruma::{UserId, events::room::message::OriginalSyncRoomMessageEvent},
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    Bot, LlamaReq,
+    commands::{Kind, Permission},
+    contexts::SlotAction,
+    run_command, send_reply,
+    store::RoomSettings,
+};
+
+/// Whether `user` may run commands needing `permission` in `rm` with `args`,
+/// or the reason they may not.
+async fn check(
+    permission: Permission,
+    args: &str,
+    user: &UserId,
+    rm: &Room,
+    bot: &Bot,
+) -> Result<(), String> {
+    match permission {
+        Permission::Anyone => Ok(()),
+        Permission::ModeratorToChange if args.is_empty() => Ok(()),
+        Permission::Moderator | Permission::ModeratorToChange
+            if bot.can_configure(rm, user).await =>
+        {
+            Ok(())
+        }
+        Permission::Moderator => Err("Only room moderators can use that command".to_owned()),
+        Permission::ModeratorToChange => {
+            Err("Only room moderators can change that setting".to_owned())
+        }
+        Permission::Admin if bot.is_admin(user) => Ok(()),
+        Permission::Admin => Err("Only bot admins can use that command".to_owned()),
+    }
+}
+
+/// Run a command on behalf of the sender of `evt`, once they're known to be
+/// allowed to, returning the reply to post, if any. Commands that generate
+/// are turned away as prompts would be, according to the room's `settings`.
+pub async fn dispatch(
+    cmd: Kind,
+    args: &str,
+    evt: &OriginalSyncRoomMessageEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+    settings: &RoomSettings,
+) -> Option<String> {
+    if let Err(reply) = check(cmd.permission(), args, &evt.sender, rm, bot).await {
+        return Some(reply);
+    }
+
+    if cmd.generates()
+        && let Err(reply) = bot.admit(rm, &evt.sender, settings).await
+    {
+        return Some(reply);
+    }
+
+    match cmd {
+        Kind::Shutdown => {
+            info!("Shutting down at the request of {}", evt.sender);
+
+            // Replied to here, as the bot may be gone before a reply could be
+            // sent on its behalf.
+            send_reply(rm, evt, bot.plain("Shutting down")).await;
+            bot.shutdown.begin();
+
+            None
+        }
+        Kind::ResetAll => {
+            let rooms = client.joined_rooms();
+
+            for room in &rooms {
+                let (reply, rx) = oneshot::channel();
+                let req = LlamaReq::Slots {
+                    room_id: room.room_id().to_owned(),
+                    action: SlotAction::Forget,
+                    reply,
+                };
+
+                bot.queue.send(req, true).await;
+                let _ = rx.await;
+            }
+
+            Some(format!(
+                "Forgot every conversation in {} rooms",
+                rooms.len()
+            ))
+        }
+        Kind::Ban | Kind::Unban => {
+            let Ok(user) = UserId::parse(args) else {
+                return Some(format!("Usage: !llama{} <user>", cmd.name()));
+            };
+
+            if cmd == Kind::Ban && bot.is_admin(&user) {
+                return Some("Admins can't be banned".to_owned());
+            }
+
+            let result = match cmd {
+                Kind::Ban => bot.bans.ban(&user).await,
+                _ => bot.bans.unban(&user).await,
+            };
+
+            Some(match (cmd, result) {
+                (Kind::Ban, Ok(true)) => format!("{} may no longer use the bot", user),
+                (Kind::Ban, Ok(false)) => format!("{} is already banned", user),
+                (_, Ok(true)) => format!("{} may use the bot again", user),
+                (_, Ok(false)) => format!("{} isn't banned", user),
+                (_, Err(e)) => {
+                    error!("Failed to save the list of banned users: {}", e);
+                    "Failed to save the list of banned users".to_owned()
+                }
+            })
+        }
+        _ => run_command(cmd, args, evt, rm, client, bot).await,
+    }
+}
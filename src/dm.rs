@@ -0,0 +1,29 @@
+use anyhow::Result;
+use matrix_sdk::{
+    Client, Room,
+    ruma::{
+        UserId,
+        api::client::room::create_room::v3::{Request as CreateRoomRequest, RoomPreset},
+    },
+};
+
+/// Find the bot's direct chat with `user`, creating one if there isn't one.
+///
+/// New chats are end-to-end encrypted unless `encrypt` is false. An existing
+/// chat is used as it is.
+pub async fn dm_room(client: &Client, user: &UserId, encrypt: bool) -> Result<Room> {
+    if let Some(rm) = client.get_dm_room(user) {
+        return Ok(rm);
+    }
+
+    if encrypt {
+        return Ok(client.create_dm(user).await?);
+    }
+
+    let mut request = CreateRoomRequest::new();
+    request.invite = vec![user.to_owned()];
+    request.is_direct = true;
+    request.preset = Some(RoomPreset::TrustedPrivateChat);
+
+    Ok(client.create_room(request).await?)
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use matrix_sdk::{Client, ruma::events::room::message::FileMessageEventContent};
+
+use crate::{llama, media};
+
+/// The most text kept from a single document, in characters. Anything beyond
+/// this is cut off, so one upload can't swamp the context.
+const MAX_DOCUMENT_CHARS: usize = 100_000;
+
+/// The size of each piece a document is added to the context in.
+const CHUNK_CHARS: usize = 8_000;
+
+/// The size of each passage of a document embedded for retrieval, small
+/// enough for each to be about one thing.
+const PASSAGE_CHARS: usize = 1_000;
+
+/// The text of a file someone has shared with the bot.
+pub struct Document {
+    pub name: String,
+    pub text: String,
+    /// Whether the text was cut off at [`MAX_DOCUMENT_CHARS`].
+    pub truncated: bool,
+}
+
+/// Download the file in `file` and extract its text, if it is a kind of file
+/// the bot can read: plain text, Markdown or PDF.
+pub async fn read(client: &Client, file: &FileMessageEventContent) -> Result<Option<Document>> {
+    let name = file.filename().to_owned();
+    let mimetype = file
+        .info
+        .as_ref()
+        .and_then(|info| info.mimetype.as_deref())
+        .unwrap_or_default();
+
+    let pdf = mimetype == "application/pdf" || name.ends_with(".pdf");
+    let text = mimetype.starts_with("text/")
+        || [".txt", ".md", ".markdown"]
+            .iter()
+            .any(|ext| name.ends_with(ext));
+
+    if !pdf && !text {
+        return Ok(None);
+    }
+
+    let size = file.info.as_ref().and_then(|info| info.size);
+    let bytes = media::download(client, file, size).await?;
+
+    let mut text = match pdf {
+        true => tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&bytes))
+            .await
+            .context("Extracting text from the PDF failed")??,
+        false => String::from_utf8(bytes).context("The file is not UTF-8 text")?,
+    };
+
+    let truncated = match text.char_indices().nth(MAX_DOCUMENT_CHARS) {
+        Some((end, _)) => {
+            text.truncate(end);
+            true
+        }
+        None => false,
+    };
+
+    Ok(Some(Document {
+        name,
+        text,
+        truncated,
+    }))
+}
+
+impl Document {
+    /// Roughly how many tokens the document takes up.
+    pub fn tokens(&self) -> usize {
+        llama::estimate_tokens(&self.text)
+    }
+
+    /// The document split into pieces of at most [`CHUNK_CHARS`], each
+    /// labelled for the model.
+    pub fn chunks(&self) -> Vec<String> {
+        let pieces = self.split(CHUNK_CHARS);
+        let count = pieces.len();
+
+        pieces
+            .into_iter()
+            .enumerate()
+            .map(|(i, piece)| {
+                format!(
+                    "Part {} of {} of the document {} shared by the user:\n\n{}",
+                    i + 1,
+                    count,
+                    self.name,
+                    piece
+                )
+            })
+            .collect()
+    }
+
+    /// The document split into passages of at most [`PASSAGE_CHARS`], for
+    /// [`crate::retrieval::Indexer::add_document`].
+    pub fn passages(&self) -> Vec<String> {
+        self.split(PASSAGE_CHARS)
+    }
+
+    /// Split the text into pieces of at most `size` characters, breaking
+    /// between paragraphs where possible.
+    fn split(&self, size: usize) -> Vec<String> {
+        let mut pieces: Vec<String> = Vec::new();
+
+        for paragraph in self.text.split("\n\n") {
+            let mut paragraph: Vec<char> = paragraph.chars().collect();
+
+            while !paragraph.is_empty() {
+                let rest = paragraph.split_off(paragraph.len().min(size));
+                let part: String = paragraph.into_iter().collect();
+                paragraph = rest;
+
+                match pieces.last_mut() {
+                    Some(last) if last.chars().count() + part.chars().count() + 2 <= size => {
+                        last.push_str("\n\n");
+                        last.push_str(&part);
+                    }
+                    _ => pieces.push(part),
+                }
+            }
+        }
+
+        pieces
+    }
+}
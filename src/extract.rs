@@ -0,0 +1,96 @@
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use serde_json::{Map, Value, json};
+
+use crate::html;
+
+pub const USAGE: &str =
+    "Usage: reply to a message or file with !llamaextract [--json] <field>, <field>, ...";
+
+/// A request to pull named fields out of a piece of text.
+pub struct Extraction {
+    fields: Vec<String>,
+    /// Answer with the raw JSON rather than a table.
+    json: bool,
+}
+
+impl Extraction {
+    /// Parse the arguments of `!llamaextract`.
+    pub fn parse(args: &str) -> Option<Self> {
+        let (json, args) = match args.strip_prefix("--json") {
+            Some(rest) => (true, rest),
+            None => (false, args),
+        };
+
+        let fields: Vec<String> = args
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        (!fields.is_empty()).then_some(Self { fields, json })
+    }
+
+    /// The JSON schema the model's answer must follow: an object with a
+    /// string for each field.
+    pub fn schema(&self) -> Value {
+        let properties: Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|f| (f.clone(), json!({ "type": "string" })))
+            .collect();
+
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": self.fields,
+        })
+    }
+
+    pub fn prompt(&self, text: &str) -> String {
+        format!(
+            "Extract the following fields from the text below: {}. Use an empty \
+             string for any field the text doesn't mention.\n\n{}",
+            self.fields.join(", "),
+            text
+        )
+    }
+
+    /// Format the model's answer, as a table of fields unless JSON was asked
+    /// for. Answers that can't be read as JSON are shown as they are.
+    pub fn render(&self, answer: &str) -> RoomMessageEventContent {
+        let values = match serde_json::from_str::<Map<String, Value>>(answer) {
+            Ok(values) if !self.json => values,
+            _ => {
+                let pretty = serde_json::from_str::<Value>(answer)
+                    .and_then(|v| serde_json::to_string_pretty(&v))
+                    .unwrap_or_else(|_| answer.to_owned());
+                let html = html::code_block("json", &pretty);
+
+                return RoomMessageEventContent::text_html(pretty, html);
+            }
+        };
+
+        let rows: Vec<(&str, String)> = self
+            .fields
+            .iter()
+            .map(|f| {
+                let value = match values.get(f) {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(Value::Null) | None => String::new(),
+                    Some(v) => v.to_string(),
+                };
+
+                (f.as_str(), value)
+            })
+            .collect();
+
+        let plain = rows
+            .iter()
+            .map(|(f, v)| format!("{}: {}", f, v))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        RoomMessageEventContent::text_html(plain, html::table(&rows))
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+};
+
+/// A queue that takes turns between rooms, so that one room with many
+/// requests waiting can't hold up everyone else's.
+///
+/// Each room's requests come out in the order they went in. Priority
+/// requests come out before any others, in the order they went in.
+pub struct FairQueue<K, T> {
+    priority: VecDeque<(K, T)>,
+    rooms: HashMap<K, VecDeque<T>>,
+    /// The rooms with requests waiting, in the order they'll be served.
+    turns: VecDeque<K>,
+}
+
+impl<K: Eq + Hash + Clone, T> Default for FairQueue<K, T> {
+    fn default() -> Self {
+        Self {
+            priority: VecDeque::new(),
+            rooms: HashMap::new(),
+            turns: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, T> FairQueue<K, T> {
+    /// Add a request, returning where in the queue it is, counting from 1.
+    pub fn push(&mut self, room: K, item: T, priority: bool) -> usize {
+        let position = self.position(&room, priority);
+
+        if priority {
+            self.priority.push_back((room, item));
+            return position;
+        }
+
+        let queued = self.rooms.entry(room.clone()).or_default();
+
+        if queued.is_empty() {
+            self.turns.push_back(room);
+        }
+
+        queued.push_back(item);
+
+        position
+    }
+
+    /// Where a request from `room` would come out if it were added now, going
+    /// by the turns the other rooms would get before it.
+    fn position(&self, room: &K, priority: bool) -> usize {
+        if priority {
+            return self.priority.len() + 1;
+        }
+
+        let own = self.rooms.get(room).map_or(0, VecDeque::len);
+        let turn = self
+            .turns
+            .iter()
+            .position(|r| r == room)
+            .unwrap_or(self.turns.len());
+
+        // Rooms whose turn comes before this one's get one more turn before
+        // the request comes out than those whose turn comes after.
+        let others: usize = self
+            .turns
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| *r != room)
+            .map(|(i, r)| match i < turn {
+                true => self.rooms[r].len().min(own + 1),
+                false => self.rooms[r].len().min(own),
+            })
+            .sum();
+
+        self.priority.len() + own + others + 1
+    }
+
+    /// Take the next request from a room that isn't `busy`. A room served
+    /// goes to the back of the line.
+    pub fn pop(&mut self, busy: impl Fn(&K) -> bool) -> Option<T> {
+        if let Some(i) = self.priority.iter().position(|(room, _)| !busy(room)) {
+            return self.priority.remove(i).map(|(_, item)| item);
+        }
+
+        let turn = self.turns.iter().position(|room| !busy(room))?;
+        let room = self.turns.remove(turn).unwrap();
+        let queued = self.rooms.get_mut(&room).unwrap();
+        let item = queued.pop_front();
+
+        if queued.is_empty() {
+            self.rooms.remove(&room);
+        } else {
+            self.turns.push_back(room);
+        }
+
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.priority.len() + self.rooms.values().map(VecDeque::len).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(queue: &mut FairQueue<&'static str, u32>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop(|_| false)).collect()
+    }
+
+    #[test]
+    fn takes_turns_between_rooms() {
+        let mut queue = FairQueue::default();
+
+        for i in 0..3 {
+            queue.push("busy", i, false);
+        }
+
+        queue.push("quiet", 10, false);
+        queue.push("other", 20, false);
+        queue.push("quiet", 11, false);
+
+        assert_eq!(drain(&mut queue), [0, 10, 20, 1, 11, 2]);
+    }
+
+    #[test]
+    fn keeps_each_room_in_order() {
+        let mut queue = FairQueue::default();
+
+        for i in 0..50 {
+            queue.push("chatty", i, false);
+        }
+
+        queue.push("quiet", 100, false);
+
+        let order = drain(&mut queue);
+
+        assert_eq!(order[..2], [0, 100]);
+        assert!(order[1..].iter().filter(|&&i| i < 50).is_sorted());
+    }
+
+    #[test]
+    fn serves_priority_first() {
+        let mut queue = FairQueue::default();
+
+        queue.push("a", 1, false);
+        queue.push("b", 2, true);
+        queue.push("a", 3, true);
+
+        assert_eq!(drain(&mut queue), [2, 3, 1]);
+    }
+
+    #[test]
+    fn skips_busy_rooms() {
+        let mut queue = FairQueue::default();
+
+        queue.push("busy", 1, false);
+        queue.push("free", 2, false);
+        queue.push("busy", 3, true);
+
+        assert_eq!(queue.pop(|room| *room == "busy"), Some(2));
+        assert_eq!(queue.pop(|room| *room == "busy"), None);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(drain(&mut queue), [3, 1]);
+    }
+
+    #[test]
+    fn keeps_turn_when_skipped() {
+        let mut queue = FairQueue::default();
+
+        queue.push("a", 1, false);
+        queue.push("b", 2, false);
+        queue.push("b", 3, false);
+
+        assert_eq!(queue.pop(|room| *room == "a"), Some(2));
+        assert_eq!(drain(&mut queue), [1, 3]);
+    }
+
+    #[test]
+    fn tells_positions() {
+        let mut queue = FairQueue::default();
+
+        assert_eq!(queue.push("busy", 0, false), 1);
+        assert_eq!(queue.push("busy", 1, false), 2);
+        assert_eq!(queue.push("busy", 2, false), 3);
+        assert_eq!(queue.push("quiet", 10, false), 2);
+        assert_eq!(queue.push("other", 20, false), 3);
+        assert_eq!(queue.push("quiet", 11, false), 5);
+        assert_eq!(queue.push("urgent", 30, true), 1);
+
+        assert_eq!(drain(&mut queue), [30, 0, 10, 20, 1, 11, 2]);
+    }
+
+    #[test]
+    fn empties() {
+        let mut queue = FairQueue::default();
+
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.pop(|_| false), None);
+
+        queue.push("a", 1, false);
+        queue.push("a", 2, true);
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(drain(&mut queue), [2, 1]);
+        assert_eq!(queue.len(), 0);
+    }
+}
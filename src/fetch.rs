@@ -0,0 +1,418 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+use reqwest::{
+    Client, Url,
+    header::{CONTENT_TYPE, LOCATION},
+    redirect::Policy,
+};
+use tokio::{net::lookup_host, time::timeout};
+
+use crate::access::glob;
+
+/// The most of a page that is downloaded. Anything beyond this is ignored.
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+/// The most text taken from a single page, in characters.
+const MAX_PAGE_CHARS: usize = 20_000;
+
+/// The most links in a single prompt that are fetched.
+const MAX_LINKS: usize = 2;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Elements whose contents are never part of what a page says.
+const BOILERPLATE: &[&str] = &[
+    "head", "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "svg",
+];
+
+/// A web page, reduced to its text.
+pub struct Page {
+    pub url: Url,
+    pub title: Option<String>,
+    pub text: String,
+}
+
+/// How many redirects are followed before giving up on a page.
+const MAX_REDIRECTS: usize = 5;
+
+/// Which hosts pages may be fetched from.
+struct Hosts {
+    /// Host patterns, with `*` wildcards. If any are given, only matching
+    /// hosts are fetched from.
+    allow: Vec<String>,
+    /// Host patterns that are never fetched from, even if allowed.
+    deny: Vec<String>,
+}
+
+impl Hosts {
+    /// Whether pages on `host` may be fetched, going by its name.
+    fn allowed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+
+        let local = host == "localhost" || host.ends_with(".localhost");
+
+        !local
+            && (self.allow.is_empty() || self.allow.iter().any(|p| glob(p, &host)))
+            && !self.deny.iter().any(|p| glob(p, &host))
+    }
+}
+
+/// Whether `ip` is an address on the internet, rather than one only visible
+/// from where the bot runs, such as a loopback, private or link-local one.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space, used for carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                // Benchmarking, and the reserved block above multicast.
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            // Addresses that carry an IPv4 one reach whatever it does.
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+
+            let segments = ip.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., hi, lo] = segments;
+                return is_public(IpAddr::V4(Ipv4Addr::from(((hi as u32) << 16) | lo as u32)));
+            }
+
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7.
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10, and the old site-local, fec0::/10.
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] & 0xffc0) == 0xfec0
+                // Documentation, 2001:db8::/32.
+                || segments[..2] == [0x2001, 0xdb8]
+                // IPv4-compatible addresses, also deprecated.
+                || segments[..6] == [0; 6])
+        }
+    }
+}
+
+/// Fetches web pages linked to from prompts, from the hosts it is allowed to.
+#[derive(Clone)]
+pub struct Fetcher {
+    hosts: Arc<Hosts>,
+}
+
+impl Fetcher {
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        let lower = |patterns: Vec<String>| patterns.iter().map(|p| p.to_lowercase()).collect();
+
+        Self {
+            hosts: Arc::new(Hosts {
+                allow: lower(allow),
+                deny: lower(deny),
+            }),
+        }
+    }
+
+    /// A client that may only connect to `url`'s host, at the addresses it
+    /// was found to have. Local addresses never are, so that the bot can't be
+    /// used to reach what is only visible to it.
+    ///
+    /// The host is resolved here rather than when connecting, or it could
+    /// resolve to somewhere else by then.
+    async fn client_for(&self, url: &Url) -> Result<Client> {
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let host = url.host_str().context("The link has no host")?;
+
+        if !self.hosts.allowed(host) {
+            bail!("Fetching pages from {} isn't allowed", host);
+        }
+
+        let (domain, addrs): (_, Vec<SocketAddr>) =
+            match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+                Err(_) => {
+                    let addrs = timeout(FETCH_TIMEOUT, lookup_host((host, port)))
+                        .await
+                        .context("Looking up the host timed out")??;
+                    (Some(host), addrs.collect())
+                }
+            };
+
+        if addrs.is_empty() {
+            bail!("{} has no addresses", host);
+        }
+
+        if let Some(local) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            bail!(
+                "Fetching pages from {} isn't allowed, as it is at {}",
+                host,
+                local.ip()
+            );
+        }
+
+        // Redirects are followed by hand, so that each one is checked, and
+        // proxies are bypassed, as they'd do their own lookup.
+        let mut builder = Client::builder().redirect(Policy::none()).no_proxy();
+        if let Some(domain) = domain {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    pub async fn fetch(&self, url: Url) -> Result<Page> {
+        let mut next = url.clone();
+        let mut redirects = 0;
+
+        let mut resp = loop {
+            if !matches!(next.scheme(), "http" | "https") {
+                bail!("Only http and https links can be fetched");
+            }
+
+            let resp = self
+                .client_for(&next)
+                .await?
+                .get(next.clone())
+                .timeout(FETCH_TIMEOUT)
+                .send()
+                .await?;
+
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .filter(|_| resp.status().is_redirection());
+
+            match location {
+                Some(_) if redirects >= MAX_REDIRECTS => bail!("Too many redirects"),
+                Some(location) => {
+                    next = next.join(location)?;
+                    redirects += 1;
+                }
+                None => break resp.error_for_status()?,
+            }
+        };
+
+        let html = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_none_or(|v| v.contains("html"));
+
+        let mut body = Vec::new();
+
+        while let Some(bytes) = resp.chunk().await? {
+            body.extend_from_slice(&bytes);
+
+            if body.len() >= MAX_PAGE_BYTES {
+                body.truncate(MAX_PAGE_BYTES);
+                break;
+            }
+        }
+
+        let body = String::from_utf8_lossy(&body);
+
+        let (title, text) = match html {
+            true => (title(&body), strip_html(&body)),
+            false => (None, body.into_owned()),
+        };
+
+        Ok(Page {
+            url,
+            title,
+            text: text.chars().take(MAX_PAGE_CHARS).collect(),
+        })
+    }
+
+    /// Append the text of any web pages linked to from `prompt`, so the model
+    /// can read what the user is pointing at.
+    pub async fn expand_links(&self, prompt: &str) -> String {
+        let mut expanded = prompt.to_owned();
+
+        for url in links(prompt).into_iter().take(MAX_LINKS) {
+            match self.fetch(url.clone()).await {
+                Ok(page) => expanded.push_str(&format!("\n\n{}", describe(&page))),
+                Err(e) => warn!("Failed to fetch {}: {}", url, e),
+            }
+        }
+
+        expanded
+    }
+}
+
+/// The web links in `text`, leaving out Matrix permalinks, which are read
+/// from the room instead.
+pub fn links(text: &str) -> Vec<Url> {
+    text.split_whitespace()
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .filter(|word| !word.starts_with("https://matrix.to/"))
+        .filter_map(|word| Url::parse(word.trim_end_matches(['.', ',', ')', '>'])).ok())
+        .collect()
+}
+
+/// Introduce a page's text to the model.
+pub fn describe(page: &Page) -> String {
+    match &page.title {
+        Some(title) => format!("The page at {} ({}) reads:\n{}", page.url, title, page.text),
+        None => format!("The page at {} reads:\n{}", page.url, page.text),
+    }
+}
+
+fn title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = collapse(&decode_entities(&html[start..end]));
+
+    (!title.is_empty()).then_some(title)
+}
+
+/// Reduce an HTML page to the text a reader would see, leaving out
+/// navigation, scripts and the like.
+fn strip_html(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut text = String::new();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        text.push_str(&html[pos..start]);
+
+        let Some(end) = lower[start..].find('>').map(|e| start + e + 1) else {
+            pos = html.len();
+            break;
+        };
+
+        let name: String = lower[start + 1..end]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+
+        pos = match BOILERPLATE.contains(&name.as_str()) {
+            true => {
+                let close = format!("</{}", name);
+                lower[end..]
+                    .find(&close)
+                    .and_then(|c| lower[end + c..].find('>').map(|e| end + c + e + 1))
+                    .unwrap_or(html.len())
+            }
+            false => end,
+        };
+
+        // Words either side of a block element mustn't run together.
+        text.push(' ');
+    }
+
+    text.push_str(&html[pos.min(html.len())..]);
+
+    collapse(&decode_entities(&text))
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn local_ipv4_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{} is local", ip);
+        }
+
+        assert!(public("93.184.215.14"));
+    }
+
+    #[test]
+    fn local_ipv6_addresses_are_not_public() {
+        for ip in [
+            "::1",
+            "::",
+            "fd00::1",
+            "fc12:3456::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!public(ip), "{} is local", ip);
+        }
+
+        assert!(public("2606:4700::1111"));
+        assert!(public("::ffff:93.184.215.14"));
+    }
+
+    #[tokio::test]
+    async fn refuses_hosts_that_resolve_locally() {
+        let fetcher = Fetcher::new(Vec::new(), Vec::new());
+
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::ffff:7f00:1]:8080/",
+            "http://[fd00::1]/",
+            "http://localhost/",
+        ] {
+            assert!(
+                fetcher.fetch(url.parse().unwrap()).await.is_err(),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn holds_hosts_to_the_patterns() {
+        let hosts = Hosts {
+            allow: vec!["*.example.org".to_owned()],
+            deny: vec!["private.example.org".to_owned()],
+        };
+
+        assert!(hosts.allowed("www.example.org"));
+        assert!(!hosts.allowed("private.example.org"));
+        assert!(!hosts.allowed("example.com"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use log::{error, info, warn};
+use tokio::{net::TcpListener, time::interval};
+
+use crate::Defaults;
+
+/// How long the sync loop may go without completing a sync before it's
+/// considered stuck. Syncs long-poll for 30 seconds at most, so a healthy
+/// loop finishes several in this time.
+const SYNC_STALE_AFTER: Duration = Duration::from_secs(120);
+
+/// How often ollama is checked on in the background.
+const OLLAMA_CHECK_EVERY: Duration = Duration::from_secs(60);
+
+/// What `/healthz`, `/readyz` and `!llamastatus` report on, shared with the
+/// sync loop.
+#[derive(Clone)]
+pub struct Health {
+    last_sync: Arc<Mutex<Instant>>,
+    defaults: Arc<RwLock<Defaults>>,
+    /// Since when ollama has been unreachable, and why, if it still is.
+    ollama_down: Arc<Mutex<Option<(Instant, String)>>>,
+}
+
+impl Health {
+    pub fn new(defaults: Arc<RwLock<Defaults>>) -> Self {
+        Self {
+            last_sync: Arc::new(Mutex::new(Instant::now())),
+            defaults,
+            ollama_down: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// How long ollama has been unreachable for, and why, if it is.
+    pub fn ollama_down(&self) -> Option<(Duration, String)> {
+        let down = self.ollama_down.lock().unwrap();
+
+        down.as_ref().map(|(since, e)| (since.elapsed(), e.clone()))
+    }
+
+    /// Check that ollama can be reached every [`OLLAMA_CHECK_EVERY`], logging
+    /// when it can't and when it can again.
+    pub fn watch_ollama(&self) {
+        let health = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticks = interval(OLLAMA_CHECK_EVERY);
+
+            loop {
+                ticks.tick().await;
+
+                let backend = health.defaults.read().unwrap().backend.clone();
+                let result = backend.version().await;
+                let mut down = health.ollama_down.lock().unwrap();
+
+                match (result, down.is_some()) {
+                    (Ok(_), true) => {
+                        info!("ollama at {} is reachable again", backend.url());
+                        *down = None;
+                    }
+                    (Err(e), false) => {
+                        warn!("ollama at {} is unreachable: {}", backend.url(), e);
+                        *down = Some((Instant::now(), e.to_string()));
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// Note that the sync loop has just completed a sync.
+    pub fn synced(&self) {
+        *self.last_sync.lock().unwrap() = Instant::now();
+    }
+
+    fn syncing(&self) -> bool {
+        self.last_sync.lock().unwrap().elapsed() < SYNC_STALE_AFTER
+    }
+}
+
+/// Alive as long as the sync loop is.
+async fn healthz(State(health): State<Health>) -> (StatusCode, String) {
+    match health.syncing() {
+        true => (StatusCode::OK, "ok".to_owned()),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The sync loop has stalled".to_owned(),
+        ),
+    }
+}
+
+/// Ready when the sync loop is alive and ollama can be reached.
+async fn readyz(State(health): State<Health>) -> (StatusCode, String) {
+    if !health.syncing() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The sync loop has stalled".to_owned(),
+        );
+    }
+
+    let backend = health.defaults.read().unwrap().backend.clone();
+
+    match backend.version().await {
+        Ok(_) => (StatusCode::OK, "ok".to_owned()),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("ollama at {} is unreachable: {}", backend.url(), e),
+        ),
+    }
+}
+
+/// Serve `/healthz` and `/readyz` on `addr`, for orchestrators such as
+/// Kubernetes to probe.
+pub async fn serve(addr: SocketAddr, health: Health) -> Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health);
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Could not listen on {}", addr))?;
+
+    info!("Serving health checks on {}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Health check server failed: {}", e);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use matrix_sdk::{
+    Room,
+    ruma::{
+        OwnedEventId,
+        events::{
+            relation::Thread,
+            room::message::{Relation, ReplacementMetadata, RoomMessageEventContent},
+        },
+    },
+};
+use tokio::{task::JoinHandle, time::interval_at};
+
+use crate::stats::{self, Throughput};
+
+/// How often the status message is updated once it has been posted.
+const HEARTBEAT_EVERY: Duration = Duration::from_secs(20);
+
+/// How a generation ended, for the status message to say so.
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    Finished,
+    TimedOut,
+    Failed,
+}
+
+#[derive(Default)]
+struct Shared {
+    tokens: AtomicU64,
+    /// The thread the answer is in, if any, for the status message to go
+    /// in too.
+    thread: Option<Thread>,
+    /// The status message, once posted.
+    status: Mutex<Option<OwnedEventId>>,
+}
+
+async fn post_status(room: &Room, shared: &Shared, text: String) {
+    let mut content = RoomMessageEventContent::notice_plain(text);
+    let status = shared.status.lock().unwrap().clone();
+
+    let content = match status {
+        Some(event_id) => content.make_replacement(ReplacementMetadata::new(event_id, None), None),
+        None => {
+            content.relates_to = shared.thread.clone().map(Relation::Thread);
+            content
+        }
+    };
+
+    match room.send(content).await {
+        Ok(resp) => {
+            shared.status.lock().unwrap().get_or_insert(resp.event_id);
+        }
+        Err(e) => warn!("Failed to post progress to {}: {}", room.room_id(), e),
+    }
+}
+
+/// Posts a status message to a room if a generation runs for longer than a
+/// threshold, and keeps it up to date until the generation ends.
+///
+/// Like [`crate::typing::TypingNotice`], the heartbeat lives as long as this
+/// guard, however the generation ends. Unless told otherwise with
+/// [`Heartbeat::end`], it was stopped.
+pub struct Heartbeat {
+    room: Room,
+    shared: Arc<Shared>,
+    started: Instant,
+    task: JoinHandle<()>,
+    outcome: Option<Outcome>,
+}
+
+impl Heartbeat {
+    /// Start the heartbeat for a response from `model`, in `thread` if it's
+    /// given, whose throughput is used to estimate how much longer it will
+    /// take.
+    pub fn start(
+        room: Room,
+        thread: Option<Thread>,
+        after: Duration,
+        throughput: Throughput,
+        model: String,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            thread,
+            ..Shared::default()
+        });
+        let started = Instant::now();
+
+        let task = tokio::spawn({
+            let room = room.clone();
+            let shared = shared.clone();
+
+            async move {
+                let mut ticker = interval_at((started + after).into(), HEARTBEAT_EVERY);
+
+                loop {
+                    ticker.tick().await;
+
+                    let tokens = shared.tokens.load(Ordering::Relaxed);
+                    let text = match throughput.remaining(&model, tokens) {
+                        Some(eta) => format!(
+                            "Still working on it… (tokens so far: {}, about {} to go)",
+                            tokens,
+                            stats::describe(eta)
+                        ),
+                        None => format!("Still working on it… (tokens so far: {})", tokens),
+                    };
+
+                    post_status(&room, &shared, text).await;
+                }
+            }
+        });
+
+        Self {
+            room,
+            shared,
+            started,
+            task,
+            outcome: None,
+        }
+    }
+
+    /// Count another generated token.
+    pub fn tick(&self) {
+        self.shared.tokens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note how the generation ended, for the last update to say.
+    pub fn end(&mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.task.abort();
+
+        if self.shared.status.lock().unwrap().is_none() {
+            return;
+        }
+
+        let ended = match self.outcome {
+            Some(Outcome::Finished) => "Finished",
+            Some(Outcome::TimedOut) => "Timed out",
+            Some(Outcome::Failed) => "Failed",
+            None => "Stopped",
+        };
+        let text = format!(
+            "{} after {}s ({} tokens)",
+            ended,
+            self.started.elapsed().as_secs(),
+            self.shared.tokens.load(Ordering::Relaxed)
+        );
+
+        let room = self.room.clone();
+        let shared = self.shared.clone();
+        tokio::spawn(async move { post_status(&room, &shared, text).await });
+    }
+}
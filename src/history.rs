@@ -0,0 +1,232 @@
+use anyhow::Result;
+use log::warn;
+use matrix_sdk::{
+    Room,
+    deserialized_responses::TimelineEvent,
+    ruma::{
+        EventId, MatrixToUri, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, UInt, UserId,
+        api::client::relations::get_relating_events_with_rel_type,
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, AnyTimelineEvent, SyncMessageLikeEvent,
+            relation::RelationType,
+            room::message::{MessageType, RoomMessageEventContent},
+        },
+        matrix_uri::MatrixId,
+        serde::Raw,
+    },
+};
+
+use crate::media;
+
+/// The most messages that will be read from a single thread.
+const MAX_THREAD_MESSAGES: usize = 500;
+
+/// The most permalinks in a single prompt that will be expanded.
+const MAX_PERMALINKS: usize = 5;
+
+/// What the bot's answers are marked with in their content, to tell them
+/// from its other messages when they're read back.
+const ANSWER_MARK: &str = "io.github.hexagonal-sun.llamatrix.answer";
+
+/// A plain text message read back from a room's history.
+pub struct TextMessage {
+    pub event_id: OwnedEventId,
+    pub sender: OwnedUserId,
+    pub body: String,
+    pub ts: MilliSecondsSinceUnixEpoch,
+}
+
+impl TextMessage {
+    /// Whether this message was addressed to the bot as a command or prompt.
+    pub fn is_trigger(&self) -> bool {
+        self.body.starts_with("!llama")
+    }
+}
+
+/// `content` marked as one of the bot's answers, to be sent with
+/// [`Room::send_raw`].
+pub fn mark_answer(content: &RoomMessageEventContent) -> serde_json::Value {
+    let mut json = serde_json::to_value(content).unwrap();
+
+    if let Some(fields) = json.as_object_mut() {
+        fields.insert(ANSWER_MARK.to_owned(), true.into());
+    }
+
+    json
+}
+
+/// Whether `evt` is marked as one of the bot's answers by [`mark_answer`].
+fn is_answer(evt: &TimelineEvent) -> bool {
+    evt.raw()
+        .get_field::<serde_json::Map<String, serde_json::Value>>("content")
+        .ok()
+        .flatten()
+        .is_some_and(|content| content.get(ANSWER_MARK) == Some(&true.into()))
+}
+
+/// Extract a [`TextMessage`] from a paginated timeline event, if it is one.
+/// Notices only count if they're answers from the bot, `own_user`, as they
+/// are with --notices, not what it says about the queue or errors, nor
+/// anything other bots post.
+pub fn text_message(evt: &TimelineEvent, own_user: &UserId) -> Option<TextMessage> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(msg),
+    )) = evt.raw().deserialize().ok()?
+    else {
+        return None;
+    };
+
+    let body = match msg.content.msgtype {
+        MessageType::Text(txt) => txt.body,
+        MessageType::Notice(notice) if msg.sender == own_user && is_answer(evt) => notice.body,
+        _ => return None,
+    };
+
+    Some(TextMessage {
+        event_id: msg.event_id,
+        sender: msg.sender,
+        body,
+        ts: msg.origin_server_ts,
+    })
+}
+
+/// Render messages as a plain `sender: body` transcript, one per line.
+pub fn transcript(messages: &[TextMessage]) -> String {
+    messages
+        .iter()
+        .map(|msg| format!("{}: {}", msg.sender, msg.body))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The prompt used to ask the model for a summary of a transcript.
+pub fn summary_prompt(transcript: &str) -> String {
+    format!(
+        "Summarise the following chat conversation in a short paragraph, \
+         mentioning who said what where it matters:\n\n{}",
+        transcript
+    )
+}
+
+/// Decrypt an event fetched outside of the sync loop, if it needs it.
+async fn decrypt(rm: &Room, raw: Raw<AnyTimelineEvent>) -> TimelineEvent {
+    if let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
+        SyncMessageLikeEvent::Original(_),
+    ))) = raw.deserialize_as::<AnySyncTimelineEvent>()
+        && let Ok(evt) = rm.decrypt_event(raw.cast_ref()).await
+    {
+        return evt;
+    }
+
+    TimelineEvent::new(raw)
+}
+
+/// Fetch the text messages of the thread rooted at `root`, including the root
+/// itself, in chronological order.
+pub async fn thread_messages(rm: &Room, root: &EventId) -> Result<Vec<TextMessage>> {
+    let mut messages = Vec::new();
+    let mut from = None;
+
+    loop {
+        let mut request = get_relating_events_with_rel_type::v1::Request::new(
+            rm.room_id().to_owned(),
+            root.to_owned(),
+            RelationType::Thread,
+        );
+        request.from = from;
+        request.limit = Some(UInt::from(100u32));
+
+        let resp = rm.client().send(request, None).await?;
+
+        for raw in resp.chunk {
+            if let Some(msg) = text_message(&decrypt(rm, raw.cast()).await, rm.own_user_id()) {
+                messages.push(msg);
+            }
+        }
+
+        match resp.next_batch {
+            Some(next) if messages.len() < MAX_THREAD_MESSAGES => from = Some(next),
+            _ => break,
+        }
+    }
+
+    if let Some(msg) = text_message(&rm.event(root, None).await?, rm.own_user_id()) {
+        messages.push(msg);
+    }
+
+    messages.truncate(MAX_THREAD_MESSAGES);
+    messages.reverse();
+
+    Ok(messages)
+}
+
+/// The text of the event `event_id`, for a command replying to it: the body
+/// of a message, or the contents of a file if it is text.
+pub async fn event_text(rm: &Room, event_id: &EventId) -> Result<Option<String>> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(msg),
+    )) = rm.event(event_id, None).await?.raw().deserialize()?
+    else {
+        return Ok(None);
+    };
+
+    Ok(match msg.content.msgtype {
+        MessageType::Text(txt) => Some(txt.body),
+        MessageType::Notice(notice) => Some(notice.body),
+        MessageType::File(file) => {
+            let size = file.info.as_ref().and_then(|info| info.size);
+            let bytes = media::download(&rm.client(), &file, size).await?;
+
+            String::from_utf8(bytes).ok()
+        }
+        _ => None,
+    })
+}
+
+/// Find the events linked to from `prompt` by `matrix.to` permalinks that
+/// point into `rm`. Links to other rooms are ignored so that the bot can't be
+/// used to read rooms the asker isn't in.
+fn linked_events(rm: &Room, prompt: &str) -> Vec<OwnedEventId> {
+    let alias = rm.canonical_alias();
+
+    prompt
+        .split_whitespace()
+        .filter(|word| word.starts_with("https://matrix.to/#/"))
+        .filter_map(|word| MatrixToUri::parse(word.trim_end_matches(['.', ',', ')'])).ok())
+        .filter_map(|uri| match uri.id() {
+            MatrixId::Event(room, event_id)
+                if room.as_str() == rm.room_id().as_str()
+                    || alias.as_ref().is_some_and(|a| a.as_str() == room.as_str()) =>
+            {
+                Some(event_id.clone())
+            }
+            _ => None,
+        })
+        .take(MAX_PERMALINKS)
+        .collect()
+}
+
+/// Append the contents of any messages permalinked from `prompt`, so the
+/// model can see what the user is pointing at.
+pub async fn expand_permalinks(rm: &Room, prompt: &str) -> String {
+    let mut expanded = prompt.to_owned();
+
+    for event_id in linked_events(rm, prompt) {
+        match rm
+            .event(&event_id, None)
+            .await
+            .map(|evt| text_message(&evt, rm.own_user_id()))
+        {
+            Ok(Some(msg)) => {
+                expanded.push_str(&format!(
+                    "\n\nThe linked message {} from {} reads:\n{}",
+                    event_id, msg.sender, msg.body
+                ));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to fetch linked event {}: {}", event_id, e),
+        }
+    }
+
+    expanded
+}
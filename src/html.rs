@@ -0,0 +1,53 @@
+/// Escape text for inclusion in an HTML message body.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render `code` as a code block, highlighted as `language`.
+pub fn code_block(language: &str, code: &str) -> String {
+    format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>",
+        language,
+        escape(code)
+    )
+}
+
+/// Render `(name, value)` pairs as a two column table.
+pub fn table(rows: &[(&str, String)]) -> String {
+    let rows: String = rows
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(name),
+                escape(value)
+            )
+        })
+        .collect();
+
+    format!("<table>{}</table>", rows)
+}
+
+/// Render `rows` as a table with a row of `headings` above them.
+pub fn grid(headings: &[&str], rows: &[Vec<String>]) -> String {
+    let head: String = headings
+        .iter()
+        .map(|heading| format!("<th>{}</th>", escape(heading)))
+        .collect();
+    let body: String = rows
+        .iter()
+        .map(|row| {
+            let cells: String = row
+                .iter()
+                .map(|cell| format!("<td>{}</td>", escape(cell)))
+                .collect();
+
+            format!("<tr>{}</tr>", cells)
+        })
+        .collect();
+
+    format!("<table><tr>{}</tr>{}</table>", head, body)
+}
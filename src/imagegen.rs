@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+/// How long an image may take to generate before giving up on it.
+const GENERATE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// An image generation server speaking the Stable Diffusion web UI's
+/// `txt2img` API, such as AUTOMATIC1111's web UI or Forge.
+#[derive(Clone)]
+pub struct ImageGenerator {
+    client: Client,
+    url: Url,
+}
+
+#[derive(Serialize)]
+struct Txt2ImgRequest<'a> {
+    prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Txt2ImgResponse {
+    /// Base64 encoded PNGs.
+    images: Vec<String>,
+}
+
+impl ImageGenerator {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+        }
+    }
+
+    /// Generate an image of `prompt`, as a PNG.
+    pub async fn generate(&self, prompt: &str) -> Result<Vec<u8>> {
+        let resp = self
+            .client
+            .post(self.url.join("/sdapi/v1/txt2img").unwrap())
+            .json(&Txt2ImgRequest { prompt })
+            .timeout(GENERATE_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Txt2ImgResponse>()
+            .await?;
+
+        let image = resp
+            .images
+            .first()
+            .context("The server generated no images")?;
+
+        Ok(BASE64_STANDARD.decode(image)?)
+    }
+}
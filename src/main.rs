@@ -1,263 +1,3982 @@
 use std::{
     collections::HashMap,
-    fs::{self, File},
+    fs,
+    net::SocketAddr,
     path::PathBuf,
-    time::Duration,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use llama::Chat;
-use log::{error, warn};
+use access::{Bans, Pattern};
+use admin::{Broadcasts, Maintenance};
+use anyhow::{Context, Result, bail};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use budget::{Budgets, GlobalBudget, Standing};
+use cache::ResponseCache;
+use cancel::{Cancellable, Cancels};
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::Live;
+use contexts::{Contexts, SlotAction};
+use extract::Extraction;
+use fairqueue::FairQueue;
+use fetch::Fetcher;
+use health::Health;
+use heartbeat::{Heartbeat, Outcome};
+use imagegen::ImageGenerator;
+use llama::{Backend, Chat, Failure, Options, Routing, TimedOut};
+use log::{error, info, warn};
 use matrix_sdk::{
-    Client, Room, ServerName,
+    Client, LoopCtrl, Room, ServerName,
+    attachment::AttachmentConfig,
     config::SyncSettings,
     event_handler::Ctx,
-    matrix_auth::MatrixSession,
     ruma::{
-        OwnedRoomId, UserId,
-        events::room::{
-            member::StrippedRoomMemberEvent,
-            message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+        api::client::filter::FilterDefinition,
+        events::{
+            relation::{InReplyTo, Thread},
+            room::{
+                member::StrippedRoomMemberEvent,
+                message::{
+                    AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent,
+                    MessageType, NoticeMessageEventContent, OriginalSyncRoomMessageEvent, Relation,
+                    ReplacementMetadata, RoomMessageEventContent,
+                    RoomMessageEventContentWithoutRelation,
+                },
+            },
         },
     },
 };
+use ratelimit::RateLimiter;
+use reactions::{Answer, Answers};
+use receipts::ReadMarkers;
 use reqwest::Url;
+use retrieval::{Indexer, VectorStore};
+use schedule::Scheduler;
+use shutdown::Shutdown;
+use stats::Throughput;
+use store::{RoomSettings, Trigger, UserProfile, Verbosity};
+use stream::{Paragraphs, Reply, StreamMode};
 use tokio::{
     select,
     sync::{
-        mpsc::{self, Receiver},
-        oneshot::{self, channel},
+        Mutex as AsyncMutex, Notify,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        oneshot, watch,
     },
-    time::sleep,
+    task::{self, JoinSet},
 };
+use tools::Tools;
+use transcribe::Transcriber;
+use trust::TrustMode;
+use typing::TypingNotice;
+use verification::{VerificationPolicy, Verifier};
+use warmup::Warmer;
+use websearch::WebSearch;
+use wizard::{Progress, Wizard, Wizards};
 
+mod access;
+mod admin;
+mod appservice;
+mod budget;
+mod cache;
+mod cancel;
+mod catchup;
+mod commands;
+mod config;
+mod contexts;
+mod dispatch;
+mod dm;
+mod documents;
+mod extract;
+mod fairqueue;
+mod fetch;
+mod health;
+mod heartbeat;
+mod history;
+mod html;
+mod imagegen;
 mod llama;
+mod mcp;
+mod media;
+mod models;
+mod presence;
+mod pull;
+mod ratelimit;
+mod reactions;
+mod receipts;
+mod retrieval;
+mod schedule;
+mod secret;
+mod session;
+mod shutdown;
+mod socket;
+mod sso;
+mod stats;
+mod status;
+mod store;
+mod stream;
+mod supervisor;
+mod tools;
+mod transcribe;
+mod trust;
+mod typing;
+mod verification;
+mod warmup;
+mod websearch;
+mod wizard;
 
 #[derive(Parser)]
+#[clap(args_override_self = true, subcommand_negates_reqs = true)]
+#[clap(group(ArgGroup::new("password_source").args(["password", "password_env", "password_file", "password_keyring"])))]
+#[clap(group(
+    ArgGroup::new("access_token_source")
+        .args(["access_token_env", "access_token_file", "access_token_keyring"])
+        .requires("device_id")
+        .conflicts_with_all(["password_source", "sso"])
+))]
+#[clap(group(
+    ArgGroup::new("appservice_token_source")
+        .args(["appservice_token_env", "appservice_token_file", "appservice_token_keyring"])
+        .conflicts_with_all(["password_source", "access_token_source", "sso"])
+))]
+#[clap(group(ArgGroup::new("ollama_token_source").args(["ollama_token_env", "ollama_token_file", "ollama_token_keyring"])))]
+#[clap(group(
+    ArgGroup::new("brave_api_key_source")
+        .args(["brave_api_key_env", "brave_api_key_file", "brave_api_key_keyring"])
+        .requires("tools")
+))]
+#[clap(group(ArgGroup::new("recovery_key_source").args(["recovery_key_env", "recovery_key_file", "recovery_key_keyring"])))]
+#[clap(group(ArgGroup::new("session_passphrase_source").args(["session_passphrase_env", "session_passphrase_file", "session_passphrase_keyring"])))]
 /// An ollama bridge bot for Matrix
 struct Args {
+    #[clap(subcommand)]
+    action: Option<Action>,
+
     /// The Matrix username of the account that the bot should use.
-    #[clap(long, short)]
-    username: String,
+    #[clap(long, global = true, short)]
+    username: Option<String>,
 
-    /// The password of the Matrix account.
-    #[clap(long, short)]
-    password: String,
+    /// The password of the Matrix account. Anyone on the machine can see it
+    /// here, so prefer one of the other --password-* flags.
+    #[clap(long, global = true, short)]
+    password: Option<String>,
+
+    /// Read the password from this environment variable.
+    #[clap(long, global = true, value_name = "VAR")]
+    password_env: Option<String>,
+
+    /// Read the password from this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from this entry in the OS keyring.
+    #[clap(long, global = true, value_name = "ENTRY")]
+    password_keyring: Option<String>,
+
+    /// Read an access token to use instead of logging in, along with
+    /// `--device-id`, from this environment variable, such as one from
+    /// another client's session. There's no flag for the token itself, as
+    /// anyone on the machine could see it on the command line.
+    #[clap(long, global = true, value_name = "VAR")]
+    access_token_env: Option<String>,
+
+    /// Read the access token from this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    access_token_file: Option<PathBuf>,
+
+    /// Read the access token from this entry in the OS keyring.
+    #[clap(long, global = true, value_name = "ENTRY")]
+    access_token_keyring: Option<String>,
+
+    /// The ID of the device the access token belongs to.
+    #[clap(long, global = true)]
+    device_id: Option<OwnedDeviceId>,
+
+    /// Log in with the homeserver's single sign-on instead of a password,
+    /// printing a URL to open in a browser. Only needed the first time, as
+    /// the session is saved.
+    #[clap(
+        long,
+        global = true,
+        conflicts_with_all = ["password", "password_env", "password_file", "password_keyring"]
+    )]
+    sso: bool,
+
+    /// The port on localhost that the browser is sent back to after logging
+    /// in with SSO. Without it, any free port is used.
+    #[clap(long, global = true, requires = "sso")]
+    sso_port: Option<u16>,
+
+    /// The identity provider to log in with when the homeserver offers
+    /// several for SSO.
+    #[clap(long, global = true, requires = "sso")]
+    sso_idp: Option<String>,
+
+    /// Read the `as_token` of an application service from this environment
+    /// variable, to log in as the `--username` in its namespace instead of
+    /// with a password, registering the user if it doesn't exist yet.
+    #[clap(long, global = true, value_name = "VAR")]
+    appservice_token_env: Option<String>,
+
+    /// Read the application service's `as_token` from this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    appservice_token_file: Option<PathBuf>,
+
+    /// Read the application service's `as_token` from this entry in the OS
+    /// keyring.
+    #[clap(long, global = true, value_name = "ENTRY")]
+    appservice_token_keyring: Option<String>,
 
     /// The homeserver upon which the Matrix acounts resides.
-    #[clap(long, short = 's', default_value = "matrix.org")]
+    #[clap(long, global = true, short = 's', default_value = "matrix.org")]
     homeserver: String,
 
-    /// The LLM to use with ollama.
+    /// An HTTP or SOCKS5 proxy to reach the homeserver through, such as
+    /// `http://localhost:8118` or Tor's `socks5h://localhost:9050`. Requests
+    /// to ollama don't use it.
+    #[clap(long, global = true, value_parser = parse_proxy)]
+    proxy: Option<Url>,
+
+    /// Read a passphrase to encrypt the saved session and the local store
+    /// with from this environment variable, so that the access token and
+    /// encryption keys aren't readable on disk. It must be given from the
+    /// first login on, and every time after. There's no flag for the
+    /// passphrase itself, as anyone on the machine could see it on the
+    /// command line.
+    #[clap(long, global = true, value_name = "VAR")]
+    session_passphrase_env: Option<String>,
+
+    /// Read the session passphrase from this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    session_passphrase_file: Option<PathBuf>,
+
+    /// Read the session passphrase from this entry in the OS keyring.
+    #[clap(long, global = true, value_name = "ENTRY")]
+    session_passphrase_keyring: Option<String>,
+
+    /// The LLM to use with ollama. Needed to run the bot, but not for the
+    /// other subcommands.
     #[clap(long, short)]
-    model: String,
+    model: Option<String>,
+
+    /// The URL of the ollama server, or `unix:///path/to/ollama.sock` for one
+    /// listening on a unix socket. May be repeated, in which case requests go
+    /// to the first server that can be reached, failing over to the next when
+    /// it can't.
+    #[clap(long = "url", short = 'o', default_value = "http://localhost:11434", value_parser = Url::parse)]
+    urls: Vec<Url>,
+
+    /// How often, in seconds, the bot advances its fully-read marker in rooms
+    /// with new activity.
+    #[clap(long, default_value_t = 300)]
+    read_marker_interval: u64,
+
+    /// How often, in seconds, the bot's presence status message is refreshed
+    /// with the model it serves and how many requests are queued.
+    #[clap(long, default_value_t = 60)]
+    presence_interval: u64,
+
+    /// How responses are delivered while they are being generated.
+    #[clap(long, value_enum, default_value_t = StreamMode::Off)]
+    stream_mode: StreamMode,
+
+    /// The maximum number of generations that may run against the ollama
+    /// server at once. Further requests wait until a slot becomes free.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent: u32,
+
+    /// How generations are spread between the servers given with --url.
+    #[clap(long, value_enum, default_value_t = Routing::Failover)]
+    routing: Routing,
+
+    /// The most generations a server may run at once, for servers that can
+    /// take fewer than --max-concurrent. May be repeated, applying to each
+    /// --url in the order they are given.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    server_max_concurrent: Vec<u32>,
+
+    /// How long ollama keeps a model loaded after answering, such as `10m`,
+    /// or a number of seconds, with `-1` keeping it loaded for ever. Without
+    /// it, ollama's own default applies.
+    #[clap(long)]
+    keep_alive: Option<String>,
+
+    /// Read the bearer token to send to ollama, for servers behind a proxy
+    /// that wants one, from this environment variable. Without any of the
+    /// --ollama-token-* flags, it is taken from OLLAMA_TOKEN, if set.
+    #[clap(long, value_name = "VAR")]
+    ollama_token_env: Option<String>,
+
+    /// Read the bearer token to send to ollama from this file.
+    #[clap(long, value_name = "PATH")]
+    ollama_token_file: Option<PathBuf>,
+
+    /// Read the bearer token to send to ollama from this entry in the OS
+    /// keyring.
+    #[clap(long, value_name = "ENTRY")]
+    ollama_token_keyring: Option<String>,
+
+    /// A file of extra headers to send to ollama, one `Name: value` to a
+    /// line, for proxies that want credentials other than a bearer token.
+    #[clap(long)]
+    ollama_headers_file: Option<PathBuf>,
+
+    /// A PEM file of CA certificates to trust for `https` ollama URLs,
+    /// besides the usual ones, such as that of a private CA.
+    #[clap(long)]
+    ollama_ca_cert: Option<PathBuf>,
+
+    /// A PEM file holding a client certificate and its private key, for
+    /// ollama servers that ask the bot to identify itself.
+    #[clap(long)]
+    ollama_client_cert: Option<PathBuf>,
+
+    /// Accept any certificate from ollama servers, even invalid or
+    /// self-signed ones. Only for testing, as anyone in between can then read
+    /// the prompts.
+    #[clap(long)]
+    ollama_insecure: bool,
+
+    /// Download the models the bot is configured with if ollama doesn't
+    /// have them yet, before the bot starts answering. Without it, missing
+    /// models are only warned about.
+    #[clap(long)]
+    pull: bool,
+
+    /// Load the model as soon as the bot starts, so that the first prompt
+    /// doesn't wait for it to load.
+    #[clap(long)]
+    warm_up: bool,
+
+    /// With --warm-up, load the model again whenever no prompt has come in
+    /// for this many seconds, in case ollama has unloaded it since.
+    #[clap(long, requires = "warm_up", value_parser = clap::value_parser!(u64).range(1..))]
+    warm_up_idle: Option<u64>,
+
+    /// Tell users the bot is busy, and where their prompt is in the queue,
+    /// once at least this many prompts are ahead of theirs.
+    #[clap(long, default_value_t = 5)]
+    busy_after: usize,
+
+    /// Turn prompts away while this many are waiting in the queue. Admins'
+    /// prompts are always queued.
+    #[clap(long, default_value_t = 1024)]
+    queue_limit: usize,
+
+    /// How many rooms may be answered at once. Requests from the same room
+    /// are still answered one at a time, in order. Generations only run side
+    /// by side if --max-concurrent allows it too.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    workers: u32,
+
+    /// A Matrix user ID with operator rights over the bot. Requests from
+    /// admins skip ahead of everyone else's in the queue. May be repeated.
+    #[clap(long = "admin")]
+    admins: Vec<OwnedUserId>,
+
+    /// The ollama model used to embed room history for search and retrieval.
+    /// History indexing is unavailable unless this is set.
+    #[clap(long)]
+    embed_model: Option<String>,
+
+    /// The maximum number of messages embedded per minute by the background
+    /// history indexer.
+    #[clap(long, default_value_t = 120, value_parser = clap::value_parser!(u32).range(1..))]
+    index_rate: u32,
+
+    /// When set, a prompt in a group room that arrives at least this many
+    /// seconds after the bot last spoke there first has the conversation it
+    /// missed summarized into its context.
+    #[clap(long)]
+    catch_up_after: Option<u64>,
+
+    /// Ignore messages sent more than this many seconds ago, such as those
+    /// that arrive all at once when the bot starts again after being down,
+    /// rather than answering prompts nobody is waiting on anymore.
+    #[clap(long)]
+    max_prompt_age: Option<u64>,
+
+    /// Send responses as replies to the prompts they answer, so that clients
+    /// show the prompt quoted above them. Only the first message of a
+    /// response quotes it.
+    #[clap(long)]
+    reply_to_prompts: bool,
+
+    /// Post answers and replies to commands as notices rather than text
+    /// messages, as bots are meant to, so that other bots know not to answer
+    /// them in turn.
+    #[clap(long)]
+    notices: bool,
+
+    /// React to prompts with 👀 as soon as they're seen, even while they wait
+    /// in the queue, then with ✅ once answered, or ⚠️ if they couldn't be.
+    #[clap(long)]
+    ack_reactions: bool,
+
+    /// Have the homeserver leave out the member lists of rooms when syncing,
+    /// sending only the members whose messages are in the timeline. On an
+    /// account in many or large rooms, this makes the first sync much
+    /// quicker. Members are fetched when they're needed, such as to share
+    /// keys in encrypted rooms.
+    #[clap(long)]
+    lazy_load_members: bool,
+
+    /// A model that users may pick as their personal default with
+    /// `!llamaprefs model`, besides the one given by --model. May be repeated.
+    #[clap(long = "user-model")]
+    user_models: Vec<String>,
+
+    /// The model that answers prompts with images in them, such as `llava`.
+    /// Without one, images go to the usual model, which has to be able to
+    /// see them itself.
+    #[clap(long)]
+    vision_model: Option<String>,
+
+    /// The URL of a Stable Diffusion web UI compatible server, which
+    /// `!llamaimage` generates images with. The command is unavailable
+    /// unless this is set.
+    #[clap(long, value_parser = Url::parse)]
+    image_url: Option<Url>,
+
+    /// The URL of an OpenAI compatible speech-to-text server, such as
+    /// whisper.cpp's, which voice messages are transcribed with. Voice
+    /// messages are ignored unless this is set.
+    #[clap(long, value_parser = Url::parse)]
+    transcription_url: Option<Url>,
+
+    /// The model the speech-to-text server transcribes with.
+    #[clap(long, default_value = "whisper-1")]
+    transcription_model: String,
+
+    /// Let the model call on tools, such as a clock, while answering. The
+    /// model has to support tool calling.
+    #[clap(long)]
+    tools: bool,
+
+    /// Give the model a web search tool backed by the SearxNG instance at
+    /// this URL, which must have its JSON output format enabled.
+    #[clap(long, value_parser = Url::parse, requires = "tools", conflicts_with = "brave_api_key_source")]
+    searxng_url: Option<Url>,
+
+    /// Give the model a web search tool backed by Brave's search API, with
+    /// the subscription token read from this environment variable. There's
+    /// no flag for the token itself, as anyone on the machine could see it
+    /// on the command line.
+    #[clap(long, value_name = "VAR")]
+    brave_api_key_env: Option<String>,
+
+    /// Read the Brave subscription token from this file.
+    #[clap(long, value_name = "PATH")]
+    brave_api_key_file: Option<PathBuf>,
+
+    /// Read the Brave subscription token from this entry in the OS keyring.
+    #[clap(long, value_name = "ENTRY")]
+    brave_api_key_keyring: Option<String>,
 
-    /// The URL of the ollama server
-    #[clap(long, short = 'o', default_value = "http://localhost:11434", value_parser = Url::parse)]
-    url: Url,
+    /// Read the web pages linked to from prompts, and allow `!llamasummarize`.
+    /// Local addresses are never fetched from.
+    #[clap(long)]
+    fetch_urls: bool,
+
+    /// Only fetch pages from hosts matching this pattern, which may contain
+    /// `*` wildcards, as in `*.wikipedia.org`. May be repeated.
+    #[clap(long = "fetch-allow", requires = "fetch_urls")]
+    fetch_allow: Vec<String>,
+
+    /// Never fetch pages from hosts matching this pattern. May be repeated.
+    #[clap(long = "fetch-deny", requires = "fetch_urls")]
+    fetch_deny: Vec<String>,
+
+    /// How many seconds the bot waits, once asked to shut down, for the
+    /// prompts already queued to be answered before cancelling them.
+    #[clap(long, default_value_t = 30)]
+    shutdown_grace: u64,
+
+    /// Serve `/healthz` and `/readyz` on this address, such as
+    /// `0.0.0.0:8080`. `/healthz` fails if the sync loop has stalled, and
+    /// `/readyz` also fails if ollama can't be reached.
+    #[clap(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// A room in which the bot asks its admins to make decisions on its
+    /// behalf.
+    #[clap(long)]
+    admin_room: Option<OwnedRoomId>,
+
+    /// Only accept invites from users matching this pattern, either a user ID
+    /// or a server name, each of which may contain `*` wildcards. Admins can
+    /// always invite the bot. May be repeated; without it, anyone can.
+    #[clap(long)]
+    allow_invites_from: Vec<Pattern>,
+
+    /// Only answer users matching this pattern, in the same form as
+    /// --allow-invites-from. May be repeated; without it, everyone is
+    /// answered.
+    #[clap(long = "allow-user")]
+    allow_users: Vec<Pattern>,
+
+    /// Never answer users matching this pattern, even if --allow-user does.
+    /// May be repeated.
+    #[clap(long = "deny-user")]
+    deny_users: Vec<Pattern>,
+
+    /// What users who may not talk to the bot are told. Without it, they
+    /// are ignored.
+    #[clap(long)]
+    refusal_message: Option<String>,
+
+    /// Report invites rejected by --allow-invites-from to the admin room.
+    #[clap(long, requires = "admin_room")]
+    report_rejected_invites: bool,
+
+    /// How the bot answers requests to verify its device. Requests the policy
+    /// doesn't accept outright are rejected, or with `prompt`, put to the
+    /// admin room, where an admin then compares the emoji too.
+    #[clap(long, value_enum, default_value_t = VerificationPolicy::Admins)]
+    verification_policy: VerificationPolicy,
+
+    /// Which devices may read the bot's messages in encrypted rooms: every
+    /// device, trusted the first time it is seen (`tofu`), or only verified
+    /// ones.
+    #[clap(long, global = true, value_enum, default_value_t = TrustMode::Tofu)]
+    trust_mode: TrustMode,
+
+    /// Don't enable encryption in the direct chats the bot starts itself.
+    #[clap(long)]
+    unencrypted_dms: bool,
+
+    /// Read the account's recovery key or passphrase from this environment
+    /// variable, to restore the bot's encryption secrets from secret storage
+    /// and decrypt history from the key backup, such as after logging in on
+    /// a new device. There's no flag for the key itself, as anyone on the
+    /// machine could see it on the command line.
+    #[clap(long, value_name = "VAR")]
+    recovery_key_env: Option<String>,
+
+    /// Read the recovery key from this file.
+    #[clap(long, value_name = "PATH")]
+    recovery_key_file: Option<PathBuf>,
+
+    /// Read the recovery key from this entry in the OS keyring.
+    #[clap(long, value_name = "ENTRY")]
+    recovery_key_keyring: Option<String>,
+
+    /// Send a direct message to a user from the bot account and exit, rather
+    /// than running the bot.
+    #[clap(long, num_args = 2, value_names = ["USER", "MESSAGE"])]
+    send_dm: Option<Vec<String>>,
+
+    /// Send an announcement to every room the bot has joined and exit, rather
+    /// than running the bot.
+    #[clap(long, value_name = "MESSAGE", conflicts_with = "send_dm")]
+    broadcast: Option<String>,
+
+    /// What the bot answers prompts with while in maintenance mode, unless
+    /// another message is given when it is turned on. Takes precedence over
+    /// the config file's `maintenance_message`.
+    #[clap(long)]
+    maintenance_message: Option<String>,
+
+    /// Keep up to this many answers to questions asked without any earlier
+    /// conversation, and answer the same question from them if it's asked
+    /// again.
+    #[clap(long)]
+    response_cache: Option<usize>,
+
+    /// How many seconds cached answers are reused for.
+    #[clap(long, default_value_t = 3600)]
+    response_cache_ttl: u64,
+
+    /// A TOML file of settings. Any of these flags can be given in it, by its
+    /// name with underscores, and flags on the command line take precedence.
+    /// Admins, user models, the default persona and quota, named personas,
+    /// generation options and the maintenance message are reloaded whenever
+    /// it changes.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Which of the accounts listed in the config file to act for. Without
+    /// it, the bot runs as all of them, and the subcommands need it to pick
+    /// one.
+    #[clap(long, global = true, value_name = "NAME")]
+    account: Option<String>,
+
+    /// The directory the session, local store and other state are kept in.
+    /// Each account listed in the config file keeps its own in a
+    /// subdirectory named after it. Defaults to `llamatrix` in the user's
+    /// data directory.
+    #[clap(long, global = true, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// The system prompt for rooms that haven't set their own, giving the bot
+    /// a persona or instructions. Takes precedence over the config file's
+    /// `persona`.
+    #[clap(long)]
+    system_prompt: Option<String>,
+
+    /// How many tokens of conversation models are given, unless a model's
+    /// `num_ctx` option says otherwise. The oldest exchanges are dropped to
+    /// keep within it, leaving a quarter for the answer. 4096 unless the
+    /// config file's `context_window` says otherwise.
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(64..))]
+    context_window: Option<usize>,
+
+    /// Pass an option such as `top_k=40` or `repeat_penalty=1.1` to ollama
+    /// with every request, in place of the same option in the config file's
+    /// `[options]` but beneath those it gives for the model or persona. May
+    /// be repeated; unknown options are refused.
+    #[clap(long = "option", value_name = "NAME=VALUE", value_parser = Options::parse_arg)]
+    options: Vec<Options>,
+
+    /// How many tokens each room may use per day, unless an admin sets a
+    /// different budget for it.
+    #[clap(long)]
+    room_token_budget: Option<u64>,
+
+    /// How many tokens the bot may use per day across all rooms, after which
+    /// it declines prompts until midnight UTC.
+    #[clap(long)]
+    daily_token_budget: Option<u64>,
+
+    /// How many seconds the bot may spend generating per day across all
+    /// rooms, after which it declines prompts until midnight UTC.
+    #[clap(long)]
+    daily_generation_secs: Option<u64>,
+
+    /// After this many seconds, a generation that is still running posts a
+    /// progress message to the room, kept up to date until it finishes. Zero
+    /// disables progress messages.
+    #[clap(long, default_value_t = 60)]
+    heartbeat_after: u64,
+
+    /// Give up on a generation that is still running after this many
+    /// seconds, telling the room it timed out. The time counts from when the
+    /// generation gets one of the `--max-concurrent` slots, not from when it
+    /// was queued. Without it, generations may run for as long as ollama
+    /// takes.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    llm_timeout: Option<u64>,
+
+    /// How many times to try a request to ollama again when it can't be
+    /// reached or answers with a server error, waiting longer each time,
+    /// before giving up.
+    #[clap(long, default_value_t = 3)]
+    llm_retries: u32,
+}
+
+/// How many matches `!llamasearch` returns.
+const SEARCH_RESULTS: usize = 5;
+
+/// What to do, other than running the bot. The flags for logging in may
+/// come after the subcommand, the rest go before it.
+#[derive(Clone, Subcommand)]
+enum Action {
+    /// Run the bot, logging in first if there is no saved session. This is
+    /// what happens without a subcommand.
+    Run,
+    /// Log in and save the session, then exit.
+    Login,
+    /// Log the bot's device out on the homeserver and clear the saved
+    /// session away.
+    Logout,
+    /// Wait for a verification request from another client and verify the
+    /// bot's device with it, comparing emoji on the terminal.
+    Verify,
+    /// Delete the saved session and the local store of encryption keys,
+    /// without telling the homeserver, such as after the device has been
+    /// removed from the account elsewhere.
+    ClearSession,
+    /// Print a registration file for an application service, for the
+    /// homeserver to give bot users a namespace of their own, then exit.
+    Registration {
+        /// The application service's ID, also the localpart of its own user.
+        #[clap(long, default_value = "llamatrix")]
+        id: String,
+        /// What the names of the application service's users start with.
+        #[clap(long, default_value = "llama-")]
+        prefix: String,
+    },
+}
+
+impl Args {
+    /// What the bot asks the homeserver for when syncing.
+    fn sync_settings(&self) -> SyncSettings {
+        let settings = SyncSettings::default();
+
+        match self.lazy_load_members {
+            true => settings.filter(FilterDefinition::with_lazy_loading().into()),
+            false => settings,
+        }
+    }
+
+    /// Where the session, local store and other state are kept.
+    fn data_dir(&self) -> PathBuf {
+        self.data_dir
+            .clone()
+            .unwrap_or_else(|| dirs::data_dir().unwrap().join("llamatrix"))
+    }
 }
 
-fn get_data_dir() -> PathBuf {
-    dirs::data_dir().unwrap().join("llamatrix")
+/// Parse `--proxy`, which must be an HTTP or SOCKS5 proxy. With `socks5h`,
+/// host names are resolved by the proxy rather than locally, as Tor needs.
+fn parse_proxy(arg: &str) -> Result<Url, String> {
+    let url = Url::parse(arg).map_err(|e| e.to_string())?;
+
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(url),
+        scheme => Err(format!(
+            "{} isn't a proxy scheme, use http, https, socks5 or socks5h",
+            scheme
+        )),
+    }
 }
 
 enum LlamaReq {
-    Chat(LlamaChatReq),
-    ClrCtx(OwnedRoomId),
+    Chat(Box<LlamaChatReq>),
+    /// Clear the room's current conversation, or the thread's if a root is
+    /// given.
+    ClrCtx(OwnedRoomId, Option<OwnedEventId>),
+    /// Remove the last exchange from the room's current conversation, or the
+    /// thread's, as long as it's still the answer to the given prompt event.
+    ForgetLast(OwnedRoomId, Option<OwnedEventId>, OwnedEventId),
+    /// Switch the default model, and the server if a URL is given, for every
+    /// request from here on. `done` is signalled once the switch is made.
+    SetDefault {
+        model: String,
+        url: Option<Url>,
+        done: oneshot::Sender<()>,
+    },
+    /// Add a document to the room's current conversation, or the thread's,
+    /// as one system message per chunk.
+    Ingest {
+        room_id: OwnedRoomId,
+        thread: Option<OwnedEventId>,
+        chunks: Vec<String>,
+    },
+    /// Change the room's conversation slots, replying with the outcome.
+    Slots {
+        room_id: OwnedRoomId,
+        action: SlotAction,
+        reply: oneshot::Sender<String>,
+    },
+    /// Generate an image of `prompt`, replying with it as a PNG.
+    Image {
+        room_id: OwnedRoomId,
+        generator: ImageGenerator,
+        prompt: String,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
 }
 
-struct LlamaChatReq {
-    room_id: OwnedRoomId,
-    prompt: String,
-    reply_tx: oneshot::Sender<String>,
+impl From<LlamaChatReq> for LlamaReq {
+    fn from(req: LlamaChatReq) -> Self {
+        LlamaReq::Chat(Box::new(req))
+    }
 }
 
-impl LlamaChatReq {
-    fn new(room_id: OwnedRoomId, prompt: impl ToString) -> (LlamaReq, oneshot::Receiver<String>) {
-        let (tx, rx) = channel();
-        (
-            LlamaReq::Chat(Self {
-                room_id,
-                prompt: prompt.to_string(),
-                reply_tx: tx,
-            }),
-            rx,
-        )
+impl LlamaReq {
+    /// The room the request is from, for all but [`LlamaReq::SetDefault`].
+    fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            LlamaReq::Chat(req) => Some(&req.room_id),
+            LlamaReq::ClrCtx(room_id, _)
+            | LlamaReq::ForgetLast(room_id, ..)
+            | LlamaReq::Ingest { room_id, .. }
+            | LlamaReq::Slots { room_id, .. }
+            | LlamaReq::Image { room_id, .. } => Some(room_id),
+            LlamaReq::SetDefault { .. } => None,
+        }
+    }
+}
+
+/// The requests waiting to be answered, shared by both halves of the queue.
+#[derive(Default)]
+struct Waiting {
+    /// Keyed by room, to take turns between them. Requests from admins are
+    /// served before anyone else's.
+    requests: Mutex<FairQueue<Option<OwnedRoomId>, LlamaReq>>,
+    /// Notified whenever a request is added.
+    added: Notify,
+    /// Set once [`llama_task`] stops taking requests.
+    closed: AtomicBool,
+}
+
+/// The sending half of the request queue feeding [`llama_task`].
+#[derive(Clone)]
+struct LlamaQueue {
+    waiting: Arc<Waiting>,
+    cancels: Cancels,
+    /// How many requests are answered at once, for telling how long a wait
+    /// will be.
+    workers: usize,
+}
+
+/// The receiving half of [`LlamaQueue`].
+struct LlamaQueueRx {
+    waiting: Arc<Waiting>,
+    /// The maintenance message, for as long as maintenance mode holds the
+    /// queue.
+    maintenance: watch::Receiver<Option<String>>,
+    /// Once the bot starts shutting down, what is left in the queue is
+    /// answered and the queue closes.
+    shutdown: Shutdown,
+    /// How many requests, each from a different room, may be answered at
+    /// once.
+    workers: usize,
+}
+
+impl Drop for LlamaQueueRx {
+    fn drop(&mut self) {
+        self.waiting.closed.store(true, Ordering::Relaxed);
     }
 }
 
-async fn llama_task(mut rx: Receiver<LlamaReq>, url: Url, model: String) {
-    let mut state: HashMap<OwnedRoomId, Chat> = HashMap::new();
+impl LlamaQueue {
+    /// How many requests are waiting to be started.
+    fn waiting(&self) -> usize {
+        self.waiting.requests.lock().unwrap().len()
+    }
 
-    loop {
-        match rx.recv().await {
-            Some(LlamaReq::Chat(chat_req)) => {
-                let mut chat = state
-                    .remove(&chat_req.room_id)
-                    .unwrap_or_else(|| Chat::new(model.clone(), url.clone()));
-
-                match chat.message(chat_req.prompt).await {
-                    Ok(resp) => {
-                        chat_req.reply_tx.send(resp).unwrap();
-                    }
-                    Err(e) => {
-                        error!("Failed to generate response from ollama: {}", e);
-                    }
-                }
-                state.insert(chat_req.room_id, chat);
-            }
-            Some(LlamaReq::ClrCtx(rm)) => {
-                state.remove(&rm);
-            }
-            None => {
-                return;
-            }
+    /// Queue `req`, returning where in the queue it is, counting from 1, or
+    /// `None` if the queue has closed.
+    async fn send(&self, mut req: LlamaReq, priority: bool) -> Option<usize> {
+        if let LlamaReq::Chat(req) = &mut req {
+            req.cancel = self.cancels.register(&req.room_id);
+        }
+
+        // The queue only closes once the bot is shutting down.
+        if self.waiting.closed.load(Ordering::Relaxed) {
+            warn!("Dropping a request that arrived as the bot shut down");
+            return None;
         }
+
+        let room_id = req.room_id().map(ToOwned::to_owned);
+        let position = self
+            .waiting
+            .requests
+            .lock()
+            .unwrap()
+            .push(room_id, req, priority);
+        self.waiting.added.notify_one();
+
+        Some(position)
     }
 }
 
-async fn accept_invites(evt: StrippedRoomMemberEvent, client: Client, rm: Room) {
-    dbg!(&evt);
-    if evt.state_key != client.user_id().unwrap() {
-        return;
+/// State shared by the Matrix event handlers.
+#[derive(Clone)]
+struct Bot {
+    queue: LlamaQueue,
+    /// Settings that may change while the bot is running.
+    config: Live,
+    /// Present only when an embedding model has been configured.
+    indexer: Option<Indexer>,
+    catch_up_after: Option<Duration>,
+    max_prompt_age: Option<Duration>,
+    reply_to_prompts: bool,
+    notices: bool,
+    /// The answers reactions can be to.
+    answers: Answers,
+    ack_reactions: bool,
+    vision_model: Option<String>,
+    /// Present only when an image generation server has been configured.
+    image_generator: Option<ImageGenerator>,
+    /// Present only when a speech-to-text server has been configured.
+    transcriber: Option<Transcriber>,
+    /// The tools offered to the model with each prompt.
+    tools: Tools,
+    /// Present only when fetching web pages is enabled.
+    fetcher: Option<Fetcher>,
+    wizards: Wizards,
+    limiter: RateLimiter,
+    verifier: Verifier,
+    /// Whether the direct chats the bot starts are encrypted.
+    encrypt_dms: bool,
+    broadcasts: Broadcasts,
+    scheduler: Scheduler,
+    maintenance: Maintenance,
+    budgets: Budgets,
+    global_budget: GlobalBudget,
+    throughput: Throughput,
+    bans: Bans,
+    /// Set when an admin or a signal asks the bot to shut down.
+    shutdown: Shutdown,
+    /// How many requests ahead of theirs users are told the bot is busy at.
+    busy_after: usize,
+    /// How many requests may wait in the queue before more are turned away.
+    queue_limit: usize,
+    /// What [`llama_task`] answers with by default, kept up to date as admins
+    /// change it.
+    defaults: Arc<RwLock<Defaults>>,
+    health: Health,
+    warmer: Warmer,
+    started: Instant,
+}
+
+/// The model and server that answer requests which don't ask for others.
+#[derive(Clone)]
+struct Defaults {
+    model: String,
+    backend: Backend,
+}
+
+impl Bot {
+    /// A message from the bot that isn't an answer, such as a command's
+    /// reply: plain text, or a plain notice with --notices.
+    fn plain(&self, text: impl Into<String>) -> RoomMessageEventContent {
+        match self.notices {
+            true => RoomMessageEventContent::notice_plain(text),
+            false => RoomMessageEventContent::text_plain(text),
+        }
     }
 
-    if let Err(e) = rm.join().await {
-        warn!(
-            "Failed to join invited room: {} ({})",
-            rm.room_id(),
-            e.to_string()
-        );
+    /// A reply of `plain` text, with `html` for clients that show it, as a
+    /// notice with --notices.
+    fn html(&self, plain: impl Into<String>, html: impl Into<String>) -> RoomMessageEventContent {
+        match self.notices {
+            true => RoomMessageEventContent::notice_html(plain, html),
+            false => RoomMessageEventContent::text_html(plain, html),
+        }
+    }
 
-        let _ = rm.leave().await;
+    /// An answer in `content`, as a notice rather than text with --notices.
+    fn answer_content(&self, mut content: RoomMessageEventContent) -> RoomMessageEventContent {
+        if self.notices
+            && let MessageType::Text(text) = content.msgtype
+        {
+            let mut notice = NoticeMessageEventContent::plain(text.body);
+            notice.formatted = text.formatted;
+            content.msgtype = MessageType::Notice(notice);
+        }
+
+        content
     }
-}
 
-async fn handle_msg_event(
-    evt: OriginalSyncRoomMessageEvent,
-    rm: Room,
-    client: Client,
-    ctx: Ctx<mpsc::Sender<LlamaReq>>,
-) {
-    // Don't respond to our own messages.
-    if evt.sender == client.user_id().unwrap() {
-        return;
+    /// Queue `req` and post its replies to `rm` as they arrive, as `answer`,
+    /// after its prompt event and in its thread, if any.
+    async fn answer(
+        &self,
+        rm: &Room,
+        mut req: LlamaChatReq,
+        rx: UnboundedReceiver<Reply>,
+        priority: bool,
+        mut answer: Answer,
+    ) {
+        let _replying = self.shutdown.replying();
+
+        let prompt = answer.prompt.clone();
+        answer.in_context = !req.oneshot;
+        req.event_id = Some(prompt.clone());
+
+        if !self.enqueue(rm, req, priority).await {
+            return;
+        }
+
+        // While the prompt waits in the queue, so that the asker knows
+        // straight away that it was seen. A prompt answered again already
+        // has its reactions, and can't be given the same one twice.
+        let seen = match self.ack_reactions && !self.answers.answered(&prompt) {
+            true => reactions::react(rm, &prompt, reactions::SEEN).await,
+            false => None,
+        };
+
+        let answered = post_replies(
+            rm,
+            rx,
+            answer,
+            &self.answers,
+            self.reply_to_prompts,
+            self.notices,
+        )
+        .await;
+
+        if let Some(seen) = seen {
+            let _ = rm.redact(&seen, None, None).await;
+
+            let key = match answered {
+                true => reactions::ANSWERED,
+                false => reactions::FAILED,
+            };
+
+            reactions::react(rm, &prompt, key).await;
+        }
     }
 
-    match evt.content.msgtype {
-        MessageType::Text(txt) => {
-            let matched = txt.body.strip_prefix("!llama");
+    /// Queue `req`, telling `rm` how long the wait will be, unless the queue
+    /// is too long to take it. Returns whether it was queued.
+    async fn enqueue(&self, rm: &Room, req: impl Into<LlamaReq>, priority: bool) -> bool {
+        self.warmer.touch();
 
-            if !rm.is_direct().await.unwrap() && !matched.is_some() {
-                return;
-            }
+        // Admins' requests are always taken.
+        if !priority && self.queue.waiting() >= self.queue_limit {
+            let notice = "I'm too busy to take on any more requests, please try again later";
+            let _ = rm.send(RoomMessageEventContent::notice_plain(notice)).await;
+            return false;
+        }
 
-            let prompt = matched.unwrap_or_else(|| txt.body.as_str());
+        let Some(position) = self.queue.send(req.into(), priority).await else {
+            return false;
+        };
 
-            if prompt == "!llamaclear" {
-                ctx.send(LlamaReq::ClrCtx(rm.room_id().into()))
-                    .await
-                    .unwrap();
+        if let Some(notice) = self.queue_notice(position) {
+            let _ = rm.send(RoomMessageEventContent::notice_plain(notice)).await;
+        }
 
-                rm.send(RoomMessageEventContent::text_plain("Context cleared"))
-                    .await
-                    .unwrap();
+        true
+    }
 
-                return;
-            }
+    /// What a user whose request is at `position` in the queue is told about
+    /// the wait, if anything.
+    fn queue_notice(&self, position: usize) -> Option<String> {
+        let ahead = position - 1;
+        // The workers each take a request at a time.
+        let rounds = position.div_ceil(self.queue.workers);
+        let eta = self
+            .throughput
+            .typical()
+            .map(|typical| stats::describe(typical * rounds as u32));
 
-            let _ = rm.typing_notice(true).await;
+        match (ahead >= self.busy_after, eta) {
+            (true, Some(eta)) => Some(format!(
+                "I'm busy, your request is #{} in the queue, expect an answer in about {}",
+                position, eta
+            )),
+            (true, None) => Some(format!(
+                "I'm busy, your request is #{} in the queue",
+                position
+            )),
+            (false, Some(eta)) if ahead > 0 => Some(format!(
+                "There are {} requests ahead of yours, expect an answer in about {}",
+                ahead, eta
+            )),
+            (false, _) => None,
+        }
+    }
 
-            let (req, mut rx) = LlamaChatReq::new(rm.room_id().into(), prompt);
+    /// Whether a prompt from `sender` may be answered in `rm` now, or what
+    /// to tell them if not: nothing is during maintenance, nor beyond the
+    /// room's quota or the daily token budgets.
+    async fn admit(
+        &self,
+        rm: &Room,
+        sender: &UserId,
+        settings: &RoomSettings,
+    ) -> Result<(), String> {
+        if let Some(message) = self.maintenance.message() {
+            return Err(message);
+        }
 
-            ctx.send(req).await.unwrap();
+        let config = self.config.get();
 
-            loop {
-                select! {
-                _ = sleep(Duration::from_secs(3)) => {
-                    let _ = rm.typing_notice(true).await;
-                },
-                resp = &mut rx => {
-                    let _ = rm.typing_notice(false).await;
-                    rm.send(RoomMessageEventContent::text_plain(resp.unwrap()))
-                      .await
-                      .unwrap();
-                    break;
-                }
-                }
+        if let Some(quota) = settings.quota.or(config.quota)
+            && !self.is_admin(sender)
+            && !self.limiter.check(rm.room_id(), sender, quota)
+        {
+            return Err(format!(
+                "You've reached this room's limit of {} prompts per hour, please try again later",
+                quota
+            ));
+        }
+
+        let global_usage = self.budgets.today(None).await.unwrap_or_else(|e| {
+            warn!("Failed to load the bot's token usage: {}", e);
+            Default::default()
+        });
+
+        if self.global_budget.exhausted(&global_usage) {
+            return Err("Sorry, I've done all the work I'm allowed to for today. \
+                        Please try again after midnight UTC."
+                .to_owned());
+        }
+
+        if let Some(budget) = settings.token_budget.or(config.token_budget) {
+            let used = self.budgets.used(rm.room_id()).await.unwrap_or_else(|e| {
+                warn!("Failed to load token usage of {}: {}", rm.room_id(), e);
+                0
+            });
+
+            if let Standing::Exhausted = budget::standing(budget, used) {
+                return Err(format!(
+                    "This room has used all {} tokens of its daily budget, which resets at midnight UTC",
+                    budget
+                ));
             }
         }
-        _ => {
-            warn!("Could not reply to non-text based message");
+
+        Ok(())
+    }
+
+    fn is_admin(&self, user: &UserId) -> bool {
+        self.config.get().admins.contains(user)
+    }
+
+    /// Whether `user` may talk to the bot at all. Admins always can, and
+    /// being denied outweighs being allowed.
+    fn may_use(&self, user: &UserId) -> bool {
+        let config = self.config.get();
+
+        if config.admins.contains(user) {
+            return true;
         }
+
+        !self.bans.contains(user)
+            && !access::any_match(&config.deny_users, user)
+            && (config.allow_users.is_empty() || access::any_match(&config.allow_users, user))
     }
-}
 
-fn write_session(session: MatrixSession) -> Result<()> {
-    let f =
-        File::create(get_data_dir().join("session")).context("Could not create session file")?;
+    /// Whether `user` may change the bot's settings for `rm`: bot admins
+    /// always can, otherwise a room moderator is required.
+    async fn can_configure(&self, rm: &Room, user: &UserId) -> bool {
+        self.is_admin(user) || rm.get_user_power_level(user).await.unwrap_or(0) >= 50
+    }
+}
 
-    Ok(serde_json::to_writer(f, &session)?)
+struct LlamaChatReq {
+    room_id: OwnedRoomId,
+    prompt: String,
+    /// A transcript of conversation the bot missed, to be summarized for
+    /// the model along with `prompt`.
+    catch_up: Option<String>,
+    /// Messages and documents from the room's index that may help answer
+    /// `prompt`.
+    retrieved: Option<String>,
+    /// Answer in a fresh context that is thrown away afterwards, leaving the
+    /// room's conversation untouched.
+    oneshot: bool,
+    /// Continue the conversation in the thread with this root, rather than
+    /// the room's.
+    thread: Option<OwnedEventId>,
+    /// The model to answer with, instead of the default.
+    model: Option<String>,
+    /// The model to answer with once there are images in the context.
+    vision_model: Option<String>,
+    /// Base64 encoded images sent along with `prompt`.
+    images: Vec<String>,
+    /// The tools the model may call while answering.
+    tools: Tools,
+    system_prompt: Option<String>,
+    /// Constrains the response to JSON, see [`Chat::set_format`].
+    format: Option<serde_json::Value>,
+    /// Options that come with the room's persona.
+    options: Options,
+    /// The room's pinned seed, if any.
+    seed: Option<u64>,
+    /// Answer the last prompt in the context again instead of `prompt`,
+    /// replacing the answer it got.
+    retry: bool,
+    /// Summarise what no longer fits in the context instead of dropping it.
+    condense: bool,
+    /// The options the room has set with `!llamaset`.
+    room_options: Options,
+    /// Add the tokens the response used to the bottom of it.
+    usage_footer: bool,
+    /// Options given with the request itself, which take precedence over all
+    /// others.
+    overrides: Options,
+    /// The event the prompt was sent in, which the exchange is remembered as
+    /// answering, so that reactions to the answer apply to it alone.
+    event_id: Option<OwnedEventId>,
+    /// Set up once the request is queued, so that `!llamastop` can cancel it.
+    cancel: Cancellable,
+    /// Each message sent on this channel is posted to the room as it arrives.
+    reply_tx: UnboundedSender<Reply>,
+    _typing: TypingNotice,
 }
 
-fn read_session() -> Option<MatrixSession> {
-    let f = File::open(get_data_dir().join("session")).ok()?;
+impl LlamaChatReq {
+    fn new(rm: &Room, prompt: impl ToString) -> (Self, UnboundedReceiver<Reply>) {
+        let (tx, rx) = unbounded_channel();
+        (
+            Self {
+                room_id: rm.room_id().into(),
+                prompt: prompt.to_string(),
+                catch_up: None,
+                retrieved: None,
+                oneshot: false,
+                thread: None,
+                model: None,
+                vision_model: None,
+                images: Vec::new(),
+                tools: Tools::default(),
+                system_prompt: None,
+                format: None,
+                options: Options::default(),
+                seed: None,
+                retry: false,
+                condense: false,
+                room_options: Options::default(),
+                usage_footer: false,
+                overrides: Options::default(),
+                event_id: None,
+                cancel: Cancellable::never(),
+                reply_tx: tx,
+                _typing: TypingNotice::start(rm.clone()),
+            },
+            rx,
+        )
+    }
+}
 
-    serde_json::from_reader(f).ok()?
+/// How responses make their way back to the room.
+#[derive(Clone)]
+struct Delivery {
+    stream_mode: StreamMode,
+    /// Post a progress message for generations that take longer than this.
+    heartbeat_after: Option<Duration>,
+    /// Give up on generations that take longer than this.
+    timeout: Option<Duration>,
+    /// Collects the statistics that progress estimates are based on.
+    throughput: Throughput,
+    /// Where answers to questions asked afresh are kept, to be reused if the
+    /// same question is asked again.
+    cache: Option<ResponseCache>,
+    /// Add the tokens each response used to the bottom of it.
+    usage_footer: bool,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Run a prompt through `chat`, posting the response to `reply_tx` according
+/// to `delivery`, with progress going to `rm` if given, in the `thread` the
+/// answer is in.
+async fn generate(
+    chat: &mut Chat,
+    prompt: String,
+    delivery: &Delivery,
+    rm: Option<Room>,
+    thread: Option<Thread>,
+    reply_tx: &UnboundedSender<Reply>,
+) -> Result<()> {
+    // Only answers that don't depend on an earlier conversation, or on an
+    // image, are reusable.
+    let cache = delivery
+        .cache
+        .as_ref()
+        .filter(|_| chat.history().is_empty() && !chat.has_images());
+    let key = cache.map(|_| cache::Key::new(chat, &prompt));
 
-    let args = Args::parse();
-    let server = ServerName::parse(args.homeserver).context("Could not parse homeserver")?;
+    if let (Some(cache), Some(key)) = (cache, &key)
+        && let Some(answer) = cache.get(key)
+    {
+        chat.push_exchange(prompt, &answer);
+        let _ = reply_tx.send(Reply::Post(answer));
+        return Ok(());
+    }
 
-    let userid = UserId::parse_with_server_name(args.username, &server)
-        .context("could not parse user ID")?;
+    generate_uncached(chat, prompt, delivery, rm, thread, reply_tx).await?;
 
-    fs::create_dir_all(get_data_dir()).context("Could not create data dir")?;
+    // What tools return, such as the time, can change from one ask to the
+    // next.
+    if let (Some(cache), Some(key), Some(answer)) = (cache, key, chat.last_response())
+        && !chat.used_tools()
+    {
+        cache.insert(key, answer.to_owned());
+    }
 
-    let client = Client::builder()
-        .server_name(&server)
-        .sqlite_store(get_data_dir().join("db"), None)
-        .build()
-        .await?;
+    Ok(())
+}
 
-    match read_session() {
-        Some(session) => client
-            .restore_session(session)
-            .await
-            .context("Failed to restore session")?,
-        None => {
-            let response = client
-                .matrix_auth()
-                .login_username(userid, &args.password)
-                .send()
-                .await
-                .context("Failed to login")?;
+/// How often a response being streamed as edits is updated.
+const EDIT_EVERY: Duration = Duration::from_secs(2);
 
-            write_session((&response).into())?;
+async fn generate_uncached(
+    chat: &mut Chat,
+    prompt: String,
+    delivery: &Delivery,
+    rm: Option<Room>,
+    thread: Option<Thread>,
+    reply_tx: &UnboundedSender<Reply>,
+) -> Result<()> {
+    // An edited response shows its own progress.
+    let mut heartbeat = match (rm, delivery.heartbeat_after) {
+        (Some(rm), Some(after)) if delivery.stream_mode != StreamMode::Edit => {
+            Some(Heartbeat::start(
+                rm,
+                thread,
+                after,
+                delivery.throughput.clone(),
+                chat.model().to_owned(),
+            ))
+        }
+        _ => None,
+    };
+    let tick = || {
+        if let Some(heartbeat) = &heartbeat {
+            heartbeat.tick();
         }
+    };
+
+    // Ollama sends a token per chunk, so responses are always streamed to
+    // keep the heartbeat's count, even when they're posted all at once.
+    let streamed = stream(chat, prompt, delivery, reply_tx, tick).await;
+
+    if let Some(heartbeat) = &mut heartbeat {
+        heartbeat.end(match &streamed {
+            Ok(()) => Outcome::Finished,
+            Err(e)
+                if e.downcast_ref::<TimedOut>().is_some()
+                    || matches!(Failure::of(e), Failure::TimedOut) =>
+            {
+                Outcome::TimedOut
+            }
+            Err(_) => Outcome::Failed,
+        });
     }
 
-    let (tx, rx) = mpsc::channel(1024);
+    streamed
+}
 
-    tokio::spawn(llama_task(rx, args.url, args.model));
+/// Stream the response to `prompt`, calling `tick` for each token.
+async fn stream(
+    chat: &mut Chat,
+    prompt: String,
+    delivery: &Delivery,
+    reply_tx: &UnboundedSender<Reply>,
+    tick: impl Fn(),
+) -> Result<()> {
+    match delivery.stream_mode {
+        StreamMode::Off => {
+            let resp = chat.message_stream(prompt, |_| tick()).await?;
+            let _ = reply_tx.send(Reply::Post(with_footer(resp, chat, delivery)));
+        }
+        StreamMode::Paragraph => {
+            let mut paragraphs = Paragraphs::default();
 
-    client.add_event_handler(accept_invites);
+            chat.message_stream(prompt, |fragment| {
+                tick();
 
-    let token = client
-        .sync_once(SyncSettings::default().timeout(Duration::from_millis(500)))
+                for para in paragraphs.push(fragment) {
+                    let _ = reply_tx.send(Reply::Post(para));
+                }
+            })
+            .await?;
+
+            if let Some(rest) = paragraphs.finish() {
+                let _ = reply_tx.send(Reply::Post(rest));
+            }
+
+            if let Some(footer) = footer(chat, delivery) {
+                let _ = reply_tx.send(Reply::Post(footer));
+            }
+        }
+        StreamMode::Edit => {
+            let mut text = String::new();
+            let mut last_update = Instant::now();
+
+            let resp = chat
+                .message_stream(prompt, |fragment| {
+                    tick();
+                    text.push_str(fragment);
+
+                    if last_update.elapsed() >= EDIT_EVERY && !text.trim().is_empty() {
+                        last_update = Instant::now();
+                        let _ = reply_tx.send(Reply::Update(text.trim().to_owned()));
+                    }
+                })
+                .await?;
+
+            let _ = reply_tx.send(Reply::Update(with_footer(resp, chat, delivery)));
+        }
+    }
+
+    Ok(())
+}
+
+/// What goes below a response: the sources the tools found for it and, if
+/// asked for, the tokens it used.
+fn footer(chat: &Chat, delivery: &Delivery) -> Option<String> {
+    let usage = chat.last_usage();
+    let mut parts = Vec::new();
+
+    if !chat.sources().is_empty() {
+        parts.push(tools::cite(chat.sources()));
+    }
+
+    if delivery.usage_footer && usage.rate().is_some() {
+        parts.push(format!("*{}*", stats::describe_usage(&usage)));
+    }
+
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+fn with_footer(resp: String, chat: &Chat, delivery: &Delivery) -> String {
+    match footer(chat, delivery) {
+        Some(footer) => format!("{}\n\n{}", resp, footer),
+        None => resp,
+    }
+}
+
+/// What a request is answered with. Each request is answered by its own copy,
+/// taken as it starts.
+#[derive(Clone)]
+struct Worker {
+    /// Shared between workers, which never work on the same room at once.
+    state: Arc<AsyncMutex<Contexts>>,
+    backend: Backend,
+    model: String,
+    delivery: Delivery,
+    config: Live,
+    budgets: Budgets,
+    client: Client,
+}
+
+impl Worker {
+    async fn serve(self, req: LlamaReq) {
+        match req {
+            LlamaReq::Chat(chat_req) => self.chat(chat_req).await,
+            LlamaReq::Ingest {
+                room_id,
+                thread,
+                chunks,
+            } => {
+                let mut state = self.state.lock().await;
+                let slot = state.current(&room_id, thread.as_deref()).await;
+                let mut chat = Chat::with_history(&self.model, self.backend, slot.history);
+
+                for chunk in chunks {
+                    chat.push_system(chunk);
+                }
+
+                // The document comes after the last exchange, which can no
+                // longer be taken out on its own.
+                state
+                    .set_history(&room_id, thread.as_deref(), chat.history().to_vec(), None)
+                    .await;
+            }
+            LlamaReq::ClrCtx(rm, thread) => {
+                self.state.lock().await.clear(&rm, thread.as_deref()).await;
+            }
+            LlamaReq::Image {
+                generator,
+                prompt,
+                reply,
+                ..
+            } => {
+                let _ = reply.send(generator.generate(&prompt).await);
+            }
+            LlamaReq::ForgetLast(room_id, thread, prompt) => {
+                let mut state = self.state.lock().await;
+                let slot = state.current(&room_id, thread.as_deref()).await;
+
+                // Another prompt has been answered since.
+                if slot.last_prompt.as_ref() != Some(&prompt) {
+                    info!(
+                        "Not forgetting {} in {}, as it's no longer the last exchange",
+                        prompt, room_id
+                    );
+                    return;
+                }
+
+                let mut chat = Chat::with_history(&self.model, self.backend, slot.history);
+
+                if chat.take_last_exchange().is_some() {
+                    state
+                        .set_history(&room_id, thread.as_deref(), chat.history().to_vec(), None)
+                        .await;
+                }
+            }
+            LlamaReq::Slots {
+                room_id,
+                action,
+                reply,
+            } => {
+                let outcome = self.state.lock().await.apply(&room_id, action).await;
+                let _ = reply.send(outcome);
+            }
+            LlamaReq::SetDefault { .. } => unreachable!("Handled by llama_task"),
+        }
+    }
+
+    async fn chat(self, mut chat_req: Box<LlamaChatReq>) {
+        // Stopped while it was waiting in the queue.
+        if chat_req.cancel.is_cancelled() {
+            return;
+        }
+
+        let slot = match chat_req.oneshot {
+            true => Default::default(),
+            false => {
+                self.state
+                    .lock()
+                    .await
+                    .current(&chat_req.room_id, chat_req.thread.as_deref())
+                    .await
+            }
+        };
+
+        let mut chat = Chat::with_history(self.model.clone(), self.backend.clone(), slot.history);
+
+        // The slot's own settings take precedence over the room's.
+        chat.set_model(
+            slot.model
+                .as_deref()
+                .or(chat_req.model.as_deref())
+                .unwrap_or(&self.model),
+        );
+        chat.set_system_prompt(
+            slot.system_prompt
+                .as_deref()
+                .or(chat_req.system_prompt.as_deref()),
+        );
+
+        // The persona's options only go with the persona's prompt.
+        let mut options = match slot.system_prompt {
+            Some(_) => Options::default(),
+            None => chat_req.options,
+        };
+
+        let config = self.config.get();
+
+        if let Some(model_options) = config.models.get(chat.model()) {
+            options.merge(model_options);
+        }
+
+        options.merge(&config.options);
+
+        if chat_req.seed.is_some() {
+            options.seed = chat_req.seed;
+        }
+
+        let mut overrides = chat_req.overrides;
+        overrides.merge(&chat_req.room_options);
+        overrides.merge(&options);
+
+        // Ollama's own name for the context window.
+        let window = overrides
+            .extra
+            .get("num_ctx")
+            .and_then(serde_json::Value::as_u64)
+            .map_or(config.context_window(), |n| n as usize);
+
+        chat.set_context_window(window);
+        chat.set_condense(chat_req.condense);
+        chat.set_options(overrides);
+        chat.attach_images(chat_req.images);
+        chat.set_tools(chat_req.tools);
+
+        if chat_req.retry {
+            match chat.take_last_exchange() {
+                Some(prompt) => chat_req.prompt = prompt,
+                None => {
+                    let _ = chat_req
+                        .reply_tx
+                        .send(Reply::Post("There's nothing to retry yet".to_owned()));
+                    return;
+                }
+            }
+        }
+
+        if chat.has_images()
+            && let Some(vision_model) = &chat_req.vision_model
+        {
+            chat.set_model(vision_model);
+        }
+
+        if let Some(transcript) = &chat_req.catch_up {
+            match catchup::summarize(self.backend.clone(), &self.model, transcript).await {
+                Ok(summary) => chat.add_note(format!(
+                    "Summary of the conversation since you last spoke in this room: {}",
+                    summary
+                )),
+                Err(e) => warn!("Failed to summarize missed messages: {}", e),
+            }
+        }
+
+        if let Some(retrieved) = chat_req.retrieved {
+            chat.add_note(retrieved);
+        }
+
+        let rm = self.client.get_room(&chat_req.room_id);
+        let thread = chat_req
+            .thread
+            .clone()
+            .zip(chat_req.event_id.clone())
+            .map(|(root, prompt)| Thread::plain(root, prompt));
+
+        // Structured output is only useful in one piece.
+        let mut delivery = self.delivery.clone();
+
+        if chat_req.format.is_some() {
+            delivery.stream_mode = StreamMode::Off;
+        }
+
+        // A cached answer is the one being retried.
+        if chat_req.retry {
+            delivery.cache = None;
+        }
+
+        delivery.usage_footer = chat_req.usage_footer;
+
+        chat.set_format(chat_req.format);
+        chat.set_time_limit(delivery.timeout);
+
+        // Dropping the generation closes the connection to ollama, which
+        // stops it there too.
+        let generated = select! {
+            result = generate(
+                &mut chat,
+                chat_req.prompt,
+                &delivery,
+                rm,
+                thread,
+                &chat_req.reply_tx,
+            ) => {
+                Some(result)
+            }
+            _ = chat_req.cancel.cancelled() => None,
+        };
+
+        // The last exchange is still the one before unless this one was
+        // answered.
+        let mut last_prompt = slot.last_prompt;
+
+        match generated {
+            None => {
+                info!("Stopped generating a response in {}", chat_req.room_id);
+                return;
+            }
+            Some(Ok(())) => {
+                let usage = chat.last_usage();
+
+                delivery
+                    .throughput
+                    .record(&chat_req.room_id, chat.model(), &usage);
+
+                if let Err(e) = self.budgets.record(&chat_req.room_id, usage).await {
+                    warn!("Failed to record token usage: {}", e);
+                }
+
+                last_prompt = chat_req.event_id;
+            }
+            Some(Err(e)) => {
+                if let Some(TimedOut(limit)) = e.downcast_ref() {
+                    warn!("Timed out generating a response in {}", chat_req.room_id);
+                    let _ = chat_req.reply_tx.send(Reply::Notice(format!(
+                        "The model timed out after {} seconds",
+                        limit.as_secs()
+                    )));
+                    return;
+                }
+
+                error!("Failed to generate response from ollama: {}", e);
+
+                // The details stay in the log, the room is only told what
+                // kind of failure it was.
+                let notice = match Failure::of(&e) {
+                    Failure::ModelMissing => format!(
+                        "The model {} isn't installed on the server, ask an admin to pull it",
+                        chat.model()
+                    ),
+                    Failure::Unreachable => {
+                        "I couldn't reach the model, even after retrying. Please try again later"
+                            .to_owned()
+                    }
+                    Failure::TimedOut => {
+                        "The model took too long to respond, please try again later".to_owned()
+                    }
+                    Failure::Other => "Something went wrong generating a response".to_owned(),
+                };
+
+                let _ = chat_req.reply_tx.send(Reply::Notice(notice));
+            }
+        }
+
+        if !chat_req.oneshot {
+            self.state
+                .lock()
+                .await
+                .set_history(
+                    &chat_req.room_id,
+                    chat_req.thread.as_deref(),
+                    chat.history().to_vec(),
+                    last_prompt,
+                )
+                .await;
+        }
+    }
+}
+
+/// Take requests off the queue and answer up to `queue.workers` of them at
+/// once, taking turns between rooms. A room's requests are answered one at a
+/// time, in the order they were made.
+async fn llama_task(
+    queue: LlamaQueueRx,
+    backend: Backend,
+    model: String,
+    delivery: Delivery,
+    config: Live,
+    budgets: Budgets,
+    client: Client,
+) {
+    let mut worker = Worker {
+        state: Arc::new(AsyncMutex::new(Contexts::new(client.clone()))),
+        backend,
+        model,
+        delivery,
+        config,
+        budgets,
+        client,
+    };
+    let mut maintenance = queue.maintenance.clone();
+    let mut running = JoinSet::new();
+    // The room each running request is from.
+    let mut busy: HashMap<task::Id, OwnedRoomId> = HashMap::new();
+
+    loop {
+        // Hold on to queued requests for as long as maintenance is on. Those
+        // still held when the bot shuts down are dropped, as the server is
+        // presumably unavailable.
+        select! {
+            biased;
+            result = maintenance.wait_for(Option::is_none) => if result.is_err() {
+                return;
+            },
+            _ = queue.shutdown.stopping() => return,
+        }
+
+        let next = match running.len() < queue.workers {
+            true => queue.waiting.requests.lock().unwrap().pop(|room_id| {
+                room_id
+                    .as_ref()
+                    .is_some_and(|room_id| busy.values().any(|busy| busy == room_id))
+            }),
+            false => None,
+        };
+
+        let Some(req) = next else {
+            // Once shutting down, this ends the task as soon as everything
+            // queued has been answered.
+            select! {
+                biased;
+                Some(finished) = running.join_next_with_id() => {
+                    let id = match finished {
+                        Ok((id, ())) => id,
+                        Err(e) => {
+                            error!("Answering a request failed: {}", e);
+                            e.id()
+                        }
+                    };
+
+                    busy.remove(&id);
+                }
+                _ = queue.waiting.added.notified() => {}
+                _ = queue.shutdown.stopping(), if running.is_empty() => return,
+            }
+
+            continue;
+        };
+
+        let room_id = match req {
+            // Requests already being answered go on with the old model.
+            LlamaReq::SetDefault {
+                model: new_model,
+                url,
+                done,
+            } => {
+                info!(
+                    "Switching the default model from {} to {}",
+                    worker.model, new_model
+                );
+                worker.model = new_model;
+
+                if let Some(url) = url {
+                    info!("Switching to the ollama server at {}", url);
+                    worker.backend = worker.backend.with_url(url);
+                }
+
+                let _ = done.send(());
+                continue;
+            }
+            ref req => req.room_id().unwrap().to_owned(),
+        };
+
+        let handle = running.spawn(worker.clone().serve(req));
+        busy.insert(handle.id(), room_id);
+    }
+}
+
+/// What [`accept_invites`] goes by. Unlike the [`Bot`], this is there from
+/// the first sync on, which brings the invites sent while the bot was away.
+#[derive(Clone)]
+struct Invites {
+    config: Live,
+    /// Where rejected invites are reported, if anywhere.
+    reports: Option<OwnedRoomId>,
+}
+
+async fn accept_invites(
+    evt: StrippedRoomMemberEvent,
+    client: Client,
+    rm: Room,
+    invites: Ctx<Invites>,
+) {
+    dbg!(&evt);
+    if evt.state_key != client.user_id().unwrap() {
+        return;
+    }
+
+    let config = invites.config.get();
+    let allowed = &config.allow_invites_from;
+
+    if !allowed.is_empty()
+        && !config.admins.contains(&evt.sender)
+        && !access::any_match(allowed, &evt.sender)
+    {
+        info!("Rejecting invite to {} from {}", rm.room_id(), evt.sender);
+
+        if let Err(e) = rm.leave().await {
+            warn!("Failed to reject invite to {}: {}", rm.room_id(), e);
+        }
+
+        if let Some(admin_room) = invites.reports.as_ref().and_then(|id| client.get_room(id)) {
+            let report = format!("Rejected an invite to {} from {}", rm.room_id(), evt.sender);
+
+            if let Err(e) = admin_room
+                .send(RoomMessageEventContent::notice_plain(report))
+                .await
+            {
+                warn!("Failed to report rejected invite: {}", e);
+            }
+        }
+
+        return;
+    }
+
+    if let Err(e) = rm.join().await {
+        warn!(
+            "Failed to join invited room: {} ({})",
+            rm.room_id(),
+            e.to_string()
+        );
+
+        let _ = rm.leave().await;
+    }
+}
+
+/// The root of the thread that `evt` was sent in, if any.
+fn thread_root(evt: &OriginalSyncRoomMessageEvent) -> Option<OwnedEventId> {
+    match &evt.content.relates_to {
+        Some(Relation::Thread(thread)) => Some(thread.event_id.clone()),
+        _ => None,
+    }
+}
+
+/// The event `evt` replies to, if it is a reply, in a thread or not.
+fn reply_target(evt: &OriginalSyncRoomMessageEvent) -> Option<OwnedEventId> {
+    match &evt.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        Some(Relation::Thread(thread)) if !thread.is_falling_back => {
+            thread.in_reply_to.as_ref().map(|r| r.event_id.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Send `content` in response to `evt`, keeping it in the same thread.
+async fn send_reply(
+    rm: &Room,
+    evt: &OriginalSyncRoomMessageEvent,
+    mut content: RoomMessageEventContent,
+) {
+    content.relates_to =
+        thread_root(evt).map(|root| Relation::Thread(Thread::plain(root, evt.event_id.clone())));
+
+    rm.send(content).await.unwrap();
+}
+
+/// Send `content` as with [`send_reply`], marked as an answer, so that it's
+/// read back as one even as a notice.
+async fn send_answer(
+    rm: &Room,
+    evt: &OriginalSyncRoomMessageEvent,
+    mut content: RoomMessageEventContent,
+) {
+    content.relates_to =
+        thread_root(evt).map(|root| Relation::Thread(Thread::plain(root, evt.event_id.clone())));
+
+    rm.send_raw("m.room.message", history::mark_answer(&content))
+        .await
+        .unwrap();
+}
+
+/// How a response relates to the `prompt` it answers: inside `thread`, if
+/// the prompt is in one, and with `quote`, as a reply to the prompt, which
+/// clients show quoted above the response.
+fn response_relation(
+    thread: Option<OwnedEventId>,
+    prompt: OwnedEventId,
+    quote: bool,
+) -> Option<Relation<RoomMessageEventContentWithoutRelation>> {
+    match (thread, quote) {
+        (Some(root), true) => Some(Relation::Thread(Thread::reply(root, prompt))),
+        (Some(root), false) => Some(Relation::Thread(Thread::plain(root, prompt))),
+        (None, true) => Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(prompt),
+        }),
+        (None, false) => None,
+    }
+}
+
+/// Post each response received on `rx` to the room as part of `answer`,
+/// after its prompt and inside its thread, if any, recording the messages in
+/// `answers`. With `quote`, the first message posted is a reply to the
+/// prompt, and with `notices`, responses are posted as notices rather than
+/// text.
+///
+/// Returns whether the prompt was answered, rather than met with a notice of
+/// what went wrong or with nothing at all, as when it's cancelled.
+async fn post_replies(
+    rm: &Room,
+    mut rx: UnboundedReceiver<Reply>,
+    mut answer: Answer,
+    answers: &Answers,
+    mut quote: bool,
+    notices: bool,
+) -> bool {
+    let mut answered = false;
+    let mut failed = false;
+
+    // The message updates are applied to, once it has been posted.
+    let mut draft: Option<OwnedEventId> = None;
+
+    // Models answer in Markdown, which is kept as the plain text body.
+    let markdown = |resp: String| match notices {
+        true => RoomMessageEventContent::notice_markdown(resp),
+        false => RoomMessageEventContent::text_markdown(resp),
+    };
+
+    while let Some(reply) = rx.recv().await {
+        let notice = matches!(reply, Reply::Notice(_));
+        let (mut content, update) = match reply {
+            Reply::Post(resp) => (markdown(resp), false),
+            Reply::Update(resp) => (markdown(resp), true),
+            Reply::Notice(notice) => (RoomMessageEventContent::notice_plain(notice), false),
+        };
+
+        if let (true, Some(event_id)) = (update, &draft) {
+            let metadata = ReplacementMetadata::new(event_id.clone(), None);
+            content = content.make_replacement(metadata, None);
+        } else {
+            content.relates_to =
+                response_relation(answer.thread.clone(), answer.prompt.clone(), quote);
+            quote = false;
+        }
+
+        let edit = update && draft.is_some();
+        // Marked, so that they're told from the bot's other notices in the
+        // room's history.
+        let resp = match notice {
+            true => rm.send(content).await.unwrap(),
+            false => rm
+                .send_raw("m.room.message", history::mark_answer(&content))
+                .await
+                .unwrap(),
+        };
+
+        if !edit {
+            answer.events.push(resp.event_id.clone());
+            answers.record(&answer);
+        }
+
+        if update && draft.is_none() {
+            draft = Some(resp.event_id);
+        }
+
+        match notice {
+            true => failed = true,
+            false => answered = true,
+        }
+    }
+
+    answered && !failed
+}
+
+const PROFILE_USAGE: &str = "Usage: !llamaprofile [show | set name|language|instructions <text> | clear [name|language|instructions]]";
+
+async fn profile_command(args: &str, user: &UserId, client: &Client) -> String {
+    let mut profile = match UserProfile::load(client, user).await {
+        Ok(profile) => profile,
+        Err(e) => {
+            error!("Failed to load profile of {}: {}", user, e);
+            return "Failed to load your profile".to_owned();
+        }
+    };
+
+    let (action, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let (field, value) = rest
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((rest.trim(), ""));
+    let value = value.trim();
+
+    match (action, field) {
+        ("" | "show", _) => {
+            let fields = [
+                ("name", &profile.name),
+                ("language", &profile.language),
+                ("instructions", &profile.instructions),
+            ];
+
+            let shown: Vec<String> = fields
+                .iter()
+                .filter_map(|(field, value)| value.as_ref().map(|v| format!("{}: {}", field, v)))
+                .collect();
+
+            return match shown.is_empty() {
+                true => "Your profile is empty".to_owned(),
+                false => shown.join("\n"),
+            };
+        }
+        ("set", _) if value.is_empty() => return PROFILE_USAGE.to_owned(),
+        ("set", "name") => profile.name = Some(value.to_owned()),
+        ("set", "language") => profile.language = Some(value.to_owned()),
+        ("set", "instructions") => profile.instructions = Some(value.to_owned()),
+        ("clear", "") => profile = UserProfile::default(),
+        ("clear", "name") => profile.name = None,
+        ("clear", "language") => profile.language = None,
+        ("clear", "instructions") => profile.instructions = None,
+        _ => return PROFILE_USAGE.to_owned(),
+    }
+
+    if let Err(e) = profile.save(client, user).await {
+        error!("Failed to save profile of {}: {}", user, e);
+        return "Failed to save your profile".to_owned();
+    }
+
+    "Profile updated".to_owned()
+}
+
+const PREFS_USAGE: &str = "Usage: !llamaprefs [show | verbosity brief|normal|detailed | language <language> | model <model> | reset]";
+
+async fn prefs_command(args: &str, user: &UserId, client: &Client, bot: &Bot) -> String {
+    let mut profile = match UserProfile::load(client, user).await {
+        Ok(profile) => profile,
+        Err(e) => {
+            error!("Failed to load profile of {}: {}", user, e);
+            return "Failed to load your preferences".to_owned();
+        }
+    };
+
+    let (pref, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let value = value.trim();
+
+    match (pref, value) {
+        ("" | "show", _) => {
+            let config = bot.config.get();
+            let mut models: Vec<&str> = config.user_models.iter().map(String::as_str).collect();
+            models.sort();
+
+            return format!(
+                "verbosity: {}\nlanguage: {}\nmodel: {}\n\nAvailable models: {}",
+                match profile.verbosity {
+                    Some(Verbosity::Brief) => "brief",
+                    Some(Verbosity::Detailed) => "detailed",
+                    Some(Verbosity::Normal) | None => "normal",
+                },
+                profile.language.as_deref().unwrap_or("any"),
+                profile.model.as_deref().unwrap_or("default"),
+                models.join(", ")
+            );
+        }
+        ("verbosity", "brief") => profile.verbosity = Some(Verbosity::Brief),
+        ("verbosity", "normal") => profile.verbosity = None,
+        ("verbosity", "detailed") => profile.verbosity = Some(Verbosity::Detailed),
+        ("language", "") | ("model", "") => return PREFS_USAGE.to_owned(),
+        ("language", language) => profile.language = Some(language.to_owned()),
+        ("model", "default") => profile.model = None,
+        ("model", model) if bot.config.get().user_models.contains(model) => {
+            profile.model = Some(model.to_owned())
+        }
+        ("model", model) => return format!("The {} model is not available", model),
+        ("reset", _) => {
+            profile.verbosity = None;
+            profile.language = None;
+            profile.model = None;
+        }
+        _ => return PREFS_USAGE.to_owned(),
+    }
+
+    if let Err(e) = profile.save(client, user).await {
+        error!("Failed to save profile of {}: {}", user, e);
+        return "Failed to save your preferences".to_owned();
+    }
+
+    "Preferences updated".to_owned()
+}
+
+const ALIAS_USAGE: &str = "Usage: !llamaalias [<alias> <command> | remove <alias>]";
+
+async fn alias_command(args: &str, rm: &Room, client: &Client) -> String {
+    let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    let words: Vec<&str> = args.split_whitespace().collect();
+
+    if words.is_empty() {
+        if settings.aliases.is_empty() {
+            return "This room has no command aliases".to_owned();
+        }
+
+        let mut aliases: Vec<String> = settings
+            .aliases
+            .iter()
+            .map(|(alias, name)| format!("!llama{} → !llama{}", alias, name))
+            .collect();
+        aliases.sort();
+
+        return aliases.join("\n");
+    }
+
+    let reply = match words.as_slice() {
+        ["remove", alias] => match settings.aliases.remove(&alias.to_lowercase()) {
+            Some(_) => format!("Removed the alias !llama{}", alias),
+            None => format!("There is no alias !llama{}", alias),
+        },
+        [alias, name] => {
+            let Some(cmd) = commands::find(name) else {
+                return format!("There is no command called {}", name);
+            };
+
+            if commands::find(alias).is_some() {
+                return format!("!llama{} is already a command", alias);
+            }
+
+            settings
+                .aliases
+                .insert(alias.to_lowercase(), cmd.name.to_owned());
+
+            format!("!llama{} now runs !llama{}", alias, cmd.name)
+        }
+        _ => return ALIAS_USAGE.to_owned(),
+    };
+
+    if let Err(e) = settings.save(client, rm.room_id()).await {
+        error!("Failed to save settings for {}: {}", rm.room_id(), e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    reply
+}
+
+const SYSTEM_USAGE: &str = "Usage: !llamasystem [<prompt> | persona <name> | none]";
+
+async fn system_command(args: &str, rm: &Room, client: &Client, bot: &Bot) -> String {
+    let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    if args.is_empty() {
+        return match (settings.named_persona, settings.persona) {
+            (Some(name), _) => format!("This room uses the {} persona", name),
+            (None, Some(persona)) => format!("This room's system prompt is: {}", persona),
+            (None, None) => SYSTEM_USAGE.to_owned(),
+        };
+    }
+
+    // A named persona has to be asked for by name, so that a prompt which
+    // happens to match one is still taken as a prompt.
+    let reply = match args.split_once(char::is_whitespace) {
+        Some(("persona", name)) => {
+            let name = name.trim();
+            let personas = &bot.config.get().personas;
+            if !personas.contains_key(name) {
+                let mut names: Vec<_> = personas.keys().map(String::as_str).collect();
+                names.sort_unstable();
+                return match names.is_empty() {
+                    true => "No personas are configured".to_owned(),
+                    false => format!("Unknown persona; choose one of: {}", names.join(", ")),
+                };
+            }
+            settings.named_persona = Some(name.to_owned());
+            format!("This room now uses the {} persona", name)
+        }
+        _ if args == "none" => {
+            settings.persona = None;
+            settings.named_persona = None;
+            "This room now uses the default system prompt".to_owned()
+        }
+        _ => {
+            settings.persona = Some(args.to_owned());
+            settings.named_persona = None;
+            "System prompt set for this room".to_owned()
+        }
+    };
+
+    if let Err(e) = settings.save(client, rm.room_id()).await {
+        error!("Failed to save settings for {}: {}", rm.room_id(), e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    reply
+}
+
+const SEED_USAGE: &str = "Usage: !llamaseed [<seed> | random]";
+
+const RETRY_USAGE: &str = "Usage: !llamaretry [seed <seed>] [temperature <temperature>]";
+
+/// The options given to `!llamaretry`, as pairs of option and value.
+fn retry_options(args: &str) -> Option<Options> {
+    let mut options = Options::default();
+    let mut words = args.split_whitespace();
+
+    while let Some(name) = words.next() {
+        let value = words.next()?;
+
+        match name {
+            "seed" => options.seed = Some(value.parse().ok()?),
+            "temperature" => {
+                let temperature: f64 = value.parse().ok().filter(|t: &f64| *t >= 0.0)?;
+                options
+                    .extra
+                    .insert("temperature".to_owned(), temperature.into());
+            }
+            _ => return None,
+        }
+    }
+
+    Some(options)
+}
+
+async fn seed_command(args: &str, rm: &Room, client: &Client) -> String {
+    let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    if args.is_empty() {
+        return match settings.seed {
+            Some(seed) => format!("Answers in this room are generated with seed {}", seed),
+            None => "Answers in this room are generated with a random seed".to_owned(),
+        };
+    }
+
+    let reply = match args {
+        "random" => {
+            settings.seed = None;
+            "Answers will be generated with a random seed".to_owned()
+        }
+        seed => match seed.parse() {
+            Ok(seed) => {
+                settings.seed = Some(seed);
+                format!("Answers will be generated with seed {}", seed)
+            }
+            Err(_) => return SEED_USAGE.to_owned(),
+        },
+    };
+
+    if let Err(e) = settings.save(client, rm.room_id()).await {
+        error!("Failed to save settings for {}: {}", rm.room_id(), e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    reply
+}
+
+/// Report what the last answer in the room used, or turn the footer showing
+/// it on every answer on or off.
+async fn usage_command(args: &str, rm: &Room, client: &Client, bot: &Bot) -> String {
+    let enable = match args {
+        "" => {
+            return match bot.throughput.last(rm.room_id()) {
+                Some(usage) => format!(
+                    "The last answer in this room used {}",
+                    stats::describe_usage(&usage)
+                ),
+                None => "I haven't answered anything in this room yet".to_owned(),
+            };
+        }
+        "on" => true,
+        "off" => false,
+        _ => return "Usage: !llamausage [on|off]".to_owned(),
+    };
+
+    let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    settings.usage_footer = enable;
+
+    if let Err(e) = settings.save(client, rm.room_id()).await {
+        error!("Failed to save settings for {}: {}", rm.room_id(), e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    match enable {
+        true => "Answers will show the tokens they used".to_owned(),
+        false => "Answers will no longer show the tokens they used".to_owned(),
+    }
+}
+
+/// Describe the model the sender of `evt` would be answered with in `rm`, or
+/// the one given in `args`, as plain text and HTML.
+async fn info_command(
+    args: &str,
+    evt: &OriginalSyncRoomMessageEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+) -> Result<(String, String), String> {
+    let config = bot.config.get();
+    let defaults = bot.defaults.read().unwrap().clone();
+    let settings = RoomSettings::load(client, rm.room_id())
+        .await
+        .map_err(|e| {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            "Failed to load room settings".to_owned()
+        })?;
+    let profile = UserProfile::load(client, &evt.sender)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load profile of {}: {}", evt.sender, e);
+            UserProfile::default()
+        });
+    let slot = status::current_slot(client, rm.room_id(), thread_root(evt)).await;
+
+    // Chosen the same way as for a prompt.
+    let model = match args {
+        "" => slot
+            .model
+            .or(profile
+                .model
+                .or(settings.model.clone())
+                .filter(|m| config.user_models.contains(m)))
+            .unwrap_or(defaults.model),
+        model => model.to_owned(),
+    };
+    let system_prompt = slot.system_prompt.or(config
+        .persona(
+            settings.named_persona.as_deref(),
+            settings.persona.as_deref(),
+        )
+        .0);
+
+    models::info(
+        &defaults.backend,
+        &model,
+        system_prompt.as_deref(),
+        settings.seed,
+    )
+    .await
+    .map_err(|e| {
+        format!(
+            "Couldn't get information about {}: {}",
+            model,
+            llama::redact_error(&e)
+        )
+    })
+}
+
+const SET_USAGE: &str = "Usage: !llamaset <option> <value> | reset";
+
+/// Set one of the room's sampling options, or go back to the defaults.
+async fn set_command(args: &str, rm: &Room, client: &Client) -> String {
+    let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return "Failed to load room settings".to_owned();
+        }
+    };
+
+    let reply = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["reset"] => {
+            settings.options = Options::default();
+            "This room is back to the bot's default sampling options".to_owned()
+        }
+        // These have commands of their own, or aren't about sampling.
+        ["seed", ..] => return "Use !llamaseed to pin the seed".to_owned(),
+        [name, _] if !llama::is_sampling_option(name) => {
+            return "Only sampling options, such as temperature or top_p, can be set per room"
+                .to_owned();
+        }
+        [name, value] => {
+            if let Err(e) = settings.options.set(name, value) {
+                return format!("Couldn't set {}: {}", name, e);
+            }
+
+            format!("Set {} to {} for this room", name, value)
+        }
+        _ => return SET_USAGE.to_owned(),
+    };
+
+    if let Err(e) = settings.save(client, rm.room_id()).await {
+        error!("Failed to save settings for {}: {}", rm.room_id(), e);
+        return "Failed to save room settings".to_owned();
+    }
+
+    reply
+}
+
+const CONTEXT_USAGE: &str = "Usage: !llamacontext [list | use <slot> | system <prompt>|none | model <model>|default | delete <slot>]";
+
+/// Work out what a conversation slot command asks for, or why it can't be
+/// done.
+fn slot_action(cmd: commands::Kind, args: &str, bot: &Bot) -> Result<SlotAction, String> {
+    let single = |usage: &str| match args.is_empty() || args.contains(char::is_whitespace) {
+        true => Err(usage.to_owned()),
+        false => Ok(args.to_owned()),
+    };
+
+    match cmd {
+        commands::Kind::Fork => Ok(SlotAction::Fork(single("Usage: !llamafork <name>")?)),
+        commands::Kind::Switch if args.is_empty() => Ok(SlotAction::List),
+        commands::Kind::Switch => Ok(SlotAction::Switch(single("Usage: !llamaswitch <name>")?)),
+        _ => {
+            let (action, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            let value = value.trim();
+
+            match (action, value) {
+                ("" | "list", _) => Ok(SlotAction::List),
+                ("use" | "system" | "model" | "delete", "") => Err(CONTEXT_USAGE.to_owned()),
+                ("use", slot) if !slot.contains(char::is_whitespace) => {
+                    Ok(SlotAction::Use(slot.to_owned()))
+                }
+                ("system", "none") => Ok(SlotAction::SystemPrompt(None)),
+                ("system", prompt) => Ok(SlotAction::SystemPrompt(Some(prompt.to_owned()))),
+                ("model", "default") => Ok(SlotAction::Model(None)),
+                ("model", model) if bot.config.get().user_models.contains(model) => {
+                    Ok(SlotAction::Model(Some(model.to_owned())))
+                }
+                ("model", model) => Err(format!("The {} model is not available", model)),
+                ("delete", slot) => Ok(SlotAction::Delete(slot.to_owned())),
+                _ => Err(CONTEXT_USAGE.to_owned()),
+            }
+        }
+    }
+}
+
+const JSON_USAGE: &str = "Usage: !llamajson [<schema>] <prompt>";
+
+/// Split the arguments of `!llamajson` into the output format, which is the
+/// JSON schema leading the prompt if there is one and plain JSON otherwise,
+/// and the prompt itself.
+fn json_args(args: &str) -> Result<(serde_json::Value, &str), String> {
+    if !args.starts_with('{') {
+        return match args.is_empty() {
+            true => Err(JSON_USAGE.to_owned()),
+            false => Ok((serde_json::Value::from("json"), args)),
+        };
+    }
+
+    let mut values = serde_json::Deserializer::from_str(args).into_iter::<serde_json::Value>();
+
+    match values.next() {
+        Some(Ok(schema)) => {
+            let prompt = args[values.byte_offset()..].trim();
+
+            match prompt.is_empty() {
+                true => Err(JSON_USAGE.to_owned()),
+                false => Ok((schema, prompt)),
+            }
+        }
+        _ => Err("The schema isn't valid JSON".to_owned()),
+    }
+}
+
+/// Execute a command, returning the reply to post, if any. Commands that
+/// respond asynchronously post their own replies and return `None`.
+///
+/// Commands reach here through [`dispatch::dispatch`], which has already
+/// checked that the sender may run them.
+async fn run_command(
+    cmd: commands::Kind,
+    args: &str,
+    evt: &OriginalSyncRoomMessageEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+) -> Option<String> {
+    let priority = bot.is_admin(&evt.sender);
+
+    match cmd {
+        commands::Kind::Clear => {
+            bot.queue
+                .send(
+                    LlamaReq::ClrCtx(rm.room_id().into(), thread_root(evt)),
+                    priority,
+                )
+                .await;
+
+            Some("Context cleared".to_owned())
+        }
+        // Handled along with ordinary prompts.
+        commands::Kind::Ask | commands::Kind::Retry => None,
+        commands::Kind::Summarize => {
+            let Some(fetcher) = &bot.fetcher else {
+                return Some("Fetching web pages is not enabled on this bot".to_owned());
+            };
+
+            let (link, question) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+            let Ok(url) = Url::parse(link) else {
+                return Some("Usage: !llamasummarize <url> [<question>]".to_owned());
+            };
+
+            let page = {
+                let _typing = TypingNotice::start(rm.clone());
+
+                match fetcher.fetch(url).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!("Failed to fetch {}: {}", link, e);
+                        return Some(format!("Sorry, I couldn't fetch that page: {}", e));
+                    }
+                }
+            };
+
+            let prompt = match question.trim() {
+                "" => format!(
+                    "Summarise the following web page in a short paragraph.\n\n{}",
+                    fetch::describe(&page)
+                ),
+                question => format!(
+                    "Answer the question below using the following web page.\n\n{}\n\nQuestion: {}",
+                    fetch::describe(&page),
+                    question
+                ),
+            };
+
+            let (mut req, rx) = LlamaChatReq::new(rm, prompt);
+            req.oneshot = true;
+
+            let answer = Answer::new(rm.room_id(), thread_root(evt), &evt.event_id, &evt.sender);
+            bot.answer(rm, req, rx, priority, answer).await;
+
+            None
+        }
+        commands::Kind::Image => {
+            let Some(generator) = &bot.image_generator else {
+                return Some("Image generation is not enabled on this bot".to_owned());
+            };
+
+            if args.is_empty() {
+                return Some("Usage: !llamaimage <prompt>".to_owned());
+            }
+
+            let (reply, rx) = oneshot::channel();
+            let req = LlamaReq::Image {
+                room_id: rm.room_id().to_owned(),
+                generator: generator.clone(),
+                prompt: args.to_owned(),
+                reply,
+            };
+
+            if !bot.enqueue(rm, req, priority).await {
+                return None;
+            }
+
+            let _typing = TypingNotice::start(rm.clone());
+
+            let image = match rx.await.context("The image request was dropped").flatten() {
+                Ok(image) => image,
+                Err(e) => {
+                    error!("Failed to generate an image: {}", e);
+                    return Some("Failed to generate the image".to_owned());
+                }
+            };
+
+            let config = AttachmentConfig::new().caption(Some(args.to_owned()));
+
+            if let Err(e) = rm
+                .send_attachment("image.png", &mime::IMAGE_PNG, image, config)
+                .await
+            {
+                error!("Failed to send an image to {}: {}", rm.room_id(), e);
+                return Some("Failed to send the image".to_owned());
+            }
+
+            None
+        }
+        commands::Kind::Stop => Some(match bot.queue.cancels.cancel(rm.room_id()) {
+            0 => "There's nothing to stop".to_owned(),
+            1 => "Stopped".to_owned(),
+            n => format!("Stopped {} requests", n),
+        }),
+        commands::Kind::Status => Some(status::status(rm, thread_root(evt), client, bot).await),
+        commands::Kind::Help => {
+            let (plain, html) = commands::help();
+
+            send_reply(rm, evt, bot.html(plain, html)).await;
+
+            None
+        }
+        commands::Kind::Info => {
+            let (plain, html) = match info_command(args, evt, rm, client, bot).await {
+                Ok(reply) => reply,
+                Err(e) => return Some(e),
+            };
+
+            send_reply(rm, evt, bot.html(plain, html)).await;
+
+            None
+        }
+        commands::Kind::Models => {
+            let defaults = bot.defaults.read().unwrap().clone();
+
+            match models::list(
+                &defaults.backend,
+                &defaults.model,
+                &bot.config.get().user_models,
+            )
+            .await
+            {
+                Ok((plain, html)) => {
+                    send_reply(rm, evt, bot.html(plain, html)).await;
+                    None
+                }
+                Err(e) => {
+                    error!("Failed to list models: {}", e);
+                    Some(format!(
+                        "Couldn't list the installed models: {}",
+                        llama::redact_error(&e)
+                    ))
+                }
+            }
+        }
+        // Handled by the dispatcher itself.
+        commands::Kind::Shutdown
+        | commands::Kind::ResetAll
+        | commands::Kind::Ban
+        | commands::Kind::Unban => None,
+        commands::Kind::Fork | commands::Kind::Switch | commands::Kind::Context => {
+            let action = match slot_action(cmd, args, bot) {
+                Ok(action) => action,
+                Err(reply) => return Some(reply),
+            };
+
+            let (reply, rx) = oneshot::channel();
+            let req = LlamaReq::Slots {
+                room_id: rm.room_id().to_owned(),
+                action,
+                reply,
+            };
+
+            bot.queue.send(req, priority).await;
+
+            rx.await.ok()
+        }
+        commands::Kind::Json => {
+            let (format, prompt) = match json_args(args) {
+                Ok(parsed) => parsed,
+                Err(reply) => return Some(reply),
+            };
+
+            let (mut req, mut rx) = LlamaChatReq::new(rm, prompt);
+            req.oneshot = true;
+            req.format = Some(format);
+
+            if !bot.enqueue(rm, req, priority).await {
+                return None;
+            }
+
+            while let Some(reply) = rx.recv().await {
+                match reply {
+                    Reply::Notice(notice) => {
+                        send_reply(rm, evt, RoomMessageEventContent::notice_plain(notice)).await
+                    }
+                    reply => {
+                        let resp = reply.into_text();
+                        let html = html::code_block("json", &resp);
+
+                        send_answer(rm, evt, bot.html(resp, html)).await;
+                    }
+                }
+            }
+
+            None
+        }
+        commands::Kind::Extract => {
+            let (Some(extraction), Some(target)) = (Extraction::parse(args), reply_target(evt))
+            else {
+                return Some(extract::USAGE.to_owned());
+            };
+
+            let text = match history::event_text(rm, &target).await {
+                Ok(Some(text)) => text,
+                Ok(None) => return Some("I can only extract from text".to_owned()),
+                Err(e) => {
+                    error!("Failed to fetch {} in {}: {}", target, rm.room_id(), e);
+                    return Some("Failed to fetch the message".to_owned());
+                }
+            };
+
+            let (mut req, mut rx) = LlamaChatReq::new(rm, extraction.prompt(&text));
+            req.oneshot = true;
+            req.format = Some(extraction.schema());
+
+            if !bot.enqueue(rm, req, priority).await {
+                return None;
+            }
+
+            while let Some(reply) = rx.recv().await {
+                match reply {
+                    Reply::Notice(notice) => {
+                        send_reply(rm, evt, RoomMessageEventContent::notice_plain(notice)).await
+                    }
+                    reply => {
+                        let content = bot.answer_content(extraction.render(&reply.into_text()));
+                        send_answer(rm, evt, content).await;
+                    }
+                }
+            }
+
+            None
+        }
+        commands::Kind::Tldr => {
+            let Some(root) = thread_root(evt) else {
+                return Some("!llamatldr can only be used inside a thread".to_owned());
+            };
+
+            let messages = match history::thread_messages(rm, &root).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Failed to fetch thread {} in {}: {}", root, rm.room_id(), e);
+                    return Some("Failed to fetch the thread".to_owned());
+                }
+            };
+
+            let messages: Vec<_> = messages.into_iter().filter(|m| !m.is_trigger()).collect();
+
+            if messages.is_empty() {
+                return Some("There is nothing in this thread to summarize".to_owned());
+            }
+
+            let prompt = history::summary_prompt(&history::transcript(&messages));
+            let (mut req, rx) = LlamaChatReq::new(rm, prompt);
+            req.oneshot = true;
+
+            let answer = Answer::new(rm.room_id(), Some(root), &evt.event_id, &evt.sender);
+            bot.answer(rm, req, rx, priority, answer).await;
+
+            None
+        }
+        commands::Kind::Search => {
+            let Some(indexer) = &bot.indexer else {
+                return Some("History indexing is not enabled on this bot".to_owned());
+            };
+
+            if args.is_empty() {
+                return Some("Usage: !llamasearch <query>".to_owned());
+            }
+
+            let results = match indexer.search(rm.room_id(), args, SEARCH_RESULTS).await {
+                Ok(results) => results,
+                Err(e) => {
+                    error!("Failed to search history of {}: {}", rm.room_id(), e);
+                    return Some("Failed to search the room history".to_owned());
+                }
+            };
+
+            if results.is_empty() {
+                return Some(
+                    "Nothing has been indexed in this room yet, see !llamaindex".to_owned(),
+                );
+            }
+
+            let (plain, html) = retrieval::format_results(rm.room_id(), &results);
+
+            send_reply(rm, evt, bot.html(plain, html)).await;
+
+            None
+        }
+        commands::Kind::Profile => Some(profile_command(args, &evt.sender, client).await),
+        commands::Kind::Prefs => {
+            if !rm.is_direct().await.unwrap_or(false) {
+                return Some(
+                    "Personal preferences can only be changed in a direct chat with me".to_owned(),
+                );
+            }
+
+            Some(prefs_command(args, &evt.sender, client, bot).await)
+        }
+        commands::Kind::Setup => {
+            let settings = match RoomSettings::load(client, rm.room_id()).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    error!("Failed to load settings for {}: {}", rm.room_id(), e);
+                    return Some("Failed to load room settings".to_owned());
+                }
+            };
+
+            let direct = rm.is_direct().await.unwrap_or(false);
+
+            let dm = match direct {
+                true => rm.clone(),
+                false => match dm::dm_room(client, &evt.sender, bot.encrypt_dms).await {
+                    Ok(dm) => dm,
+                    Err(e) => {
+                        error!("Failed to open a direct chat with {}: {}", evt.sender, e);
+                        return Some("I couldn't open a direct chat with you".to_owned());
+                    }
+                },
+            };
+
+            let wizard = Wizard::new(rm.room_id().to_owned(), settings);
+            let intro = format!(
+                "Let's set up {}.\n\n{}",
+                rm.name().unwrap_or_else(|| rm.room_id().to_string()),
+                wizard.question(&bot.config.get().user_models)
+            );
+
+            bot.wizards.start(dm.room_id(), &evt.sender, wizard);
+
+            if let Err(e) = dm.send(bot.plain(intro)).await {
+                error!("Failed to start setup with {}: {}", evt.sender, e);
+                bot.wizards.take(dm.room_id(), &evt.sender);
+                return Some("I couldn't message you in our direct chat".to_owned());
+            }
+
+            match direct {
+                true => None,
+                false => {
+                    Some("I've sent you a direct message to go through the settings".to_owned())
+                }
+            }
+        }
+        commands::Kind::Alias => Some(alias_command(args, rm, client).await),
+        commands::Kind::Seed => Some(seed_command(args, rm, client).await),
+        commands::Kind::Usage => Some(usage_command(args, rm, client, bot).await),
+        commands::Kind::Set => Some(set_command(args, rm, client).await),
+        commands::Kind::Settings => {
+            let settings = match RoomSettings::load(client, rm.room_id()).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    error!("Failed to load settings for {}: {}", rm.room_id(), e);
+                    return Some("Failed to load room settings".to_owned());
+                }
+            };
+
+            match settings.options.sampling().describe().as_slice() {
+                [] => Some("This room uses the bot's default sampling options".to_owned()),
+                options => Some(format!(
+                    "Sampling options for this room:\n{}",
+                    options.join("\n")
+                )),
+            }
+        }
+        commands::Kind::System => Some(system_command(args, rm, client, bot).await),
+        commands::Kind::Verify => match args.split_once(char::is_whitespace) {
+            Some(("accept", flow_id)) => {
+                Some(bot.verifier.decide(client, flow_id.trim(), true).await)
+            }
+            Some(("reject", flow_id)) => {
+                Some(bot.verifier.decide(client, flow_id.trim(), false).await)
+            }
+            Some(("match", flow_id)) => Some(bot.verifier.compare(flow_id.trim(), true)),
+            Some(("mismatch", flow_id)) => Some(bot.verifier.compare(flow_id.trim(), false)),
+            _ => Some("Usage: !llamaverify accept|reject|match|mismatch <request>".to_owned()),
+        },
+        commands::Kind::Admin => {
+            Some(admin::admin_command(args, &evt.sender, rm, client, bot).await)
+        }
+        commands::Kind::Index => {
+            let Some(indexer) = &bot.indexer else {
+                return Some("History indexing is not enabled on this bot".to_owned());
+            };
+
+            let enable = match args {
+                "on" => true,
+                "off" => false,
+                _ => return Some("Usage: !llamaindex on|off".to_owned()),
+            };
+
+            let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    error!("Failed to load settings for {}: {}", rm.room_id(), e);
+                    return Some("Failed to load room settings".to_owned());
+                }
+            };
+
+            if enable && settings.ephemeral {
+                return Some("History can't be indexed while the room is ephemeral".to_owned());
+            }
+
+            settings.index_history = enable;
+
+            if let Err(e) = settings.save(client, rm.room_id()).await {
+                error!("Failed to save settings for {}: {}", rm.room_id(), e);
+                return Some("Failed to save room settings".to_owned());
+            }
+
+            if enable {
+                indexer.touch(rm.room_id());
+                Some("History indexing enabled for this room".to_owned())
+            } else {
+                Some("History indexing disabled for this room".to_owned())
+            }
+        }
+        commands::Kind::Ephemeral => {
+            let enable = match args {
+                "on" => true,
+                "off" => false,
+                _ => return Some("Usage: !llamaephemeral on|off".to_owned()),
+            };
+
+            let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    error!("Failed to load settings for {}: {}", rm.room_id(), e);
+                    return Some("Failed to load room settings".to_owned());
+                }
+            };
+
+            settings.ephemeral = enable;
+            settings.index_history &= !enable;
+
+            if let Err(e) = settings.save(client, rm.room_id()).await {
+                error!("Failed to save settings for {}: {}", rm.room_id(), e);
+                return Some("Failed to save room settings".to_owned());
+            }
+
+            if !enable {
+                return Some("Ephemeral mode disabled for this room".to_owned());
+            }
+
+            // Nothing from before should outlive the switch either.
+            let (reply, rx) = oneshot::channel();
+            let req = LlamaReq::Slots {
+                room_id: rm.room_id().to_owned(),
+                action: SlotAction::Forget,
+                reply,
+            };
+
+            bot.queue.send(req, priority).await;
+            let _ = rx.await;
+
+            Some(
+                "Ephemeral mode enabled: I'll forget each exchange once it's answered, \
+                 and nothing said here will be stored"
+                    .to_owned(),
+            )
+        }
+        commands::Kind::Condense => {
+            let enable = match args {
+                "on" => true,
+                "off" => false,
+                _ => return Some("Usage: !llamacondense on|off".to_owned()),
+            };
+
+            let mut settings = match RoomSettings::load(client, rm.room_id()).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    error!("Failed to load settings for {}: {}", rm.room_id(), e);
+                    return Some("Failed to load room settings".to_owned());
+                }
+            };
+
+            settings.condense = enable;
+
+            if let Err(e) = settings.save(client, rm.room_id()).await {
+                error!("Failed to save settings for {}: {}", rm.room_id(), e);
+                return Some("Failed to save room settings".to_owned());
+            }
+
+            match enable {
+                true => Some(
+                    "Old messages will be summarised once the conversation outgrows the context"
+                        .to_owned(),
+                ),
+                false => Some(
+                    "Old messages will be forgotten once the conversation outgrows the context"
+                        .to_owned(),
+                ),
+            }
+        }
+    }
+}
+
+/// Feed a reply from the user into their setup wizard, saving the settings
+/// once every question has been answered.
+async fn continue_wizard(
+    mut wizard: Wizard,
+    answer: &str,
+    evt: &OriginalSyncRoomMessageEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+) {
+    let reply = if answer.trim() == "cancel" {
+        "Setup cancelled, nothing was changed".to_owned()
+    } else {
+        match wizard.answer(answer, &bot.config.get().user_models) {
+            Progress::Ask(question) => {
+                bot.wizards.start(rm.room_id(), &evt.sender, wizard);
+                question
+            }
+            Progress::Done => match wizard.settings.save(client, &wizard.room_id).await {
+                Ok(()) => "All done, the new settings are now in effect".to_owned(),
+                Err(e) => {
+                    error!("Failed to save settings for {}: {}", wizard.room_id, e);
+                    "Failed to save the room settings".to_owned()
+                }
+            },
+        }
+    };
+
+    send_reply(rm, evt, bot.plain(reply)).await;
+}
+
+/// Media that came along with a prompt.
+enum Attachment<'a> {
+    Image(&'a ImageMessageEventContent),
+    Voice(&'a AudioMessageEventContent),
+    Document(&'a FileMessageEventContent),
+}
+
+/// Add the document in `file` to the conversation that `thread` belongs to,
+/// and confirm how much of it was read. Returns whether there is a prompt to
+/// answer about it as well.
+async fn ingest_document(
+    file: &FileMessageEventContent,
+    settings: &RoomSettings,
+    thread: Option<OwnedEventId>,
+    evt: &OriginalSyncRoomMessageEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+) -> bool {
+    let reply = |text: String| {
+        let mut content = RoomMessageEventContent::notice_plain(text);
+        content.relates_to = thread
+            .clone()
+            .map(|root| Relation::Thread(Thread::plain(root, evt.event_id.clone())));
+        content
+    };
+
+    if settings.ephemeral {
+        let text = "This room doesn't keep its conversation, so I can't keep the document in it";
+        let _ = rm.send(reply(text.to_owned())).await;
+        return false;
+    }
+
+    let _typing = TypingNotice::start(rm.clone());
+
+    let document = match documents::read(client, file).await {
+        Ok(Some(document)) => document,
+        Ok(None) => {
+            let text = "I can only read plain text, Markdown and PDF files";
+            let _ = rm.send(reply(text.to_owned())).await;
+            return false;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to read {} in {}: {}",
+                file.filename(),
+                rm.room_id(),
+                e
+            );
+            let text = format!("Sorry, I couldn't read {}", file.filename());
+            let _ = rm.send(reply(text)).await;
+            return false;
+        }
+    };
+
+    bot.queue
+        .send(
+            LlamaReq::Ingest {
+                room_id: rm.room_id().to_owned(),
+                thread: thread.clone(),
+                chunks: document.chunks(),
+            },
+            bot.is_admin(&evt.sender),
+        )
+        .await;
+
+    let mut text = format!(
+        "Read {} (about {} tokens)",
+        document.name,
+        document.tokens()
+    );
+
+    if document.truncated {
+        text.push_str(", though it was too long to read all of it");
+    }
+
+    // Documents are kept in the index too, to be found again once they have
+    // scrolled out of the conversation.
+    if let Some(indexer) = &bot.indexer
+        && settings.index_history
+    {
+        match indexer
+            .add_document(rm.room_id(), &document.name, &document.passages())
+            .await
+        {
+            Ok(()) => text.push_str(", and added it to this room's index"),
+            Err(e) => warn!(
+                "Failed to index {} in {}: {}",
+                document.name,
+                rm.room_id(),
+                e
+            ),
+        }
+    }
+
+    let _ = rm.send(reply(text)).await;
+
+    file.caption().is_some()
+}
+
+/// Download the recording in a voice message and transcribe it.
+async fn transcribe_voice(
+    transcriber: &Transcriber,
+    client: &Client,
+    audio: &AudioMessageEventContent,
+) -> Result<String> {
+    let size = audio.info.as_ref().and_then(|info| info.size);
+    let bytes = media::download(client, audio, size).await?;
+
+    transcriber.transcribe(bytes, audio.filename()).await
+}
+
+/// The prompt for an image posted without a caption.
+const DESCRIBE_IMAGE: &str = "Describe this image.";
+
+async fn handle_msg_event(
+    evt: OriginalSyncRoomMessageEvent,
+    rm: Room,
+    client: Client,
+    bot: Ctx<Bot>,
+) {
+    if let Some(indexer) = &bot.indexer {
+        indexer.touch(rm.room_id());
+    }
+
+    // Don't respond to our own messages.
+    if evt.sender == client.user_id().unwrap() {
+        return;
+    }
+
+    // By the homeserver's clock, which may not quite agree with ours.
+    let age = evt
+        .origin_server_ts
+        .to_system_time()
+        .and_then(|sent| SystemTime::now().duration_since(sent).ok());
+
+    if let (Some(max_age), Some(age)) = (bot.max_prompt_age, age)
+        && age > max_age
+    {
+        info!(
+            "Ignoring a message from {} in {} sent {}s ago",
+            evt.sender,
+            rm.room_id(),
+            age.as_secs()
+        );
+        return;
+    }
+
+    // Media can only be addressed to the bot through its caption, so without
+    // one it is taken as a prompt only where every message is.
+    let (text, attachment) = match &evt.content.msgtype {
+        MessageType::Text(txt) => (txt.body.as_str(), None),
+        MessageType::Image(img) => (
+            img.caption().unwrap_or(DESCRIBE_IMAGE),
+            Some(Attachment::Image(img)),
+        ),
+        MessageType::Audio(audio) if bot.transcriber.is_some() => {
+            ("", Some(Attachment::Voice(audio)))
+        }
+        MessageType::File(file) => (
+            file.caption().unwrap_or_default(),
+            Some(Attachment::Document(file)),
+        ),
+        MessageType::VerificationRequest(_) => {
+            bot.verifier
+                .on_request(&client, &evt.sender, evt.event_id.as_str())
+                .await;
+            return;
+        }
+        // Notices are sent by other bots, and answering them could have two
+        // bots answer each other forever.
+        MessageType::Notice(_) => return,
+        _ => {
+            warn!("Could not reply to non-text based message");
+            return;
+        }
+    };
+
+    let matched = text.strip_prefix("!llama");
+
+    if matched.is_none()
+        && attachment.is_none()
+        && let Some(wizard) = bot.wizards.take(rm.room_id(), &evt.sender)
+    {
+        continue_wizard(wizard, text, &evt, &rm, &client, &bot).await;
+        return;
+    }
+
+    let direct = rm.is_direct().await.unwrap();
+
+    let settings = RoomSettings::load(&client, rm.room_id())
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load settings for {}: {}", rm.room_id(), e);
+            RoomSettings::default()
+        });
+
+    if !direct && matched.is_none() && settings.trigger != Trigger::All {
+        return;
+    }
+
+    receipts::mark_read(&rm, &evt.event_id).await;
+
+    if !bot.may_use(&evt.sender) {
+        if let Some(refusal) = &bot.config.get().refusal_message {
+            send_reply(&rm, &evt, bot.plain(refusal)).await;
+        }
+
+        return;
+    }
+
+    if bot.shutdown.is_stopping() {
+        let reply = "I'm shutting down, please try again in a moment";
+        send_reply(&rm, &evt, bot.plain(reply)).await;
+        return;
+    }
+
+    let mut prompt = matched.unwrap_or(text);
+    let mut oneshot = false;
+
+    if let Some((cmd, args)) = matched.and_then(|m| commands::parse(m, &settings.aliases)) {
+        // A one-shot question is a prompt like any other, bar the context
+        // it is answered in, so goes through the same checks. So does a
+        // retry, in [`retry`].
+        if cmd == commands::Kind::Retry {
+            let Some(options) = retry_options(args) else {
+                send_reply(&rm, &evt, bot.plain(RETRY_USAGE)).await;
+                return;
+            };
+
+            let thread = thread_root(&evt);
+            retry(
+                &rm,
+                &bot,
+                &settings,
+                &evt.sender,
+                thread,
+                &evt.event_id,
+                options,
+            )
+            .await;
+            return;
+        } else if cmd != commands::Kind::Ask {
+            if let Some(reply) =
+                dispatch::dispatch(cmd, args, &evt, &rm, &client, &bot, &settings).await
+            {
+                send_reply(&rm, &evt, bot.plain(reply)).await;
+            }
+
+            return;
+        } else if args.is_empty() {
+            let reply = bot.plain("Usage: !llamaq <question>");
+            send_reply(&rm, &evt, reply).await;
+            return;
+        } else {
+            prompt = args;
+            oneshot = true;
+        }
+    }
+
+    if let Err(reply) = bot.admit(&rm, &evt.sender, &settings).await {
+        send_reply(&rm, &evt, bot.plain(reply)).await;
+        return;
+    }
+
+    // Only once the prompt is sure to be answered, as transcribing takes
+    // a while.
+    let transcript = match (&attachment, &bot.transcriber) {
+        (Some(Attachment::Voice(audio)), Some(transcriber)) => {
+            let _typing = TypingNotice::start(rm.clone());
+
+            match transcribe_voice(transcriber, &client, audio).await {
+                Ok(transcript) if !transcript.is_empty() => Some(transcript),
+                Ok(_) => {
+                    let reply = "I couldn't make out any words in that recording";
+                    send_reply(&rm, &evt, bot.plain(reply)).await;
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to transcribe voice message in {}: {}",
+                        rm.room_id(),
+                        e
+                    );
+
+                    let reply = "Sorry, I couldn't transcribe that recording";
+                    send_reply(&rm, &evt, bot.plain(reply)).await;
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(transcript) = &transcript {
+        prompt = transcript;
+    }
+
+    let catch_up = match bot.catch_up_after {
+        Some(gap) if !direct && !oneshot => catchup::missed_messages(&rm, &evt.event_id, gap)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to read back missed messages: {}", e);
+                None
+            }),
+        _ => None,
+    };
+
+    // In group rooms, a prompt outside of a thread starts one, so that
+    // each conversation keeps to itself and out of the timeline.
+    let thread = match thread_root(&evt) {
+        Some(root) => Some(root),
+        None if !direct => Some(evt.event_id.clone()),
+        None => None,
+    };
+
+    let mut prompt = history::expand_permalinks(&rm, prompt).await;
+
+    if let Some(fetcher) = &bot.fetcher {
+        prompt = fetcher.expand_links(&prompt).await;
+    }
+
+    let retrieved = match &bot.indexer {
+        Some(indexer) if settings.index_history && !settings.ephemeral => indexer
+            .context_for(rm.room_id(), &prompt, &evt.event_id)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to search the index of {}: {}", rm.room_id(), e);
+                None
+            }),
+        _ => None,
+    };
+
+    let profile = UserProfile::load(&client, &evt.sender)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load profile of {}: {}", evt.sender, e);
+            UserProfile::default()
+        });
+
+    if !oneshot && let Some(about) = profile.describe() {
+        prompt = format!(
+            "[The following message is from {}. {}]\n\n{}",
+            evt.sender, about, prompt
+        );
+    }
+
+    if let Some(Attachment::Document(file)) = attachment
+        && !ingest_document(file, &settings, thread.clone(), &evt, &rm, &client, &bot).await
+    {
+        return;
+    }
+
+    let images = match attachment {
+        Some(Attachment::Image(img)) => {
+            match media::download(&client, img, img.info.as_ref().and_then(|info| info.size)).await
+            {
+                Ok(bytes) => vec![BASE64_STANDARD.encode(bytes)],
+                Err(e) => {
+                    warn!("Failed to download image from {}: {}", rm.room_id(), e);
+
+                    let reply = bot.plain("Sorry, I couldn't download that image");
+                    send_reply(&rm, &evt, reply).await;
+                    return;
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+
+    let (mut req, rx) = room_request(&rm, &bot, &settings, &profile, prompt);
+
+    // Show what was heard, ahead of the answer to it.
+    if let Some(transcript) = &transcript {
+        let _ = req
+            .reply_tx
+            .send(Reply::Post(transcribe::quote(transcript)));
+    }
+
+    req.catch_up = catch_up;
+    req.retrieved = retrieved;
+    // Ephemeral rooms never keep a conversation beyond the exchange.
+    req.oneshot = oneshot || settings.ephemeral;
+    req.thread = thread.clone();
+    req.images = images;
+
+    let answer = Answer::new(rm.room_id(), thread, &evt.event_id, &evt.sender);
+    bot.answer(&rm, req, rx, bot.is_admin(&evt.sender), answer)
+        .await;
+
+    budget_notice(&rm, &bot, &settings).await;
+}
+
+/// A request for the model to answer `prompt` in `rm`, with the room's
+/// settings and persona, and the model of `profile` if its user may pick it.
+fn room_request(
+    rm: &Room,
+    bot: &Bot,
+    settings: &RoomSettings,
+    profile: &UserProfile,
+    prompt: impl ToString,
+) -> (LlamaChatReq, UnboundedReceiver<Reply>) {
+    let config = bot.config.get();
+    let (mut req, rx) = LlamaChatReq::new(rm, prompt);
+
+    req.vision_model = bot.vision_model.clone();
+    req.tools = bot.tools.clone();
+    req.model = profile
+        .model
+        .clone()
+        .or(settings.model.clone())
+        .filter(|m| config.user_models.contains(m));
+    (req.system_prompt, req.options) = config.persona(
+        settings.named_persona.as_deref(),
+        settings.persona.as_deref(),
+    );
+    req.seed = settings.seed;
+    req.format = config.format.clone();
+    req.condense = settings.condense;
+    req.usage_footer = settings.usage_footer;
+    req.room_options = settings.options.clone().sampling();
+
+    (req, rx)
+}
+
+/// Answer the last prompt of the conversation in `thread`, or the room's,
+/// again for `sender`, as `!llamaretry` asks: with `overrides` on top of
+/// every other option. The new answer follows `prompt`, the event asking for
+/// it, and replaces the last one in the conversation.
+async fn retry(
+    rm: &Room,
+    bot: &Bot,
+    settings: &RoomSettings,
+    sender: &UserId,
+    thread: Option<OwnedEventId>,
+    prompt: &EventId,
+    overrides: Options,
+) {
+    if let Err(reply) = bot.admit(rm, sender, settings).await {
+        let mut content = bot.plain(reply);
+        content.relates_to = thread
+            .clone()
+            .map(|root| Relation::Thread(Thread::plain(root, prompt.to_owned())));
+
+        let _ = rm.send(content).await;
+        return;
+    }
+
+    let profile = UserProfile::load(&rm.client(), sender)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load profile of {}: {}", sender, e);
+            UserProfile::default()
+        });
+
+    // The prompt is taken from the conversation.
+    let (mut req, rx) = room_request(rm, bot, settings, &profile, "");
+    req.thread = thread.clone();
+    req.retry = true;
+    // The room's seed would only give the same answer again.
+    req.seed = None;
+    req.overrides = overrides;
+
+    let answer = Answer::new(rm.room_id(), thread, prompt, sender);
+    bot.answer(rm, req, rx, bot.is_admin(sender), answer).await;
+
+    budget_notice(rm, bot, settings).await;
+}
+
+/// Tell `rm` when it has used up its daily token budget, or has little of it
+/// left.
+async fn budget_notice(rm: &Room, bot: &Bot, settings: &RoomSettings) {
+    let budget = settings.token_budget.or(bot.config.get().token_budget);
+
+    if let Some(budget) = budget
+        && let Ok(used) = bot.budgets.used(rm.room_id()).await
+    {
+        let notice = match budget::standing(budget, used) {
+            Standing::Exhausted => Some(format!(
+                "This room has now used all {} tokens of its daily budget, which resets at midnight UTC",
+                budget
+            )),
+            Standing::Low(remaining) => Some(format!(
+                "This room has {} of its {} daily tokens left",
+                remaining, budget
+            )),
+            Standing::Fine => None,
+        };
+
+        if let Some(notice) = notice {
+            let _ = rm.send(RoomMessageEventContent::notice_plain(notice)).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let command_line = config::command_line()?;
+    let accounts = config::accounts(config::path(&command_line).as_deref())?;
+
+    // Each account's flags are checked once they're put together with its
+    // own, so those given for all of them needn't make a whole command line.
+    let args = match accounts.is_empty() {
+        true => Args::parse_from(command_line),
+        false => Args::from_arg_matches(
+            &Args::command()
+                .ignore_errors(true)
+                .get_matches_from(command_line),
+        )?,
+    };
+
+    if accounts.is_empty() {
+        if let Some(account) = &args.account {
+            bail!(
+                "--account {} was given, but the config file lists no accounts",
+                account
+            );
+        }
+
+        return run(args).await;
+    }
+
+    let accounts: Vec<_> = accounts
+        .into_iter()
+        .filter(|(name, _)| args.account.as_ref().is_none_or(|account| account == name))
+        .collect();
+
+    match (&args.action, accounts.as_slice()) {
+        (Some(Action::Registration { .. }), _) => run(args).await,
+        (_, []) => bail!(
+            "There is no account {} in the config file",
+            args.account.as_deref().unwrap_or_default()
+        ),
+        (Some(Action::Run) | None, _) => supervisor::run(accounts).await,
+        (_, [(name, command_line)]) => run(supervisor::account_args(name, command_line)?).await,
+        _ => bail!("The config file lists several accounts, pick one with --account"),
+    }
+}
+
+/// Run the bot, or whichever subcommand was given, as one account.
+async fn run(args: Args) -> Result<()> {
+    let server = ServerName::parse(&args.homeserver).context("Could not parse homeserver")?;
+    let data_dir = args.data_dir();
+    let sync_settings = args.sync_settings();
+
+    fs::create_dir_all(&data_dir).context("Could not create data dir")?;
+
+    match &args.action {
+        Some(Action::Registration { id, prefix }) => {
+            return appservice::print_registration(id, prefix, &server);
+        }
+        Some(Action::Login) => return session::login_only(&args, &server).await,
+        Some(Action::Logout) => return session::logout(&args, &server).await,
+        Some(Action::Verify) => {
+            let client = session::connect(&args, &server).await?;
+
+            return verification::verify_interactively(&client).await;
+        }
+        Some(Action::ClearSession) => return session::clear(&args),
+        Some(Action::Run) | None => {}
+    }
+
+    let Some(model) = args.model.clone() else {
+        bail!("--model is needed to run the bot");
+    };
+
+    let ollama_token = secret::read(
+        "ollama token",
+        None,
+        args.ollama_token_env.as_deref(),
+        args.ollama_token_file.as_deref(),
+        args.ollama_token_keyring.as_deref(),
+    )
+    .await?;
+    let recovery_key = secret::read(
+        "recovery key",
+        None,
+        args.recovery_key_env.as_deref(),
+        args.recovery_key_file.as_deref(),
+        args.recovery_key_keyring.as_deref(),
+    )
+    .await?;
+    let brave_api_key = secret::read(
+        "Brave API key",
+        None,
+        args.brave_api_key_env.as_deref(),
+        args.brave_api_key_file.as_deref(),
+        args.brave_api_key_keyring.as_deref(),
+    )
+    .await?;
+
+    if args.server_max_concurrent.len() > args.urls.len() {
+        bail!("--server-max-concurrent was given more times than --url");
+    }
+
+    let mut servers = Vec::new();
+
+    for (i, url) in args.urls.iter().enumerate() {
+        servers.push(llama::Server {
+            url: url.clone(),
+            max_concurrent: args.server_max_concurrent.get(i).map(|&n| n as usize),
+        });
+    }
+
+    let backend = Backend::new(
+        servers,
+        args.routing,
+        args.max_concurrent as usize,
+        args.keep_alive.as_deref(),
+        args.llm_retries,
+        llama::Connection {
+            headers: llama::auth_headers(ollama_token, args.ollama_headers_file.as_deref())?,
+            ca_cert: args.ollama_ca_cert.clone(),
+            client_cert: args.ollama_client_cert.clone(),
+            insecure: args.ollama_insecure,
+        },
+    )?;
+
+    // Rather than logging in only to answer nobody. Sending a message or a
+    // broadcast doesn't need ollama.
+    if args.send_dm.is_none() && args.broadcast.is_none() {
+        let version = backend.version().await.with_context(|| {
+            format!(
+                "Could not reach ollama at {}, check --url and that ollama is running",
+                backend.url()
+            )
+        })?;
+
+        info!("Using ollama {} at {}", version, backend.url());
+    }
+
+    let client = session::connect(&args, &server).await?;
+
+    if let Some([user, message]) = args.send_dm.as_deref() {
+        let user = UserId::parse(user.as_str()).context("Could not parse user ID")?;
+
+        client
+            .sync_once(sync_settings.clone().timeout(Duration::from_millis(500)))
+            .await?;
+
+        return admin::send_dm(&client, &user, message, !args.unencrypted_dms).await;
+    }
+
+    if let Some(message) = &args.broadcast {
+        client
+            .sync_once(sync_settings.clone().timeout(Duration::from_millis(500)))
+            .await?;
+
+        let (sent, failed) = admin::broadcast(&client, message).await;
+
+        println!("Broadcast sent to {} rooms", sent);
+
+        for (room_id, e) in &failed {
+            eprintln!("Failed to send to {}: {}", room_id, e);
+        }
+
+        if !failed.is_empty() {
+            bail!("Broadcast failed in {} rooms", failed.len());
+        }
+
+        return Ok(());
+    }
+
+    tokio::spawn(trust::log_new_devices(client.clone(), args.trust_mode));
+
+    let shutdown = Shutdown::default();
+    shutdown
+        .on_signals()
+        .context("Could not listen for signals")?;
+
+    let waiting = Arc::new(Waiting::default());
+    let queue = LlamaQueue {
+        waiting: waiting.clone(),
+        cancels: Cancels::default(),
+        workers: args.workers as usize,
+    };
+
+    let mcp_servers = config::mcp_servers(args.config.as_deref())?;
+    let config = config::load(
+        config::Settings {
+            admins: args.admins.into_iter().collect(),
+            allow_invites_from: args.allow_invites_from,
+            allow_users: args.allow_users,
+            deny_users: args.deny_users,
+            refusal_message: args.refusal_message,
+            user_models: args
+                .user_models
+                .into_iter()
+                .chain([model.clone()])
+                .collect(),
+            persona: args.system_prompt,
+            quota: None,
+            maintenance_message: args.maintenance_message,
+            token_budget: args.room_token_budget,
+            context_window: args.context_window,
+            format: None,
+            // The last given takes precedence, as with any other flag.
+            options: args
+                .options
+                .iter()
+                .rev()
+                .fold(Options::default(), |mut all, options| {
+                    all.merge(options);
+                    all
+                }),
+            models: Default::default(),
+            personas: Default::default(),
+        },
+        args.config,
+    )?;
+    let maintenance = Maintenance::new(config.clone());
+    let budgets = Budgets::new(client.clone());
+    let throughput = Throughput::default();
+
+    let llama = tokio::spawn(llama_task(
+        LlamaQueueRx {
+            waiting,
+            maintenance: maintenance.subscribe(),
+            shutdown: shutdown.clone(),
+            workers: args.workers as usize,
+        },
+        backend.clone(),
+        model.clone(),
+        Delivery {
+            stream_mode: args.stream_mode,
+            heartbeat_after: (args.heartbeat_after > 0)
+                .then(|| Duration::from_secs(args.heartbeat_after)),
+            timeout: args.llm_timeout.map(Duration::from_secs),
+            throughput: throughput.clone(),
+            cache: args.response_cache.filter(|&c| c > 0).map(|capacity| {
+                ResponseCache::new(capacity, Duration::from_secs(args.response_cache_ttl))
+            }),
+            usage_footer: false,
+        },
+        config.clone(),
+        budgets.clone(),
+        client.clone(),
+    ));
+
+    client.add_event_handler_context(Invites {
+        config: config.clone(),
+        reports: args
+            .admin_room
+            .clone()
+            .filter(|_| args.report_rejected_invites),
+    });
+    client.add_event_handler(accept_invites);
+
+    let token = client
+        .sync_once(sync_settings.clone().timeout(Duration::from_millis(500)))
         .await?;
 
-    client.add_event_handler_context(tx);
+    if let Some(recovery_key) = &recovery_key {
+        session::recover(&client, recovery_key).await;
+    }
+
+    let models: Vec<&str> = [
+        Some(&model),
+        args.vision_model.as_ref(),
+        args.embed_model.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(String::as_str)
+    .collect();
+
+    // After the first sync, so that the admin room is known. Any server
+    // could be failed over to, so each needs the models.
+    for backend in backend.each() {
+        pull::ensure_models(
+            &backend,
+            &models,
+            args.pull,
+            args.admin_room
+                .as_ref()
+                .and_then(|room_id| client.get_room(room_id)),
+        )
+        .await;
+    }
+
+    let markers = ReadMarkers::default();
+
+    tokio::spawn(receipts::advance_markers_task(
+        client.clone(),
+        markers.clone(),
+        Duration::from_secs(args.read_marker_interval),
+    ));
+
+    let indexer = match args.embed_model {
+        Some(model) => Some(retrieval::spawn_indexer(
+            client.clone(),
+            backend.clone(),
+            model,
+            VectorStore::new(data_dir.join("index")).context("Could not open vector store")?,
+            args.index_rate,
+        )),
+        None => None,
+    };
+
+    let scheduler = Scheduler::load(client.clone())
+        .await
+        .context("Could not load scheduled announcements")?;
+
+    tokio::spawn(scheduler.clone().run());
+
+    let verifier = Verifier::new(
+        args.verification_policy,
+        config.clone(),
+        args.admin_room.clone(),
+        !args.unencrypted_dms,
+    );
+
+    client.add_event_handler_context(verifier.clone());
+    client.add_event_handler(verification::on_to_device_request);
+
+    let defaults = Arc::new(RwLock::new(Defaults {
+        model: model.clone(),
+        backend: backend.clone(),
+    }));
+    let health = Health::new(defaults.clone());
+
+    health.watch_ollama();
+    let warmer = Warmer::new(defaults.clone());
+
+    if args.warm_up {
+        warmer
+            .clone()
+            .start(args.warm_up_idle.map(Duration::from_secs));
+    }
+
+    if let Some(addr) = args.health_addr {
+        health::serve(addr, health.clone()).await?;
+    }
+
+    let mut tools = Tools::default();
+
+    if args.tools {
+        tools.register(tools::Clock);
+    }
+
+    let search = match (args.searxng_url, brave_api_key) {
+        (Some(url), _) => Some(websearch::Provider::Searxng(url)),
+        (None, Some(key)) => Some(websearch::Provider::Brave(key)),
+        (None, None) => None,
+    };
+
+    if let Some(provider) = search {
+        tools.register(WebSearch::new(provider));
+    }
+
+    for (name, server) in &mcp_servers {
+        if !args.tools {
+            warn!("Not starting MCP server {}, as --tools isn't set", name);
+            continue;
+        }
+
+        let found = match mcp::Server::start(name, server).await {
+            Ok(server) => server.tools().await,
+            Err(e) => Err(e),
+        };
+
+        match found {
+            Ok(found) => found.into_iter().for_each(|tool| tools.register(tool)),
+            Err(e) => warn!("Failed to set up MCP server {}: {}", name, e),
+        }
+    }
+
+    let cancels = queue.cancels.clone();
+
+    let bot = Bot {
+        queue,
+        config,
+        verifier,
+        encrypt_dms: !args.unencrypted_dms,
+        broadcasts: Broadcasts::default(),
+        scheduler,
+        maintenance,
+        budgets,
+        throughput,
+        global_budget: GlobalBudget {
+            tokens: args.daily_token_budget,
+            generation_secs: args.daily_generation_secs,
+        },
+        indexer,
+        catch_up_after: args.catch_up_after.map(Duration::from_secs),
+        max_prompt_age: args.max_prompt_age.map(Duration::from_secs),
+        reply_to_prompts: args.reply_to_prompts,
+        notices: args.notices,
+        answers: Answers::default(),
+        ack_reactions: args.ack_reactions,
+        vision_model: args.vision_model,
+        image_generator: args.image_url.map(ImageGenerator::new),
+        transcriber: args
+            .transcription_url
+            .map(|url| Transcriber::new(url, args.transcription_model)),
+        tools,
+        fetcher: args
+            .fetch_urls
+            .then(|| Fetcher::new(args.fetch_allow, args.fetch_deny)),
+        wizards: Wizards::default(),
+        limiter: RateLimiter::default(),
+        bans: Bans::load(client.clone()).await?,
+        shutdown: shutdown.clone(),
+        busy_after: args.busy_after,
+        queue_limit: args.queue_limit,
+        defaults,
+        health: health.clone(),
+        warmer,
+        started: Instant::now(),
+    };
+
+    tokio::spawn(presence::publish_task(
+        client.clone(),
+        bot.clone(),
+        Duration::from_secs(args.presence_interval),
+    ));
+
+    client.add_event_handler_context(bot);
+    client.add_event_handler_context(markers);
     client.add_event_handler(handle_msg_event);
+    client.add_event_handler(reactions::on_reaction);
+    client.add_event_handler(receipts::track_timeline_event);
 
-    client
-        .sync(SyncSettings::default().token(token.next_batch))
-        .await?;
+    let sync = client.sync_with_callback(sync_settings.token(token.next_batch), |_| async {
+        health.synced();
+        LoopCtrl::Continue
+    });
+    tokio::pin!(sync);
+
+    select! {
+        result = &mut sync => return Ok(result?),
+        _ = shutdown.stopping() => {}
+    }
+
+    // Syncing carries on while the queue drains, as the event handlers that
+    // post the remaining replies run as part of it.
+    select! {
+        result = &mut sync => result?,
+        _ = shutdown.drain(llama, &cancels, Duration::from_secs(args.shutdown_grace), &client) => {}
+    }
 
     Ok(())
 }
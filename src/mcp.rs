@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::{Mutex as AsyncMutex, oneshot},
+    time::timeout,
+};
+
+use crate::tools::{Tool, ToolOutput};
+
+/// The version of the Model Context Protocol spoken to servers.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long a server has to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How to start an MCP server, as given in the config file.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>>;
+
+/// A running MCP server, spoken to with JSON-RPC over its stdin and stdout.
+pub struct Server {
+    name: String,
+    stdin: AsyncMutex<ChildStdin>,
+    /// Requests waiting for an answer, by ID.
+    pending: Pending,
+    next_id: AtomicU64,
+    /// Killed when the server is dropped.
+    _child: Child,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: Option<u64>,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolList {
+    tools: Vec<ToolInfo>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolInfo {
+    name: String,
+    #[serde(default)]
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallResult {
+    #[serde(default)]
+    content: Vec<Content>,
+    #[serde(default)]
+    is_error: bool,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+impl Server {
+    /// Start the server `name` and go through the protocol's handshake.
+    pub async fn start(name: &str, config: &ServerConfig) -> Result<Arc<Self>> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Could not start {}", config.command))?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let pending = Pending::default();
+
+        tokio::spawn({
+            let name = name.to_owned();
+            let pending = pending.clone();
+
+            async move {
+                let mut lines = BufReader::new(stdout).lines();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    // Anything that isn't an answer, such as a notification,
+                    // is of no interest.
+                    let Ok(Response {
+                        id: Some(id),
+                        result,
+                        error,
+                    }) = serde_json::from_str(&line)
+                    else {
+                        continue;
+                    };
+
+                    let Some(tx) = pending.lock().unwrap().remove(&id) else {
+                        continue;
+                    };
+
+                    let _ = tx.send(match error {
+                        Some(error) => Err(anyhow!(error.message)),
+                        None => Ok(result.unwrap_or_default()),
+                    });
+                }
+
+                warn!("MCP server {} exited", name);
+                pending.lock().unwrap().clear();
+            }
+        });
+
+        let server = Arc::new(Self {
+            name: name.to_owned(),
+            stdin: AsyncMutex::new(stdin),
+            pending,
+            next_id: AtomicU64::new(1),
+            _child: child,
+        });
+
+        server
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": "llamatrix",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        server
+            .send(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+
+        Ok(server)
+    }
+
+    async fn send(&self, message: Value) -> Result<()> {
+        let mut line = serde_json::to_vec(&message)?;
+        line.push(b'\n');
+
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(&line).await?;
+        stdin.flush().await?;
+
+        Ok(())
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let sent = self
+            .send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await;
+
+        if let Err(e) = sent {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        match timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => bail!("MCP server {} has exited", self.name),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                bail!(
+                    "MCP server {} took too long to answer {}",
+                    self.name,
+                    method
+                )
+            }
+        }
+    }
+
+    /// The tools the server offers.
+    pub async fn tools(self: &Arc<Self>) -> Result<Vec<McpTool>> {
+        let mut tools = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+
+            let list: ToolList = serde_json::from_value(self.request("tools/list", params).await?)?;
+
+            tools.extend(list.tools.into_iter().map(|info| McpTool {
+                server: self.clone(),
+                name: info.name,
+                description: info.description,
+                parameters: info.input_schema,
+            }));
+
+            match list.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        info!("MCP server {} offers {} tools", self.name, tools.len());
+
+        Ok(tools)
+    }
+}
+
+/// A tool offered by an MCP server.
+pub struct McpTool {
+    server: Arc<Server>,
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+impl Tool for McpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn call(&self, args: Value) -> BoxFuture<'_, Result<ToolOutput>> {
+        Box::pin(async move {
+            let result: CallResult = serde_json::from_value(
+                self.server
+                    .request(
+                        "tools/call",
+                        json!({ "name": self.name, "arguments": args }),
+                    )
+                    .await?,
+            )?;
+
+            // Only text is of any use to the model.
+            let text = result
+                .content
+                .into_iter()
+                .filter(|content| content.kind == "text")
+                .map(|content| content.text)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            match result.is_error {
+                true => bail!(text),
+                false => Ok(text.into()),
+            }
+        })
+    }
+}
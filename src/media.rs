@@ -0,0 +1,32 @@
+use anyhow::{Context, Result, bail};
+use matrix_sdk::{Client, media::MediaEventContent, ruma::UInt};
+
+/// The largest attachment the bot downloads, in bytes, so that one upload
+/// can't exhaust its memory.
+pub const MAX_BYTES: usize = 20 * 1024 * 1024;
+
+/// Download the file in `content`, which claims to be `size` bytes, refusing
+/// ones larger than [`MAX_BYTES`].
+pub async fn download(
+    client: &Client,
+    content: &impl MediaEventContent,
+    size: Option<UInt>,
+) -> Result<Vec<u8>> {
+    // Refused up front where the sender says how big it is, and checked again
+    // after, as nothing holds them to it.
+    if size.is_some_and(|size| u64::from(size) > MAX_BYTES as u64) {
+        bail!("The file is larger than {} MB", MAX_BYTES / 1024 / 1024);
+    }
+
+    let bytes = client
+        .media()
+        .get_file(content, true)
+        .await?
+        .context("The message has no file in it")?;
+
+    if bytes.len() > MAX_BYTES {
+        bail!("The file is larger than {} MB", MAX_BYTES / 1024 / 1024);
+    }
+
+    Ok(bytes)
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::{
+    html,
+    llama::{self, Backend},
+};
+
+/// Describe a size in bytes to one decimal place.
+fn describe_size(bytes: u64) -> String {
+    match bytes {
+        0..1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9),
+    }
+}
+
+/// The models installed on `backend`, as plain text and as an HTML table,
+/// noting the default and those users may pick for themselves.
+pub async fn list(
+    backend: &Backend,
+    default: &str,
+    user_models: &HashSet<String>,
+) -> Result<(String, String)> {
+    let mut models = backend.installed().await?;
+
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let choosable: HashSet<String> = user_models.iter().map(|m| llama::full_name(m)).collect();
+    let rows: Vec<Vec<String>> = models
+        .iter()
+        .map(|model| {
+            let note = if model.name == llama::full_name(default) {
+                "default"
+            } else if choosable.contains(&model.name) {
+                "pick with !llamaprefs model"
+            } else {
+                ""
+            };
+
+            vec![
+                model.name.clone(),
+                describe_size(model.size),
+                model.details.parameter_size.clone(),
+                model.details.quantization_level.clone(),
+                note.to_owned(),
+            ]
+        })
+        .collect();
+
+    if rows.is_empty() {
+        let text = format!(
+            "There are no models installed on {}",
+            llama::redact(backend.url())
+        );
+        return Ok((text.clone(), html::escape(&text)));
+    }
+
+    let plain: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let note = match row[4].as_str() {
+                "" => String::new(),
+                note => format!(" ({})", note),
+            };
+
+            format!(
+                "{}: {}, {} parameters, {}{}",
+                row[0], row[1], row[2], row[3], note
+            )
+        })
+        .collect();
+
+    let html = html::grid(
+        &["Model", "Size", "Parameters", "Quantization", "Notes"],
+        &rows,
+    );
+
+    Ok((
+        format!("Installed models:\n{}", plain.join("\n")),
+        format!("<p>Installed models:</p>{}", html),
+    ))
+}
+
+/// Describe `model`, along with the system prompt and seed a room answers
+/// with, as plain text and as HTML.
+pub async fn info(
+    backend: &Backend,
+    model: &str,
+    system_prompt: Option<&str>,
+    seed: Option<u64>,
+) -> Result<(String, String)> {
+    let info = backend.show(model).await?;
+    let or_unknown = |value: &str| match value {
+        "" => "unknown".to_owned(),
+        value => value.to_owned(),
+    };
+
+    let rows = [
+        ("Model", model.to_owned()),
+        ("Family", or_unknown(&info.details.family)),
+        ("Parameters", or_unknown(&info.details.parameter_size)),
+        ("Quantization", or_unknown(&info.details.quantization_level)),
+        (
+            "Context length",
+            info.context_length()
+                .map_or("unknown".to_owned(), |tokens| format!("{} tokens", tokens)),
+        ),
+        (
+            "System prompt",
+            match (system_prompt, &info.system) {
+                (Some(prompt), _) => prompt.to_owned(),
+                (None, Some(own)) => format!("{} (the model's own)", own),
+                (None, None) => "none".to_owned(),
+            },
+        ),
+        (
+            "Seed",
+            seed.map_or("random".to_owned(), |seed| seed.to_string()),
+        ),
+    ];
+
+    let mut plain: Vec<String> = rows
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    let mut html = html::table(&rows);
+
+    if !info.template.is_empty() {
+        plain.push(format!("Template:\n{}", info.template));
+        html.push_str(&format!(
+            "<p>Template:</p>{}",
+            html::code_block("text", &info.template)
+        ));
+    }
+
+    Ok((plain.join("\n"), html))
+}
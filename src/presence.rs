@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use log::warn;
+use matrix_sdk::{
+    Client,
+    ruma::{api::client::presence::set_presence, presence::PresenceState},
+};
+use tokio::{select, time::interval};
+
+use crate::Bot;
+
+/// Set the bot's presence, along with a status message if there is one.
+pub async fn set(client: &Client, state: PresenceState, status: Option<String>) {
+    let Some(user_id) = client.user_id() else {
+        return;
+    };
+
+    let mut request = set_presence::v3::Request::new(user_id.to_owned(), state.clone());
+    request.status_msg = status;
+
+    if let Err(e) = client.send(request, None).await {
+        warn!("Failed to set presence to {}: {}", state, e);
+    }
+}
+
+/// What the bot is up to, as its presence shows.
+fn describe(bot: &Bot) -> (PresenceState, String) {
+    if bot.health.ollama_down().is_some() {
+        return (
+            PresenceState::Unavailable,
+            "ollama is unreachable".to_owned(),
+        );
+    }
+
+    let model = bot.defaults.read().unwrap().model.clone();
+    let status = match bot.queue.waiting() {
+        1 => format!("serving {}, 1 request queued", model),
+        n => format!("serving {}, {} requests queued", model, n),
+    };
+
+    (PresenceState::Online, status)
+}
+
+/// Keep the bot's presence and status message up to date every `period`,
+/// going unavailable once it starts shutting down. Going offline is left to
+/// [`crate::shutdown::Shutdown::drain`], once the last replies are posted.
+pub async fn publish_task(client: Client, bot: Bot, period: Duration) {
+    let mut ticker = interval(period);
+    let mut published = None;
+
+    loop {
+        select! {
+            _ = ticker.tick() => {}
+            _ = bot.shutdown.stopping() => break,
+        }
+
+        let current = describe(&bot);
+
+        // Only changes are sent, as each one goes out to everyone who shares
+        // a room with the bot.
+        if published.as_ref() != Some(&current) {
+            set(&client, current.0.clone(), Some(current.1.clone())).await;
+            published = Some(current);
+        }
+    }
+
+    set(
+        &client,
+        PresenceState::Unavailable,
+        Some("shutting down".to_owned()),
+    )
+    .await;
+}
@@ -0,0 +1,95 @@
+use log::{info, warn};
+use matrix_sdk::{Room, ruma::events::room::message::RoomMessageEventContent};
+
+use crate::llama::{self, Backend};
+
+async fn report(admin_room: Option<&Room>, message: &str) {
+    if let Some(admin_room) = admin_room
+        && let Err(e) = admin_room
+            .send(RoomMessageEventContent::notice_plain(message))
+            .await
+    {
+        warn!("Failed to report to the admin room: {}", e);
+    }
+}
+
+/// Make sure each of `models` is installed on the server, pulling those that
+/// aren't if `pull` is set. Progress is logged, and the start and end of each
+/// pull is reported to the admin room if there is one.
+///
+/// Nothing here stops the bot from starting: a model that's still missing
+/// afterwards only fails the prompts that need it.
+pub async fn ensure_models(
+    backend: &Backend,
+    models: &[&str],
+    pull: bool,
+    admin_room: Option<Room>,
+) {
+    let installed = match backend.installed().await {
+        Ok(installed) => installed,
+        Err(e) => {
+            warn!("Couldn't list the models on {}: {}", backend.url(), e);
+            return;
+        }
+    };
+
+    for &model in models {
+        if installed
+            .iter()
+            .any(|installed| installed.name == llama::full_name(model))
+        {
+            continue;
+        }
+
+        if !pull {
+            warn!(
+                "{} isn't installed on {}, pass --pull to download it",
+                model,
+                backend.url()
+            );
+            continue;
+        }
+
+        info!("Pulling {}", model);
+        report(
+            admin_room.as_ref(),
+            &format!("Pulling {}, which isn't installed yet", model),
+        )
+        .await;
+
+        let mut last = (String::new(), 0);
+        let result = backend
+            .pull(model, |progress| {
+                // Log each step, and downloads every tenth of the way.
+                let percent = match (progress.completed, progress.total) {
+                    (Some(completed), Some(total)) if total > 0 => {
+                        completed * 100 / total / 10 * 10
+                    }
+                    _ => 0,
+                };
+
+                if (progress.status.as_str(), percent) != (last.0.as_str(), last.1) {
+                    match percent {
+                        0 => info!("Pulling {}: {}", model, progress.status),
+                        _ => info!("Pulling {}: {} {}%", model, progress.status, percent),
+                    }
+
+                    last = (progress.status.clone(), percent);
+                }
+            })
+            .await;
+
+        let message = match result {
+            Ok(()) => {
+                info!("Pulled {}", model);
+                format!("Pulled {}", model)
+            }
+            Err(e) => {
+                warn!("Failed to pull {}: {}", model, e);
+                format!("Failed to pull {}: {}", model, llama::redact_error(&e))
+            }
+        };
+
+        report(admin_room.as_ref(), &message).await;
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+type Key = (OwnedRoomId, OwnedUserId);
+
+/// Tracks when each user last sent prompts in each room, to enforce room
+/// quotas over a sliding one hour window.
+#[derive(Clone, Default)]
+pub struct RateLimiter(Arc<Mutex<HashMap<Key, VecDeque<Instant>>>>);
+
+impl RateLimiter {
+    /// Record a prompt from `user` in `room_id`, unless they have already
+    /// sent `per_hour` within the last hour, in which case `false` is
+    /// returned and nothing is recorded.
+    pub fn check(&self, room_id: &RoomId, user: &UserId, per_hour: u32) -> bool {
+        let now = Instant::now();
+        let mut sent = self.0.lock().unwrap();
+        let times = sent
+            .entry((room_id.to_owned(), user.to_owned()))
+            .or_default();
+
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= WINDOW)
+        {
+            times.pop_front();
+        }
+
+        if times.len() >= per_hour as usize {
+            return false;
+        }
+
+        times.push_back(now);
+        true
+    }
+}
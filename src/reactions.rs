@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use log::{error, info, warn};
+use matrix_sdk::{
+    Client, Room,
+    event_handler::Ctx,
+    ruma::{
+        EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+        events::{
+            reaction::{OriginalSyncReactionEvent, ReactionEventContent},
+            relation::Annotation,
+        },
+    },
+};
+
+use crate::{Bot, LlamaReq, llama::Options, store::RoomSettings};
+
+/// Reacting to an answer with this redacts it and removes it from the
+/// conversation.
+const REMOVE: &str = "❌";
+
+/// Reacting to an answer with this answers its prompt again.
+const REGENERATE: &str = "🔁";
+
+/// What the bot reacts to prompts with as soon as it sees them, with
+/// `--ack-reactions`.
+pub const SEEN: &str = "👀";
+
+/// What replaces [`SEEN`] once a prompt has been answered.
+pub const ANSWERED: &str = "✅";
+
+/// What replaces [`SEEN`] if a prompt couldn't be answered.
+pub const FAILED: &str = "⚠️";
+
+/// How many of the most recent answers can be reacted to.
+const REMEMBERED: usize = 1000;
+
+/// An answer the bot has posted, as one or more messages.
+#[derive(Clone)]
+pub struct Answer {
+    pub room_id: OwnedRoomId,
+    /// The root of the thread the answer is in, if any.
+    pub thread: Option<OwnedEventId>,
+    pub prompt: OwnedEventId,
+    pub asker: OwnedUserId,
+    /// Whether the exchange is kept in the conversation, which one-shot
+    /// questions aren't.
+    pub in_context: bool,
+    /// The messages the answer was posted as, so far.
+    pub events: Vec<OwnedEventId>,
+}
+
+impl Answer {
+    /// The answer to the `prompt` event from `asker`, about to be posted to
+    /// `room_id`, in `thread` if it's given.
+    pub fn new(
+        room_id: &RoomId,
+        thread: Option<OwnedEventId>,
+        prompt: &EventId,
+        asker: &UserId,
+    ) -> Self {
+        Self {
+            room_id: room_id.to_owned(),
+            thread,
+            prompt: prompt.to_owned(),
+            asker: asker.to_owned(),
+            in_context: true,
+            events: Vec::new(),
+        }
+    }
+
+    fn same_conversation(&self, other: &Answer) -> bool {
+        self.room_id == other.room_id && self.thread == other.thread
+    }
+}
+
+/// The most recent answers, oldest first, to look up what a reaction is to.
+#[derive(Clone, Default)]
+pub struct Answers(Arc<Mutex<VecDeque<Answer>>>);
+
+impl Answers {
+    /// Note `answer` as it stands, after another of its messages is posted.
+    pub fn record(&self, answer: &Answer) {
+        let mut answers = self.0.lock().unwrap();
+
+        match answers.iter_mut().find(|a| a.prompt == answer.prompt) {
+            Some(recorded) => *recorded = answer.clone(),
+            None => {
+                if answers.len() == REMEMBERED {
+                    answers.pop_front();
+                }
+
+                answers.push_back(answer.clone());
+            }
+        }
+    }
+
+    /// The answer `event_id` is part of, if it's still remembered, and
+    /// whether it's the latest exchange of its conversation.
+    fn find(&self, event_id: &OwnedEventId) -> Option<(Answer, bool)> {
+        let answers = self.0.lock().unwrap();
+        let pos = answers.iter().position(|a| a.events.contains(event_id))?;
+        let answer = answers[pos].clone();
+        let latest = answer.in_context
+            && !answers
+                .iter()
+                .skip(pos + 1)
+                .any(|a| a.in_context && a.same_conversation(&answer));
+
+        Some((answer, latest))
+    }
+
+    /// Whether `prompt` has been answered before, as it has when it's
+    /// answered again through [`REGENERATE`].
+    pub fn answered(&self, prompt: &EventId) -> bool {
+        self.0.lock().unwrap().iter().any(|a| a.prompt == prompt)
+    }
+
+    fn forget(&self, prompt: &OwnedEventId) {
+        self.0.lock().unwrap().retain(|a| &a.prompt != prompt);
+    }
+}
+
+/// Act on reactions to the bot's answers: [`REMOVE`] and [`REGENERATE`].
+///
+/// Only the latest exchange of a conversation can be taken out of it or
+/// answered again, as with `!llamaretry`. Older answers can still be
+/// redacted.
+pub async fn on_reaction(evt: OriginalSyncReactionEvent, rm: Room, client: Client, bot: Ctx<Bot>) {
+    if evt.sender == client.user_id().unwrap() || !bot.may_use(&evt.sender) {
+        return;
+    }
+
+    let annotation = &evt.content.relates_to;
+
+    let Some((answer, latest)) = bot.answers.find(&annotation.event_id) else {
+        return;
+    };
+
+    // Some clients send emoji with a variation selector.
+    match annotation.key.trim_end_matches('\u{fe0f}') {
+        REMOVE => remove(&evt, &rm, &bot, answer, latest).await,
+        REGENERATE if latest => regenerate(&evt, &rm, &client, &bot, answer).await,
+        REGENERATE => info!(
+            "Not answering {} again in {}, as it's no longer the latest prompt",
+            answer.prompt, answer.room_id
+        ),
+        _ => {}
+    }
+}
+
+/// Redact the messages of `answer` and, if it's the `latest` exchange,
+/// remove it from the conversation. Only whoever asked or a moderator may.
+async fn remove(
+    evt: &OriginalSyncReactionEvent,
+    rm: &Room,
+    bot: &Bot,
+    answer: Answer,
+    latest: bool,
+) {
+    if evt.sender != answer.asker && !bot.can_configure(rm, &evt.sender).await {
+        return;
+    }
+
+    let reason = format!("Removed at the request of {}", evt.sender);
+
+    for event_id in &answer.events {
+        if let Err(e) = rm.redact(event_id, Some(&reason), None).await {
+            warn!("Failed to redact {} in {}: {}", event_id, rm.room_id(), e);
+        }
+    }
+
+    if latest {
+        bot.queue
+            .send(
+                LlamaReq::ForgetLast(
+                    answer.room_id.clone(),
+                    answer.thread.clone(),
+                    answer.prompt.clone(),
+                ),
+                bot.is_admin(&evt.sender),
+            )
+            .await;
+    }
+
+    bot.answers.forget(&answer.prompt);
+}
+
+/// Answer the prompt of `answer` again, taking the reaction as
+/// `!llamaretry` from whoever reacted, in the conversation the answer is in.
+/// The new answer follows the prompt, as the first did. Only whoever asked or
+/// a moderator may, and not while the bot is shutting down.
+async fn regenerate(
+    evt: &OriginalSyncReactionEvent,
+    rm: &Room,
+    client: &Client,
+    bot: &Bot,
+    answer: Answer,
+) {
+    if bot.shutdown.is_stopping()
+        || evt.sender != answer.asker && !bot.can_configure(rm, &evt.sender).await
+    {
+        return;
+    }
+
+    let settings = match RoomSettings::load(client, rm.room_id()).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings for {}: {}", rm.room_id(), e);
+            return;
+        }
+    };
+
+    crate::retry(
+        rm,
+        bot,
+        &settings,
+        &evt.sender,
+        answer.thread,
+        &answer.prompt,
+        Options::default(),
+    )
+    .await;
+}
+
+/// React to `event_id` with `key`, returning the reaction's ID so that it can
+/// be taken back.
+pub async fn react(rm: &Room, event_id: &EventId, key: &str) -> Option<OwnedEventId> {
+    let content = ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+
+    match rm.send(content).await {
+        Ok(resp) => Some(resp.event_id),
+        Err(e) => {
+            warn!("Failed to react to {} in {}: {}", event_id, rm.room_id(), e);
+            None
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::warn;
+use matrix_sdk::{
+    Client, Room,
+    event_handler::Ctx,
+    room::Receipts,
+    ruma::{
+        EventId, OwnedEventId, OwnedRoomId,
+        api::client::receipt::create_receipt::v3::ReceiptType,
+        events::{AnySyncTimelineEvent, receipt::ReceiptThread},
+    },
+};
+use tokio::time::interval;
+
+/// The most recent timeline event seen in each room since the markers were
+/// last advanced.
+#[derive(Clone, Default)]
+pub struct ReadMarkers(Arc<Mutex<HashMap<OwnedRoomId, OwnedEventId>>>);
+
+impl ReadMarkers {
+    fn record(&self, room_id: OwnedRoomId, event_id: OwnedEventId) {
+        self.0.lock().unwrap().insert(room_id, event_id);
+    }
+
+    fn take(&self) -> HashMap<OwnedRoomId, OwnedEventId> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+pub async fn track_timeline_event(evt: AnySyncTimelineEvent, rm: Room, markers: Ctx<ReadMarkers>) {
+    markers.record(rm.room_id().into(), evt.event_id().into());
+}
+
+/// Periodically move the bot's fully-read marker (and private read receipt)
+/// up to the latest event in every room that has seen activity, so that the
+/// account's unread and notification counts don't grow without bound.
+pub async fn advance_markers_task(client: Client, markers: ReadMarkers, period: Duration) {
+    let mut ticker = interval(period);
+
+    loop {
+        ticker.tick().await;
+
+        for (room_id, event_id) in markers.take() {
+            let Some(rm) = client.get_room(&room_id) else {
+                continue;
+            };
+
+            let receipts = Receipts::new()
+                .fully_read_marker(event_id.clone())
+                .private_read_receipt(event_id);
+
+            if let Err(e) = rm.send_multiple_receipts(receipts).await {
+                warn!("Failed to advance read marker in {}: {}", room_id, e);
+            }
+        }
+    }
+}
+
+/// Send a public read receipt for a prompt the bot has taken up, so that
+/// whoever sent it can see it was, well before any answer. The markers kept
+/// by [`advance_markers_task`] are private and only catch up periodically.
+pub async fn mark_read(rm: &Room, event_id: &EventId) {
+    let receipt = rm
+        .send_single_receipt(
+            ReceiptType::Read,
+            ReceiptThread::Unthreaded,
+            event_id.to_owned(),
+        )
+        .await;
+
+    if let Err(e) = receipt {
+        warn!("Failed to send read receipt in {}: {}", rm.room_id(), e);
+    }
+}
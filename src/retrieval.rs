@@ -0,0 +1,455 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use matrix_sdk::{
+    Client, Room,
+    room::MessagesOptions,
+    ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+    time::sleep,
+};
+
+use crate::{history::text_message, html, llama::Backend, store::RoomSettings};
+
+/// How many of a room's most recent messages are embedded when it first opts
+/// in to indexing. This also bounds how far a single catch-up pass looks back.
+const BACKFILL_LIMIT: usize = 500;
+
+/// How many messages are embedded per request to the backend.
+const BATCH_SIZE: usize = 16;
+
+/// How many entries are added to a prompt's context by [`Indexer::context_for`].
+const CONTEXT_RESULTS: usize = 4;
+
+/// A piece of text and its embedding vector.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub event_id: Option<OwnedEventId>,
+    pub sender: Option<OwnedUserId>,
+    /// The name of the document the text is a passage of, if it isn't a
+    /// message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+/// The entries belonging to a single room.
+#[derive(Default)]
+struct Namespace {
+    entries: Vec<Entry>,
+    events: HashSet<OwnedEventId>,
+}
+
+/// A simple on-disk vector store, with a separate namespace per room.
+///
+/// Each namespace is an append-only JSON lines file, loaded into memory the
+/// first time the room is accessed.
+#[derive(Clone)]
+pub struct VectorStore {
+    dir: PathBuf,
+    rooms: Arc<Mutex<HashMap<OwnedRoomId, Namespace>>>,
+}
+
+impl VectorStore {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            rooms: Default::default(),
+        })
+    }
+
+    fn path(&self, room_id: &RoomId) -> PathBuf {
+        let name: String = room_id
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        self.dir.join(name).with_extension("jsonl")
+    }
+
+    fn load(&self, room_id: &RoomId) -> Result<Namespace> {
+        let mut ns = Namespace::default();
+
+        let Ok(f) = File::open(self.path(room_id)) else {
+            return Ok(ns);
+        };
+
+        for line in BufReader::new(f).lines() {
+            let entry: Entry = serde_json::from_str(&line?)?;
+
+            if let Some(event_id) = &entry.event_id {
+                ns.events.insert(event_id.clone());
+            }
+
+            ns.entries.push(entry);
+        }
+
+        Ok(ns)
+    }
+
+    async fn with_namespace<R>(
+        &self,
+        room_id: &RoomId,
+        f: impl FnOnce(&mut Namespace) -> Result<R>,
+    ) -> Result<R> {
+        let mut rooms = self.rooms.lock().await;
+
+        let ns = match rooms.get_mut(room_id) {
+            Some(ns) => ns,
+            None => rooms
+                .entry(room_id.to_owned())
+                .or_insert(self.load(room_id)?),
+        };
+
+        f(ns)
+    }
+
+    pub async fn contains(&self, room_id: &RoomId, event_id: &OwnedEventId) -> Result<bool> {
+        self.with_namespace(room_id, |ns| Ok(ns.events.contains(event_id)))
+            .await
+    }
+
+    pub async fn insert(&self, room_id: &RoomId, entries: Vec<Entry>) -> Result<()> {
+        let path = self.path(room_id);
+
+        self.with_namespace(room_id, |ns| {
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+
+            for entry in entries {
+                writeln!(f, "{}", serde_json::to_string(&entry)?)?;
+
+                if let Some(event_id) = &entry.event_id {
+                    ns.events.insert(event_id.clone());
+                }
+
+                ns.entries.push(entry);
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Find the `k` entries in a room's namespace most similar to `query`,
+    /// best match first.
+    pub async fn search(&self, room_id: &RoomId, query: &[f32], k: usize) -> Result<Vec<Entry>> {
+        self.with_namespace(room_id, |ns| {
+            let mut scored: Vec<(f32, &Entry)> = ns
+                .entries
+                .iter()
+                .map(|entry| (cosine_similarity(query, &entry.vector), entry))
+                .collect();
+
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            Ok(scored
+                .into_iter()
+                .take(k)
+                .map(|(_, entry)| entry.clone())
+                .collect())
+        })
+        .await
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// A handle to the background indexer and the vector store it maintains.
+#[derive(Clone)]
+pub struct Indexer {
+    tx: UnboundedSender<OwnedRoomId>,
+    backend: Backend,
+    model: String,
+    store: VectorStore,
+}
+
+impl Indexer {
+    /// Tell the indexer that a room has new messages.
+    pub fn touch(&self, room_id: &RoomId) {
+        let _ = self.tx.send(room_id.to_owned());
+    }
+
+    /// Return the `k` indexed messages in a room that best match `query`.
+    pub async fn search(&self, room_id: &RoomId, query: &str, k: usize) -> Result<Vec<Entry>> {
+        let vector = self
+            .backend
+            .embed(&self.model, &[query.to_owned()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("No embedding returned for query"))?;
+
+        self.store.search(room_id, &vector, k).await
+    }
+
+    /// Embed the passages of a document shared in a room, so they can be
+    /// found along with its messages.
+    pub async fn add_document(
+        &self,
+        room_id: &RoomId,
+        name: &str,
+        passages: &[String],
+    ) -> Result<()> {
+        for batch in passages.chunks(BATCH_SIZE) {
+            let vectors = self.backend.embed(&self.model, batch).await?;
+
+            let entries = batch
+                .iter()
+                .zip(vectors)
+                .map(|(text, vector)| Entry {
+                    event_id: None,
+                    sender: None,
+                    document: Some(name.to_owned()),
+                    text: text.clone(),
+                    vector,
+                })
+                .collect();
+
+            self.store.insert(room_id, entries).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The indexed messages and document passages in a room that are most
+    /// relevant to `prompt`, as context for the model to answer it with. The
+    /// message the prompt came in, `event_id`, is left out should it have
+    /// been indexed already.
+    pub async fn context_for(
+        &self,
+        room_id: &RoomId,
+        prompt: &str,
+        event_id: &EventId,
+    ) -> Result<Option<String>> {
+        let results = self.search(room_id, prompt, CONTEXT_RESULTS + 1).await?;
+
+        let excerpts: Vec<String> = results
+            .iter()
+            .filter(|entry| entry.event_id.as_deref() != Some(event_id))
+            .take(CONTEXT_RESULTS)
+            .map(|entry| match (&entry.document, &entry.sender) {
+                (Some(document), _) => format!("From the document {}: {}", document, entry.text),
+                (None, Some(sender)) => format!("{} said: {}", sender, entry.text),
+                (None, None) => entry.text.clone(),
+            })
+            .collect();
+
+        Ok(match excerpts.is_empty() {
+            true => None,
+            false => Some(format!(
+                "Excerpts from this room that may help with the next message:\n\n{}",
+                excerpts.join("\n\n")
+            )),
+        })
+    }
+}
+
+/// Render search results as quotes, linking to each original message and
+/// mentioning its sender with a pill. Returns the plain text and HTML bodies.
+pub fn format_results(room_id: &RoomId, results: &[Entry]) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::new();
+
+    for entry in results {
+        let sender = match (&entry.document, &entry.sender) {
+            (Some(document), _) => (document.clone(), html::escape(document)),
+            (None, Some(sender)) => (
+                sender.to_string(),
+                format!(
+                    "<a href=\"{}\">{}</a>",
+                    sender.matrix_to_uri(),
+                    html::escape(sender.as_str())
+                ),
+            ),
+            (None, None) => ("unknown".to_owned(), "unknown".to_owned()),
+        };
+
+        let link = match &entry.event_id {
+            Some(event_id) => format!(
+                " (<a href=\"{}\">view</a>)",
+                room_id.matrix_to_event_uri(event_id.clone())
+            ),
+            None => String::new(),
+        };
+
+        plain.push_str(&format!("> {}: {}\n\n", sender.0, entry.text));
+        html.push_str(&format!(
+            "<blockquote>{}: {}{}</blockquote>",
+            sender.1,
+            html::escape(&entry.text),
+            link
+        ));
+    }
+
+    (plain.trim_end().to_owned(), html)
+}
+
+struct IndexerTask {
+    backend: Backend,
+    model: String,
+    store: VectorStore,
+    /// How long to pause after embedding each message.
+    delay: Duration,
+}
+
+impl IndexerTask {
+    /// Embed any messages in `rm` that are newer than the newest one already
+    /// in the index.
+    ///
+    /// History is walked backwards until an indexed message is found, then
+    /// embedded oldest first. Should the bot stop part way through, the index
+    /// therefore always ends at a contiguous point in the timeline and the
+    /// next pass picks up from there.
+    async fn catch_up(&self, rm: &Room) -> Result<()> {
+        let room_id = rm.room_id();
+        let mut pending = Vec::new();
+        let mut from = None;
+
+        'paginate: while pending.len() < BACKFILL_LIMIT {
+            let mut opts = MessagesOptions::backward();
+            opts.from = from;
+            opts.limit = UInt::from(100u32);
+
+            let page = rm.messages(opts).await?;
+
+            for evt in &page.chunk {
+                // Commands to the bot aren't worth remembering.
+                let Some(msg) = text_message(evt, rm.own_user_id()).filter(|msg| !msg.is_trigger())
+                else {
+                    continue;
+                };
+
+                if self.store.contains(room_id, &msg.event_id).await? {
+                    break 'paginate;
+                }
+
+                pending.push(msg);
+            }
+
+            match page.end {
+                Some(end) if !page.chunk.is_empty() => from = Some(end),
+                _ => break,
+            }
+        }
+
+        pending.truncate(BACKFILL_LIMIT);
+        pending.reverse();
+
+        if !pending.is_empty() {
+            info!("Indexing {} new messages in {}", pending.len(), room_id);
+        }
+
+        for batch in pending.chunks(BATCH_SIZE) {
+            let texts: Vec<String> = batch.iter().map(|msg| msg.body.clone()).collect();
+            let vectors = self.backend.embed(&self.model, &texts).await?;
+
+            let entries = batch
+                .iter()
+                .zip(vectors)
+                .map(|(msg, vector)| Entry {
+                    event_id: Some(msg.event_id.clone()),
+                    sender: Some(msg.sender.clone()),
+                    document: None,
+                    text: msg.body.clone(),
+                    vector,
+                })
+                .collect();
+
+            self.store.insert(room_id, entries).await?;
+
+            sleep(self.delay * batch.len() as u32).await;
+        }
+
+        Ok(())
+    }
+
+    async fn run(self, client: Client, mut rx: UnboundedReceiver<OwnedRoomId>) {
+        // Every room gets a pass on startup to pick up whatever was missed
+        // while the bot was offline.
+        let mut dirty: HashSet<OwnedRoomId> = client
+            .joined_rooms()
+            .iter()
+            .map(|rm| rm.room_id().to_owned())
+            .collect();
+
+        loop {
+            for room_id in dirty.drain() {
+                let Some(rm) = client.get_room(&room_id) else {
+                    continue;
+                };
+
+                match RoomSettings::load(&client, &room_id).await {
+                    Ok(settings) if settings.index_history && !settings.ephemeral => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Failed to load settings for {}: {}", room_id, e);
+                        continue;
+                    }
+                }
+
+                if let Err(e) = self.catch_up(&rm).await {
+                    warn!("Failed to index history of {}: {}", room_id, e);
+                }
+            }
+
+            match rx.recv().await {
+                Some(room_id) => dirty.insert(room_id),
+                None => return,
+            };
+
+            while let Ok(room_id) = rx.try_recv() {
+                dirty.insert(room_id);
+            }
+        }
+    }
+}
+
+/// Start the background indexer, which embeds at most `rate` messages a
+/// minute from rooms that have opted in.
+pub fn spawn_indexer(
+    client: Client,
+    backend: Backend,
+    model: String,
+    store: VectorStore,
+    rate: u32,
+) -> Indexer {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let task = IndexerTask {
+        backend: backend.clone(),
+        model: model.clone(),
+        store: store.clone(),
+        delay: Duration::from_secs(60) / rate,
+    };
+
+    tokio::spawn(task.run(client, rx));
+
+    Indexer {
+        tx,
+        backend,
+        model,
+        store,
+    }
+}
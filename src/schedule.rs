@@ -0,0 +1,194 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedRoomId, events::room::message::RoomMessageEventContent},
+};
+use tokio::{sync::Mutex, time::interval};
+
+use crate::store::{Announcement, Schedule};
+
+/// How often the scheduler checks for announcements that have fallen due.
+const TICK: Duration = Duration::from_secs(30);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Parse a duration such as `90s`, `30m`, `2h` or `7d`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.len().checked_sub(1)?;
+    let (n, unit) = text.split_at(split);
+    let n: u64 = n.parse().ok()?;
+
+    let secs = match unit {
+        "s" => n,
+        "m" => n * 60,
+        "h" => n * 60 * 60,
+        "d" => n * 60 * 60 * 24,
+        _ => return None,
+    };
+
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Describe a number of seconds in the largest whole unit that fits.
+pub fn describe_secs(secs: u64) -> String {
+    match secs {
+        s if s >= 86400 && s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s >= 3600 && s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Posts announcements to rooms at the times they were scheduled for, keeping
+/// the schedule in the store so it outlives restarts.
+#[derive(Clone)]
+pub struct Scheduler {
+    client: Client,
+    schedule: Arc<Mutex<Schedule>>,
+}
+
+impl Scheduler {
+    pub async fn load(client: Client) -> Result<Self> {
+        let schedule = Schedule::load(&client).await?;
+
+        Ok(Self {
+            client,
+            schedule: Arc::new(Mutex::new(schedule)),
+        })
+    }
+
+    /// Schedule `message` to be posted to a room after `delay`, and then
+    /// every `delay` after that if `recurring`. Returns the announcement's ID.
+    pub async fn add(
+        &self,
+        room_id: OwnedRoomId,
+        message: String,
+        delay: Duration,
+        recurring: bool,
+    ) -> Result<u64> {
+        let mut schedule = self.schedule.lock().await;
+
+        let id = schedule.next_id;
+        schedule.next_id += 1;
+        schedule.announcements.push(Announcement {
+            id,
+            room_id,
+            message,
+            due: now() + delay.as_secs(),
+            every: recurring.then_some(delay.as_secs()),
+        });
+
+        schedule.save(&self.client).await?;
+
+        Ok(id)
+    }
+
+    /// Remove an announcement, returning whether it existed.
+    pub async fn remove(&self, id: u64) -> Result<bool> {
+        let mut schedule = self.schedule.lock().await;
+        let before = schedule.announcements.len();
+
+        schedule.announcements.retain(|a| a.id != id);
+
+        if schedule.announcements.len() == before {
+            return Ok(false);
+        }
+
+        schedule.save(&self.client).await?;
+
+        Ok(true)
+    }
+
+    /// Describe every scheduled announcement, one per line.
+    pub async fn describe(&self) -> Option<String> {
+        let schedule = self.schedule.lock().await;
+        let now = now();
+
+        let lines: Vec<String> = schedule
+            .announcements
+            .iter()
+            .map(|a| {
+                let when = match a.every {
+                    Some(every) => format!("every {}", describe_secs(every)),
+                    None => "once".to_owned(),
+                };
+
+                format!(
+                    "#{} in {}, {}, next in {}: {}",
+                    a.id,
+                    a.room_id,
+                    when,
+                    describe_secs(a.due.saturating_sub(now)),
+                    a.message
+                )
+            })
+            .collect();
+
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// Post every announcement that has fallen due, then reschedule the
+    /// recurring ones and drop the rest.
+    ///
+    /// A recurring announcement that fell due several times while the bot was
+    /// offline is only posted once.
+    async fn post_due(&self) -> Result<()> {
+        let mut schedule = self.schedule.lock().await;
+        let now = now();
+        let mut changed = false;
+
+        for a in schedule.announcements.iter_mut().filter(|a| a.due <= now) {
+            match self.client.get_room(&a.room_id) {
+                Some(rm) => match rm
+                    .send(RoomMessageEventContent::notice_plain(&a.message))
+                    .await
+                {
+                    Ok(_) => info!("Posted announcement #{} to {}", a.id, a.room_id),
+                    Err(e) => warn!("Failed to post announcement #{}: {}", a.id, e),
+                },
+                None => warn!("Announcement #{} is for unknown room {}", a.id, a.room_id),
+            }
+
+            if let Some(every) = a.every {
+                while a.due <= now {
+                    a.due += every;
+                }
+            }
+
+            changed = true;
+        }
+
+        if !changed {
+            return Ok(());
+        }
+
+        schedule
+            .announcements
+            .retain(|a| a.every.is_some() || a.due > now);
+
+        schedule.save(&self.client).await
+    }
+
+    pub async fn run(self) {
+        let mut ticks = interval(TICK);
+
+        loop {
+            ticks.tick().await;
+
+            if let Err(e) = self.post_due().await {
+                warn!("Failed to run scheduled announcements: {}", e);
+            }
+        }
+    }
+}
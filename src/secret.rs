@@ -0,0 +1,81 @@
+use std::{env, fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+
+/// The service secrets are filed under in the OS keyring.
+const KEYRING_SERVICE: &str = "llamatrix";
+
+/// Read a secret, such as a password, from whichever of its sources was
+/// given, if any: the command line, an environment variable, a file or an
+/// entry in the OS keyring. `what` names the secret in errors.
+///
+/// Anything but the command line keeps the secret out of `ps` and shell
+/// history. Surrounding whitespace, such as a file's final newline, is
+/// trimmed.
+pub async fn read(
+    what: &str,
+    value: Option<String>,
+    var: Option<&str>,
+    file: Option<&Path>,
+    entry: Option<&str>,
+) -> Result<Option<String>> {
+    let secret = match (value, var, file, entry) {
+        (Some(value), ..) => value,
+        (_, Some(var), ..) => {
+            env::var(var).with_context(|| format!("Could not read the {} from ${}", what, var))?
+        }
+        (_, _, Some(path), _) => fs::read_to_string(path)
+            .with_context(|| format!("Could not read the {} from {}", what, path.display()))?,
+        (.., Some(entry)) => keyring(entry)
+            .await
+            .with_context(|| format!("Could not read the {} from the keyring", what))?,
+        _ => return Ok(None),
+    };
+
+    let secret = secret.trim();
+
+    if secret.is_empty() {
+        bail!("The {} is empty", what);
+    }
+
+    Ok(Some(secret.to_owned()))
+}
+
+/// Look up `entry` in the OS keyring: the login keychain on macOS, and
+/// elsewhere the Secret Service, as GNOME Keyring and KWallet provide. Both
+/// are read through their command line tools, `security` and `secret-tool`.
+///
+/// Entries are stored under the service `llamatrix`, such as with
+/// `secret-tool store --label=llamatrix service llamatrix account <entry>`.
+async fn keyring(entry: &str) -> Result<String> {
+    let (tool, args) = match cfg!(target_os = "macos") {
+        true => (
+            "security",
+            vec![
+                "find-generic-password",
+                "-s",
+                KEYRING_SERVICE,
+                "-a",
+                entry,
+                "-w",
+            ],
+        ),
+        false => (
+            "secret-tool",
+            vec!["lookup", "service", KEYRING_SERVICE, "account", entry],
+        ),
+    };
+
+    let output = Command::new(tool)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Could not run {}, which the keyring is read with", tool))?;
+
+    if !output.status.success() {
+        bail!("There is no entry {} for {}", entry, KEYRING_SERVICE);
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
@@ -0,0 +1,393 @@
+use std::{
+    fs::{self, File, OpenOptions, Permissions},
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result, bail};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use log::{error, info};
+use matrix_sdk::{
+    Client, ServerName, SessionChange, SessionMeta,
+    encryption::{BackupDownloadStrategy, EncryptionSettings, recovery::RecoveryState},
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::UserId,
+};
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::{Deserialize, Serialize};
+
+use crate::{Args, appservice, secret, sso};
+
+/// The session file, when a session passphrase is given: a key encrypted
+/// with the passphrase, and the session encrypted with the key, both base64
+/// encoded.
+#[derive(Serialize, Deserialize)]
+struct EncryptedSession {
+    key: String,
+    session: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedSession {
+    Encrypted(EncryptedSession),
+    Plain(MatrixSession),
+}
+
+/// Where the session is saved, and the passphrase it's encrypted with, if
+/// there is one.
+#[derive(Clone)]
+struct SessionFile {
+    path: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl SessionFile {
+    async fn new(args: &Args) -> Result<Self> {
+        let passphrase = secret::read(
+            "session passphrase",
+            None,
+            args.session_passphrase_env.as_deref(),
+            args.session_passphrase_file.as_deref(),
+            args.session_passphrase_keyring.as_deref(),
+        )
+        .await?;
+
+        Ok(Self {
+            path: args.data_dir().join("session"),
+            passphrase,
+        })
+    }
+
+    fn write(&self, session: &MatrixSession) -> Result<()> {
+        let contents = match &self.passphrase {
+            Some(passphrase) => {
+                let cipher = StoreCipher::new()?;
+
+                serde_json::to_vec(&EncryptedSession {
+                    key: BASE64_STANDARD.encode(cipher.export(passphrase)?),
+                    session: BASE64_STANDARD.encode(cipher.encrypt_value(session)?),
+                })?
+            }
+            None => serde_json::to_vec(session)?,
+        };
+
+        // Only the bot's own user may read the session, with its access
+        // token. The mode is set again for a file saved before it was.
+        let write = || {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&self.path)?;
+            f.set_permissions(Permissions::from_mode(0o600))?;
+            f.write_all(&contents)
+        };
+
+        write().context("Could not write session file")
+    }
+
+    /// The saved session, if there is one. A file that can't be read, rather
+    /// than logging in again with a new device and leaving the old one
+    /// behind, is an error.
+    fn read(&self) -> Result<Option<MatrixSession>> {
+        let f = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Could not read the saved session, {}", self.path.display())
+                });
+            }
+        };
+        let saved = serde_json::from_reader(f).with_context(|| {
+            format!(
+                "The saved session, {}, is damaged. Run llamatrix clear-session and log in \
+                 again to start over",
+                self.path.display()
+            )
+        })?;
+
+        match (saved, &self.passphrase) {
+            (SavedSession::Plain(session), None) => Ok(Some(session)),
+            (SavedSession::Plain(_), Some(_)) => bail!(
+                "The saved session isn't encrypted, run llamatrix clear-session and log in \
+                 again to start using a session passphrase"
+            ),
+            (SavedSession::Encrypted(_), None) => bail!(
+                "The saved session is encrypted, give its passphrase with \
+                 --session-passphrase-keyring or one of the other --session-passphrase-* flags"
+            ),
+            (SavedSession::Encrypted(encrypted), Some(passphrase)) => {
+                let cipher =
+                    StoreCipher::import(passphrase, &BASE64_STANDARD.decode(encrypted.key)?)
+                        .context("Could not decrypt the saved session, is the passphrase right?")?;
+
+                Ok(Some(cipher.decrypt_value(
+                    &BASE64_STANDARD.decode(encrypted.session)?,
+                )?))
+            }
+        }
+    }
+}
+
+/// A client for the homeserver, without a session yet.
+async fn build(args: &Args, server: &ServerName, file: &SessionFile) -> Result<Client> {
+    let mut builder = Client::builder()
+        .server_name(server)
+        .sqlite_store(args.data_dir().join("db"), file.passphrase.as_deref())
+        .with_room_key_recipient_strategy(args.trust_mode.strategy())
+        .with_encryption_settings(EncryptionSettings {
+            // Keys missing for a message are looked for in the backup, once
+            // there's a key for it.
+            backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+            ..Default::default()
+        })
+        .handle_refresh_tokens();
+
+    if let Some(proxy) = &args.proxy {
+        info!("Reaching the homeserver through {}", proxy);
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build().await?)
+}
+
+/// A client for the homeserver, restoring the saved session or, if there
+/// isn't one, logging in with whatever the command line gives and saving the
+/// new session.
+pub async fn connect(args: &Args, server: &ServerName) -> Result<Client> {
+    connect_with(args, server, SessionFile::new(args).await?).await
+}
+
+async fn connect_with(args: &Args, server: &ServerName, file: SessionFile) -> Result<Client> {
+    let session = file.read()?;
+    let client = build(args, server, &file).await?;
+
+    match session {
+        Some(session) => client
+            .restore_session(session)
+            .await
+            .context("Failed to restore session")?,
+        None => login(&client, args, server, &file).await?,
+    }
+
+    keep_session_saved(&client, file);
+
+    Ok(client)
+}
+
+async fn login(
+    client: &Client,
+    args: &Args,
+    server: &ServerName,
+    file: &SessionFile,
+) -> Result<()> {
+    if args.sso {
+        let response = sso::login(client, args.sso_port, args.sso_idp.as_deref()).await?;
+
+        return file.write(&(&response).into());
+    }
+
+    let appservice_token = secret::read(
+        "appservice token",
+        None,
+        args.appservice_token_env.as_deref(),
+        args.appservice_token_file.as_deref(),
+        args.appservice_token_keyring.as_deref(),
+    )
+    .await?;
+
+    if let Some(as_token) = appservice_token {
+        let Some(username) = &args.username else {
+            bail!("--username is needed to log in as an application service's user");
+        };
+
+        let user_id = UserId::parse_with_server_name(username.as_str(), server)
+            .context("Could not parse user ID")?;
+        let session = appservice::login(client, args.proxy.as_ref(), &as_token, &user_id).await?;
+
+        client
+            .restore_session(session.clone())
+            .await
+            .context("Failed to restore session")?;
+
+        return file.write(&session);
+    }
+
+    let access_token = secret::read(
+        "access token",
+        None,
+        args.access_token_env.as_deref(),
+        args.access_token_file.as_deref(),
+        args.access_token_keyring.as_deref(),
+    )
+    .await?;
+
+    if let (Some(access_token), Some(device_id)) = (access_token, &args.device_id) {
+        let Some(username) = &args.username else {
+            bail!("--username is needed along with an access token");
+        };
+
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: UserId::parse_with_server_name(username.as_str(), server)
+                    .context("could not parse user ID")?,
+                device_id: device_id.clone(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token,
+                refresh_token: None,
+            },
+        };
+
+        client
+            .restore_session(session.clone())
+            .await
+            .context("Failed to use the access token")?;
+
+        return file.write(&session);
+    }
+
+    let password = secret::read(
+        "password",
+        args.password.clone(),
+        args.password_env.as_deref(),
+        args.password_file.as_deref(),
+        args.password_keyring.as_deref(),
+    )
+    .await?;
+
+    let (Some(username), Some(password)) = (&args.username, &password) else {
+        bail!("--username and --password are needed to log in, or --sso");
+    };
+
+    let userid = UserId::parse_with_server_name(username.as_str(), server)
+        .context("could not parse user ID")?;
+
+    let response = client
+        .matrix_auth()
+        .login_username(userid, password)
+        .request_refresh_token()
+        .send()
+        .await
+        .context("Failed to login")?;
+
+    file.write(&(&response).into())
+}
+
+/// Log in and save the session, for `llamatrix login`.
+pub async fn login_only(args: &Args, server: &ServerName) -> Result<()> {
+    let file = SessionFile::new(args).await?;
+
+    if let Some(session) = file.read()? {
+        bail!(
+            "Already logged in as {}, run llamatrix logout first to log in again",
+            session.meta.user_id
+        );
+    }
+
+    let client = connect_with(args, server, file).await?;
+
+    println!(
+        "Logged in as {} on device {}",
+        client.user_id().unwrap(),
+        client.device_id().unwrap()
+    );
+
+    Ok(())
+}
+
+/// Log the saved session's device out on the homeserver, then clear the
+/// session away, for `llamatrix logout`.
+pub async fn logout(args: &Args, server: &ServerName) -> Result<()> {
+    let file = SessionFile::new(args).await?;
+    let Some(session) = file.read()? else {
+        bail!("There is no saved session to log out of");
+    };
+
+    let client = build(args, server, &file).await?;
+    let user_id = session.meta.user_id.clone();
+
+    client
+        .restore_session(session)
+        .await
+        .context("Failed to restore session")?;
+    client
+        .matrix_auth()
+        .logout()
+        .await
+        .context("Failed to log out")?;
+
+    clear(args)?;
+
+    println!("Logged {} out", user_id);
+
+    Ok(())
+}
+
+/// Delete the saved session along with the local store of encryption keys
+/// and sync state, for `llamatrix clear-session`. The homeserver isn't told,
+/// so the device stays listed on the account until it's removed there.
+pub fn clear(args: &Args) -> Result<()> {
+    let session = args.data_dir().join("session");
+    let store = args.data_dir().join("db");
+
+    if session.exists() {
+        fs::remove_file(&session).context("Could not delete the session file")?;
+    }
+
+    if store.exists() {
+        fs::remove_dir_all(&store).context("Could not delete the local store")?;
+    }
+
+    println!("Cleared the saved session and local store");
+
+    Ok(())
+}
+
+/// Restore the bot's encryption secrets from secret storage with the
+/// recovery key, unless they were already. Once the key backup's key is among
+/// them, keys for messages the bot can't decrypt are fetched from the backup.
+pub async fn recover(client: &Client, recovery_key: &str) {
+    let recovery = client.encryption().recovery();
+
+    if recovery.state() == RecoveryState::Enabled {
+        return;
+    }
+
+    match recovery.recover(recovery_key).await {
+        Ok(()) => info!("Recovered the encryption secrets from secret storage"),
+        Err(e) => error!("Failed to recover from secret storage: {}", e),
+    }
+}
+
+/// Save the session again whenever its tokens are refreshed, so that the
+/// next start doesn't restore one the homeserver has already rotated away.
+fn keep_session_saved(client: &Client, file: SessionFile) {
+    let client = client.clone();
+    let mut changes = client.subscribe_to_session_changes();
+
+    tokio::spawn(async move {
+        while let Ok(change) = changes.recv().await {
+            match change {
+                SessionChange::TokensRefreshed => {
+                    let Some(session) = client.matrix_auth().session() else {
+                        continue;
+                    };
+
+                    match file.write(&session) {
+                        Ok(()) => info!("Saved the refreshed session tokens"),
+                        Err(e) => error!("Failed to save the refreshed session tokens: {}", e),
+                    }
+                }
+                SessionChange::UnknownToken { .. } => error!(
+                    "The homeserver no longer accepts the session's access token, run \
+                     llamatrix clear-session and log in again"
+                ),
+            }
+        }
+    });
+}
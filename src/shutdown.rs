@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use matrix_sdk::{Client, ruma::presence::PresenceState};
+use tokio::{signal::unix, sync::watch, task::JoinHandle, time::timeout};
+
+use crate::{cancel::Cancels, presence};
+
+/// How long replies that are still being posted are waited for, once there
+/// is nothing left to generate.
+const REPLIES_GRACE: Duration = Duration::from_secs(10);
+
+/// Whether the bot is shutting down, and what it is still waiting on before
+/// it can.
+#[derive(Clone)]
+pub struct Shutdown {
+    stopping: watch::Sender<bool>,
+    /// How many requests still have replies to post.
+    replying: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: watch::channel(false).0,
+            replying: watch::channel(0).0,
+        }
+    }
+}
+
+/// Held for as long as a request's replies are being posted, so that they
+/// are sent before the bot exits.
+pub struct Replying(watch::Sender<usize>);
+
+impl Drop for Replying {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Shutdown {
+    /// Start shutting down. New prompts are turned away from now on.
+    pub fn begin(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    /// Wait until the bot starts shutting down.
+    pub async fn stopping(&self) {
+        let _ = self
+            .stopping
+            .subscribe()
+            .wait_for(|stopping| *stopping)
+            .await;
+    }
+
+    pub fn replying(&self) -> Replying {
+        self.replying.send_modify(|n| *n += 1);
+        Replying(self.replying.clone())
+    }
+
+    /// Shut down when the process is sent SIGINT or SIGTERM.
+    pub fn on_signals(&self) -> std::io::Result<()> {
+        let mut terminate = unix::signal(unix::SignalKind::terminate())?;
+        let mut interrupt = unix::signal(unix::SignalKind::interrupt())?;
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM"),
+                _ = interrupt.recv() => info!("Received SIGINT"),
+            }
+
+            shutdown.begin();
+        });
+
+        Ok(())
+    }
+
+    /// Let the queue drain, cancelling whatever is left of it after `grace`,
+    /// wait for the last replies to be posted and go offline.
+    ///
+    /// Conversations are saved as each response finishes, so there are none
+    /// left to save here.
+    pub async fn drain(
+        &self,
+        mut llama: JoinHandle<()>,
+        cancels: &Cancels,
+        grace: Duration,
+        client: &Client,
+    ) {
+        info!("Shutting down, waiting for queued prompts to be answered");
+
+        if timeout(grace, &mut llama).await.is_err() {
+            let cancelled = cancels.cancel_all();
+            info!("Cancelled {} prompts that were still unanswered", cancelled);
+
+            let _ = llama.await;
+        }
+
+        let mut replying = self.replying.subscribe();
+
+        if timeout(REPLIES_GRACE, replying.wait_for(|n| *n == 0))
+            .await
+            .is_err()
+        {
+            warn!("Gave up waiting for replies to be posted");
+        }
+
+        presence::set(client, PresenceState::Offline, None).await;
+    }
+}
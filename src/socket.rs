@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use hyper::{
+    Uri,
+    client::conn::http1,
+    header::{HOST, HeaderValue},
+};
+use hyper_util::rt::TokioIo;
+use reqwest::{Body, Request, Response, Url, header::HeaderMap};
+use tokio::{net::UnixStream, time::timeout};
+
+/// The socket `url` names, if it is a `unix://` URL such as
+/// `unix:///run/ollama.sock` rather than the URL of an HTTP server.
+pub fn path(url: &Url) -> Option<PathBuf> {
+    (url.scheme() == "unix").then(|| PathBuf::from(url.path()))
+}
+
+/// A server on a unix socket that couldn't be connected to, or didn't answer
+/// in time.
+#[derive(Debug)]
+pub struct Unreachable {
+    path: PathBuf,
+    reason: String,
+}
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Could not reach {}: {}",
+            self.path.display(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// Send `request` to the HTTP server listening on the unix socket at `path`,
+/// along with any of `headers` it doesn't set itself, as reqwest can't
+/// connect to one. Each request has a connection of its own, as there's
+/// little to save by reusing them with a local socket.
+pub async fn send(path: &Path, request: Request, headers: &HeaderMap) -> Result<Response> {
+    let limit = request.timeout().copied();
+    let mut request = hyper::Request::<Body>::try_from(request)?;
+
+    // The socket's path stands in for the host, so the request is only for
+    // the path on it.
+    let target: Uri = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |target| target.as_str())
+        .parse()?;
+    *request.uri_mut() = target;
+    request
+        .headers_mut()
+        .insert(HOST, HeaderValue::from_static("localhost"));
+
+    for (name, value) in headers {
+        if !request.headers().contains_key(name) {
+            request.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    let exchange = async {
+        let stream = UnixStream::connect(path).await?;
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+
+        // Drives the connection for as long as the response is being read.
+        tokio::spawn(connection);
+
+        anyhow::Ok(sender.send_request(request).await?)
+    };
+
+    let result = match limit {
+        Some(limit) => timeout(limit, exchange)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out"))),
+        None => exchange.await,
+    };
+
+    let response = result.map_err(|e| Unreachable {
+        path: path.to_owned(),
+        reason: e.to_string(),
+    })?;
+
+    Ok(Response::from(response.map(Body::wrap)))
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::{Router, extract::RawQuery, routing::get};
+use log::info;
+use matrix_sdk::{Client, ruma::api::client::session::login};
+use reqwest::Url;
+use tokio::{net::TcpListener, sync::oneshot};
+
+/// Log in with the homeserver's single sign-on, for homeservers that don't
+/// take passwords.
+///
+/// The URL to log in at is printed for the user to open in a browser. Once
+/// they have, the homeserver sends the browser back to a listener on
+/// `port`, or any free port, with the token to log in with.
+pub async fn login(
+    client: &Client,
+    port: Option<u16>,
+    idp: Option<&str>,
+) -> Result<login::v3::Response> {
+    let listener = TcpListener::bind(("127.0.0.1", port.unwrap_or(0)))
+        .await
+        .context("Could not listen for the SSO redirect")?;
+    let redirect = format!("http://localhost:{}/", listener.local_addr()?.port());
+
+    let auth = client.matrix_auth();
+    let url = auth
+        .get_sso_login_url(&redirect, idp)
+        .await
+        .context("Failed to get the SSO login URL")?;
+
+    println!("Open this URL in a browser to log in:\n\n{}\n", url);
+
+    let (tx, rx) = oneshot::channel();
+    let tx = Arc::new(Mutex::new(Some(tx)));
+
+    let app = Router::new().route(
+        "/",
+        get(|RawQuery(query): RawQuery| async move {
+            let Some(token) = query.as_deref().and_then(login_token) else {
+                return "The homeserver didn't give a login token, try logging in again";
+            };
+
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(token);
+            }
+
+            "Logged in, you can close this page"
+        }),
+    );
+
+    // Shut down gracefully, so that the browser is still told it's done.
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let token = rx.await.context("Stopped listening for the SSO redirect")?;
+
+    let _ = stop.send(());
+    let _ = server.await;
+
+    info!("Received a login token, logging in");
+
+    auth.login_token(&token)
+        .request_refresh_token()
+        .send()
+        .await
+        .context("Failed to login with SSO")
+}
+
+/// The `loginToken` in the query string of the redirect.
+fn login_token(query: &str) -> Option<String> {
+    let mut url = Url::parse("http://localhost/").unwrap();
+    url.set_query(Some(query));
+
+    url.query_pairs()
+        .find(|(name, _)| name == "loginToken")
+        .map(|(_, token)| token.into_owned())
+}
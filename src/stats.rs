@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+
+use crate::llama::Usage;
+
+/// How much weight each new response carries in the rolling averages.
+const SMOOTHING: f64 = 0.2;
+
+/// A rolling average, weighted towards recent samples.
+#[derive(Default, Clone, Copy)]
+struct Average(Option<f64>);
+
+impl Average {
+    fn add(&mut self, sample: f64) {
+        self.0 = Some(match self.0 {
+            Some(avg) => avg + SMOOTHING * (sample - avg),
+            None => sample,
+        });
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct ModelStats {
+    /// Response tokens generated per second.
+    rate: Average,
+    /// Tokens in a response.
+    length: Average,
+    /// Seconds from a request being started to it being answered.
+    duration: Average,
+}
+
+/// Rolling throughput statistics for each model, used to estimate how long
+/// answers will take, and what the last answer in each room took.
+#[derive(Clone, Default)]
+pub struct Throughput {
+    models: Arc<Mutex<HashMap<String, ModelStats>>>,
+    last: Arc<Mutex<HashMap<OwnedRoomId, Usage>>>,
+}
+
+/// The entry covering every model.
+const ALL: &str = "";
+
+impl Throughput {
+    pub fn record(&self, room_id: &RoomId, model: &str, usage: &Usage) {
+        let Some(rate) = usage.rate() else {
+            return;
+        };
+
+        self.last.lock().unwrap().insert(room_id.to_owned(), *usage);
+
+        let duration = usage.total_duration as f64 / 1e9;
+        let mut models = self.models.lock().unwrap();
+
+        for model in [model, ALL] {
+            let stats = models.entry(model.to_owned()).or_default();
+
+            stats.rate.add(rate);
+            stats.length.add(usage.eval_count as f64);
+            stats.duration.add(duration);
+        }
+    }
+
+    /// How much longer a response from `model` is likely to take, having
+    /// generated `tokens` so far.
+    pub fn remaining(&self, model: &str, tokens: u64) -> Option<Duration> {
+        let stats = *self.models.lock().unwrap().get(model)?;
+        let remaining = (stats.length.0? - tokens as f64).max(0.0);
+
+        Some(Duration::from_secs_f64(remaining / stats.rate.0?))
+    }
+
+    /// How long a whole response usually takes, across all models.
+    pub fn typical(&self) -> Option<Duration> {
+        let stats = *self.models.lock().unwrap().get(ALL)?;
+
+        Some(Duration::from_secs_f64(stats.duration.0?))
+    }
+
+    /// What the last answer generated for a room used, since the bot started.
+    pub fn last(&self, room_id: &RoomId) -> Option<Usage> {
+        self.last.lock().unwrap().get(room_id).copied()
+    }
+}
+
+/// Describe the tokens a response used and how quickly it was generated.
+pub fn describe_usage(usage: &Usage) -> String {
+    format!(
+        "{} prompt + {} response tokens, {:.1} tokens/s",
+        usage.prompt_eval_count,
+        usage.eval_count,
+        usage.rate().unwrap_or_default()
+    )
+}
+
+/// Describe a duration roughly, to the nearest sensible unit.
+pub fn describe(duration: Duration) -> String {
+    match duration.as_secs() {
+        0..=59 => format!("{}s", duration.as_secs().max(1)),
+        secs => format!("{}m", secs.div_ceil(60)),
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use log::warn;
+use matrix_sdk::{
+    Client, Room,
+    ruma::{OwnedEventId, RoomId},
+};
+
+use crate::{
+    Bot, contexts, llama,
+    store::{SavedSlot, SavedSlots},
+};
+
+fn uptime(elapsed: Duration) -> String {
+    let mins = elapsed.as_secs() / 60;
+
+    match (mins / 1440, mins / 60 % 24, mins % 60) {
+        (0, 0, mins) => format!("{}m", mins),
+        (0, hours, mins) => format!("{}h {}m", hours, mins),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+/// The conversation that a prompt would continue, with its own settings.
+pub async fn current_slot(
+    client: &Client,
+    room_id: &RoomId,
+    thread: Option<OwnedEventId>,
+) -> SavedSlot {
+    let mut slots = SavedSlots::load(client, room_id).await.unwrap_or_else(|e| {
+        warn!("Failed to load conversations of {}: {}", room_id, e);
+        SavedSlots::default()
+    });
+
+    let slot = match thread {
+        Some(root) => slots
+            .threads
+            .into_iter()
+            .find(|t| t.root == root)
+            .map(|t| t.slot),
+        None => slots
+            .slots
+            .remove(slots.current.as_deref().unwrap_or(contexts::MAIN)),
+    };
+
+    slot.unwrap_or_default()
+}
+
+/// Describe the state of the bot, as seen from `rm`, for working out why it
+/// isn't answering.
+pub async fn status(rm: &Room, thread: Option<OwnedEventId>, client: &Client, bot: &Bot) -> String {
+    let defaults = bot.defaults.read().unwrap().clone();
+
+    let url = llama::redact(defaults.backend.url());
+    let server = match defaults.backend.version().await {
+        Ok(version) => format!("{} (ollama {})", url, version),
+        Err(e) => format!("{} (unreachable: {})", url, llama::redact_error(&e)),
+    };
+
+    let mut lines = vec![
+        format!("Model: {}", defaults.model),
+        format!("Server: {}", server),
+        format!(
+            "Messages in this conversation: {}",
+            current_slot(client, rm.room_id(), thread)
+                .await
+                .history
+                .len()
+        ),
+        format!("Requests waiting: {}", bot.queue.waiting()),
+        format!("Up for: {}", uptime(bot.started.elapsed())),
+    ];
+
+    if let Some((down_for, e)) = bot.health.ollama_down() {
+        lines.push(format!(
+            "Degraded: ollama has been unreachable for {} ({})",
+            uptime(down_for),
+            e
+        ));
+    }
+
+    if let Some(message) = bot.maintenance.message() {
+        lines.push(format!("In maintenance mode: {}", message));
+    }
+
+    lines.join("\n")
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::llama::{Message, Options};
+
+/// Read a value previously written with [`set`], falling back to the type's
+/// default if nothing has been stored under `key` yet.
+///
+/// Values live in the SDK's state store, so they share the bot's database and
+/// survive restarts along with the rest of its state.
+async fn get<T: DeserializeOwned + Default>(client: &Client, key: &str) -> Result<T> {
+    match client.store().get_custom_value(key.as_bytes()).await? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(T::default()),
+    }
+}
+
+async fn set<T: Serialize>(client: &Client, key: &str, value: &T) -> Result<()> {
+    client
+        .store()
+        .set_custom_value_no_read(key.as_bytes(), serde_json::to_vec(value)?)
+        .await?;
+
+    Ok(())
+}
+
+/// Which messages in a group room the bot answers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// Only messages starting with `!llama`.
+    #[default]
+    Prefix,
+    /// Every message.
+    All,
+}
+
+/// Settings that room moderators can change for their own room.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// Whether the room's messages are embedded into the vector store.
+    pub index_history: bool,
+    /// The model to answer with, in place of the bot's default.
+    pub model: Option<String>,
+    /// A system prompt giving the bot a persona in this room.
+    pub persona: Option<String>,
+    /// One of the configured personas, taken on in place of `persona`.
+    pub named_persona: Option<String>,
+    pub trigger: Trigger,
+    /// How many prompts each user may send per hour.
+    pub quota: Option<u32>,
+    /// The room's own names for commands, mapped to their canonical names.
+    pub aliases: HashMap<String, String>,
+    /// How many tokens the room may use per day, set by a bot admin.
+    pub token_budget: Option<u64>,
+    /// The seed answers are generated with, for reproducible answers.
+    pub seed: Option<u64>,
+    /// Keep nothing from the room's conversation: each exchange is answered
+    /// afresh and never written to the store.
+    pub ephemeral: bool,
+    /// Summarise the oldest exchanges once they no longer fit in the context
+    /// window, rather than forgetting them.
+    pub condense: bool,
+    /// Show the tokens each answer used at the bottom of it.
+    pub usage_footer: bool,
+    /// Sampling options set with `!llamaset`, which take precedence over the
+    /// bot's own.
+    pub options: Options,
+}
+
+impl RoomSettings {
+    fn key(room_id: &RoomId) -> String {
+        format!("llamatrix.room.{}", room_id)
+    }
+
+    pub async fn load(client: &Client, room_id: &RoomId) -> Result<Self> {
+        get(client, &Self::key(room_id)).await
+    }
+
+    pub async fn save(&self, client: &Client, room_id: &RoomId) -> Result<()> {
+        set(client, &Self::key(room_id), self).await
+    }
+}
+
+/// How long a user would like answers to be.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    Brief,
+    Normal,
+    Detailed,
+}
+
+/// What a user has told the bot about themselves, applied wherever they talk
+/// to it.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct UserProfile {
+    /// What the user would like to be called.
+    pub name: Option<String>,
+    /// The language the user would like answers in.
+    pub language: Option<String>,
+    /// Instructions to follow whenever answering this user.
+    pub instructions: Option<String>,
+    /// How long the user would like answers to be.
+    pub verbosity: Option<Verbosity>,
+    /// The model the user would like answers from, if the operator allows it.
+    pub model: Option<String>,
+}
+
+impl UserProfile {
+    fn key(user_id: &UserId) -> String {
+        format!("llamatrix.user.{}", user_id)
+    }
+
+    pub async fn load(client: &Client, user_id: &UserId) -> Result<Self> {
+        get(client, &Self::key(user_id)).await
+    }
+
+    pub async fn save(&self, client: &Client, user_id: &UserId) -> Result<()> {
+        set(client, &Self::key(user_id), self).await
+    }
+
+    /// Describe the profile for the model, or `None` if it is empty.
+    pub fn describe(&self) -> Option<String> {
+        let mut notes = Vec::new();
+
+        if let Some(name) = &self.name {
+            notes.push(format!("They would like to be called {}.", name));
+        }
+
+        if let Some(language) = &self.language {
+            notes.push(format!("Reply to them in {}.", language));
+        }
+
+        match self.verbosity {
+            Some(Verbosity::Brief) => notes.push("Keep answers to them short.".to_owned()),
+            Some(Verbosity::Detailed) => notes.push("Give them thorough answers.".to_owned()),
+            Some(Verbosity::Normal) | None => {}
+        }
+
+        if let Some(instructions) = &self.instructions {
+            notes.push(format!("Their standing instructions are: {}", instructions));
+        }
+
+        (!notes.is_empty()).then(|| notes.join(" "))
+    }
+}
+
+/// What a room, or with no room the whole bot, has used on a single day.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct DailyUsage {
+    /// Days since the Unix epoch, in UTC.
+    pub day: u64,
+    pub tokens: u64,
+    /// Time spent generating, in seconds.
+    pub generation_secs: f64,
+}
+
+impl DailyUsage {
+    fn key(room_id: Option<&RoomId>) -> String {
+        match room_id {
+            Some(room_id) => format!("llamatrix.usage.{}", room_id),
+            None => "llamatrix.usage".to_owned(),
+        }
+    }
+
+    pub async fn load(client: &Client, room_id: Option<&RoomId>) -> Result<Self> {
+        get(client, &Self::key(room_id)).await
+    }
+
+    pub async fn save(&self, client: &Client, room_id: Option<&RoomId>) -> Result<()> {
+        set(client, &Self::key(room_id), self).await
+    }
+}
+
+/// A named conversation in a room, with its own settings.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct SavedSlot {
+    pub history: Vec<Message>,
+    /// Overrides the room's persona.
+    pub system_prompt: Option<String>,
+    /// Overrides the room's model.
+    pub model: Option<String>,
+    /// The event of the prompt the last exchange in `history` answers, if
+    /// it came from one.
+    pub last_prompt: Option<OwnedEventId>,
+}
+
+/// The conversation in a thread, which is kept apart from the room's slots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThreadSlot {
+    pub root: OwnedEventId,
+    #[serde(flatten)]
+    pub slot: SavedSlot,
+}
+
+/// Every conversation the bot is having in a room.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct SavedSlots {
+    /// The slot in use, or `None` for the main one.
+    pub current: Option<String>,
+    pub slots: HashMap<String, SavedSlot>,
+    /// Conversations in threads, least recently used first.
+    pub threads: Vec<ThreadSlot>,
+}
+
+impl SavedSlots {
+    fn key(room_id: &RoomId) -> String {
+        format!("llamatrix.slots.{}", room_id)
+    }
+
+    pub async fn load(client: &Client, room_id: &RoomId) -> Result<Self> {
+        get(client, &Self::key(room_id)).await
+    }
+
+    pub async fn save(&self, client: &Client, room_id: &RoomId) -> Result<()> {
+        set(client, &Self::key(room_id), self).await
+    }
+}
+
+/// An announcement waiting to be posted by the scheduler.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    pub id: u64,
+    pub room_id: OwnedRoomId,
+    pub message: String,
+    /// When the announcement is next due, in seconds since the Unix epoch.
+    pub due: u64,
+    /// For recurring announcements, the number of seconds between postings.
+    pub every: Option<u64>,
+}
+
+/// Every scheduled announcement.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Schedule {
+    pub next_id: u64,
+    pub announcements: Vec<Announcement>,
+}
+
+impl Schedule {
+    const KEY: &str = "llamatrix.schedule";
+
+    pub async fn load(client: &Client) -> Result<Self> {
+        get(client, Self::KEY).await
+    }
+
+    pub async fn save(&self, client: &Client) -> Result<()> {
+        set(client, Self::KEY, self).await
+    }
+}
+
+/// Users banned from the bot by an admin.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct BannedUsers {
+    pub users: HashSet<OwnedUserId>,
+}
+
+impl BannedUsers {
+    const KEY: &str = "llamatrix.bans";
+
+    pub async fn load(client: &Client) -> Result<Self> {
+        get(client, Self::KEY).await
+    }
+
+    pub async fn save(&self, client: &Client) -> Result<()> {
+        set(client, Self::KEY, self).await
+    }
+}
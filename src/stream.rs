@@ -0,0 +1,80 @@
+use clap::ValueEnum;
+
+/// How a response is delivered to the room while it is being generated.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum StreamMode {
+    /// Wait for the complete response and send it as a single message.
+    Off,
+    /// Send each paragraph as its own message as soon as it is complete.
+    Paragraph,
+    /// Send a single message straight away and keep editing it as the
+    /// response grows.
+    Edit,
+}
+
+/// A response, or part of one, on its way to the room.
+pub enum Reply {
+    /// A message of its own.
+    Post(String),
+    /// The response so far, replacing the previous update of the same
+    /// message. The first update is posted as a new message.
+    Update(String),
+    /// A notice about the request rather than part of the response, such as
+    /// that it failed.
+    Notice(String),
+}
+
+impl Reply {
+    pub fn into_text(self) -> String {
+        match self {
+            Reply::Post(text) | Reply::Update(text) | Reply::Notice(text) => text,
+        }
+    }
+}
+
+/// Accumulates streamed fragments and splits them into paragraphs.
+///
+/// A paragraph ends at a blank line, but never inside a fenced code block, so
+/// code is always delivered in one piece.
+#[derive(Default)]
+pub struct Paragraphs {
+    buf: String,
+}
+
+fn in_code_block(text: &str) -> bool {
+    text.matches("```").count() % 2 == 1
+}
+
+impl Paragraphs {
+    /// Append a fragment, returning any paragraphs that it completed.
+    pub fn push(&mut self, fragment: &str) -> Vec<String> {
+        self.buf.push_str(fragment);
+
+        let mut done = Vec::new();
+        let mut start = 0;
+
+        while let Some(pos) = self.buf[start..].find("\n\n").map(|p| p + start) {
+            if !in_code_block(&self.buf[..pos]) {
+                let para = self.buf[..pos].trim();
+
+                if !para.is_empty() {
+                    done.push(para.to_owned());
+                }
+
+                self.buf.drain(..pos + 2);
+                start = 0;
+            } else {
+                start = pos + 2;
+            }
+        }
+
+        done
+    }
+
+    /// Return whatever is left once the stream has finished.
+    pub fn finish(self) -> Option<String> {
+        let rest = self.buf.trim();
+
+        (!rest.is_empty()).then(|| rest.to_owned())
+    }
+}
@@ -0,0 +1,67 @@
+use std::ffi::OsString;
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use log::{error, info};
+use tokio::task::JoinSet;
+
+use crate::Args;
+
+/// The flags of the account `name` from its command line, with its state
+/// kept in a subdirectory of the data directory named after it.
+pub fn account_args(name: &str, command_line: &[OsString]) -> Result<Args> {
+    let mut args = Args::try_parse_from(command_line)
+        .with_context(|| format!("Invalid settings for account {}", name))?;
+
+    args.data_dir = Some(args.data_dir().join(name));
+
+    Ok(args)
+}
+
+/// Run the bot as each of `accounts` at once, each with its own sync loop,
+/// queue and data directory, until they have all stopped.
+///
+/// An account that fails, such as to log in, is logged and leaves the others
+/// running. It isn't restarted, as tasks it started may still be running.
+pub async fn run(accounts: Vec<(String, Vec<OsString>)>) -> Result<()> {
+    let mut bots = JoinSet::new();
+
+    // All are parsed before any is started, so that a mistake in the config
+    // file doesn't leave the accounts before it running on their own.
+    let accounts = accounts
+        .into_iter()
+        .map(|(name, command_line)| {
+            let args = account_args(&name, &command_line)?;
+
+            Ok((name, args))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (name, args) in accounts {
+        info!("Starting account {}", name);
+
+        bots.spawn(async move { (name, crate::run(args).await) });
+    }
+
+    let mut failed = 0;
+
+    while let Some(stopped) = bots.join_next().await {
+        match stopped {
+            Ok((name, Ok(()))) => info!("Account {} has stopped", name),
+            Ok((name, Err(e))) => {
+                error!("Account {} stopped: {:#}", name, e);
+                failed += 1;
+            }
+            Err(e) => {
+                error!("An account's task failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} accounts stopped with an error", failed);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use serde_json::{Value, json};
+
+/// Something the model can call on while answering, such as a search.
+pub trait Tool: Send + Sync {
+    /// The name the model calls the tool by.
+    fn name(&self) -> &str;
+
+    /// What the tool does, for the model to decide when to use it.
+    fn description(&self) -> &str;
+
+    /// A JSON schema for the tool's arguments.
+    fn parameters(&self) -> Value;
+
+    /// Run the tool, returning what the model should be told.
+    fn call(&self, args: Value) -> BoxFuture<'_, Result<ToolOutput>>;
+}
+
+/// What a tool returned.
+pub struct ToolOutput {
+    /// What the model is told.
+    pub content: String,
+    /// Where the content came from, to be cited alongside the answer.
+    pub sources: Vec<Source>,
+}
+
+impl From<String> for ToolOutput {
+    fn from(content: String) -> Self {
+        Self {
+            content,
+            sources: Vec::new(),
+        }
+    }
+}
+
+/// A web page that a tool drew on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Source {
+    pub title: String,
+    pub url: String,
+}
+
+/// List `sources` as Markdown links, to go at the bottom of an answer.
+/// Titles and URLs come from whoever runs the page, so neither is trusted to
+/// be free of Markdown, and only web links are linked to.
+pub fn cite(sources: &[Source]) -> String {
+    let links: Vec<String> = sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| {
+            let title = escape_markdown(&source.title);
+
+            match reqwest::Url::parse(&source.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {
+                    let url = url.as_str().replace('(', "%28").replace(')', "%29");
+                    format!("{}. [{}]({})", i + 1, title, url)
+                }
+                _ => format!("{}. {}", i + 1, title),
+            }
+        })
+        .collect();
+
+    format!("Sources:\n\n{}", links.join("\n"))
+}
+
+/// `text` with the characters Markdown would treat as markup escaped.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '(' | ')' | '<' | '>' | '#' | '!' | '|' | '~'
+        ) {
+            escaped.push('\\');
+        }
+
+        // A title is one line of a list, and a newline would end it.
+        escaped.push(if c == '\n' { ' ' } else { c });
+    }
+
+    escaped
+}
+
+/// The tools registered with the bot, which are offered to the model with
+/// every prompt.
+#[derive(Clone, Default)]
+pub struct Tools(Vec<Arc<dyn Tool>>);
+
+impl Tools {
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        if self.0.iter().any(|t| t.name() == tool.name()) {
+            warn!(
+                "There is already a tool called {}, skipping another",
+                tool.name()
+            );
+            return;
+        }
+
+        self.0.push(Arc::new(tool));
+    }
+
+    /// The tools as declared to ollama in a chat request's `tools` field.
+    pub fn declarations(&self) -> Vec<Value> {
+        self.0
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name(),
+                        "description": tool.description(),
+                        "parameters": tool.parameters(),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Call the tool `name` on the model's behalf. Failures are reported to
+    /// the model rather than ending the response, so that it can carry on
+    /// without the tool.
+    pub async fn call(&self, name: &str, args: Value) -> ToolOutput {
+        let Some(tool) = self.0.iter().find(|tool| tool.name() == name) else {
+            warn!("The model called an unknown tool {}", name);
+            return format!("There is no tool called {}", name).into();
+        };
+
+        info!("Calling tool {} with {}", name, args);
+
+        match tool.call(args).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Tool {} failed: {}", name, e);
+                format!("The tool failed: {}", e).into()
+            }
+        }
+    }
+}
+
+/// Tells the model the current date and time, which it can't otherwise know.
+pub struct Clock;
+
+impl Tool for Clock {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current date and time in UTC"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call(&self, _args: Value) -> BoxFuture<'_, Result<ToolOutput>> {
+        Box::pin(async {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+            Ok(format_utc(secs).into())
+        })
+    }
+}
+
+/// Format seconds since the Unix epoch as an ISO 8601 UTC date and time.
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Howard Hinnant's days_from_civil, in reverse.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(title: &str, url: &str) -> Source {
+        Source {
+            title: title.to_owned(),
+            url: url.to_owned(),
+        }
+    }
+
+    #[test]
+    fn cites_sources_as_links() {
+        let cited = cite(&[source("Rust", "https://www.rust-lang.org/")]);
+
+        assert_eq!(cited, "Sources:\n\n1. [Rust](https://www.rust-lang.org/)");
+    }
+
+    #[test]
+    fn escapes_markdown_in_titles() {
+        let cited = cite(&[source(
+            "[click](https://evil.example) *now*",
+            "https://example.org/",
+        )]);
+
+        assert!(
+            cited.contains(r"1. [\[click\]\(https://evil.example\) \*now\*](https://example.org/)")
+        );
+    }
+
+    #[test]
+    fn keeps_urls_inside_the_link() {
+        let cited = cite(&[source(
+            "Page",
+            "https://example.org/a) [x](https://evil.example",
+        )]);
+
+        assert!(!cited.contains(")("));
+        assert!(cited.contains("https://example.org/a%29%20[x]%28https://evil.example"));
+    }
+
+    #[test]
+    fn only_links_to_the_web() {
+        let cited = cite(&[source("Page", "javascript:alert(1)")]);
+
+        assert_eq!(cited, "Sources:\n\n1. Page");
+    }
+}
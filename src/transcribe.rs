@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{
+    Client, Url,
+    multipart::{Form, Part},
+};
+use serde::Deserialize;
+
+/// How long a recording may take to transcribe before giving up on it.
+const TRANSCRIBE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A speech-to-text server speaking OpenAI's `/v1/audio/transcriptions` API,
+/// such as whisper.cpp's server or faster-whisper-server.
+#[derive(Clone)]
+pub struct Transcriber {
+    client: Client,
+    url: Url,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+impl Transcriber {
+    pub fn new(url: Url, model: String) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            model,
+        }
+    }
+
+    /// The words spoken in `audio`, a recording named `filename`.
+    pub async fn transcribe(&self, audio: Vec<u8>, filename: &str) -> Result<String> {
+        let form = Form::new()
+            .text("model", self.model.clone())
+            .part("file", Part::bytes(audio).file_name(filename.to_owned()));
+
+        let resp = self
+            .client
+            .post(self.url.join("/v1/audio/transcriptions").unwrap())
+            .multipart(form)
+            .timeout(TRANSCRIBE_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TranscriptionResponse>()
+            .await?;
+
+        Ok(resp.text.trim().to_owned())
+    }
+}
+
+/// Quote `transcript` as Markdown, to show what the bot heard.
+pub fn quote(transcript: &str) -> String {
+    transcript
+        .lines()
+        .map(|line| format!("> {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
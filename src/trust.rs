@@ -0,0 +1,59 @@
+use clap::ValueEnum;
+use futures_util::StreamExt;
+use log::{info, warn};
+use matrix_sdk::{Client, crypto::CollectStrategy};
+
+/// Which devices the bot shares its room keys with, and so which devices can
+/// read what it sends to encrypted rooms.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TrustMode {
+    /// Trust every device the first time it is seen.
+    Tofu,
+    /// Only trust devices that have been verified, either directly or through
+    /// their owner's cross-signing identity.
+    Verified,
+}
+
+impl TrustMode {
+    pub fn strategy(self) -> CollectStrategy {
+        CollectStrategy::DeviceBasedStrategy {
+            only_allow_trusted_devices: self == TrustMode::Verified,
+            error_on_verified_user_problem: false,
+        }
+    }
+}
+
+/// Log every unverified device as it is discovered, so it's clear which ones
+/// the bot is trusting implicitly, or with `verified`, leaving out.
+pub async fn log_new_devices(client: Client, mode: TrustMode) {
+    let mut updates = match client.encryption().devices_stream().await {
+        Ok(updates) => updates,
+        Err(e) => {
+            warn!("Failed to watch for new devices: {}", e);
+            return;
+        }
+    };
+
+    while let Some(update) = updates.next().await {
+        for (user_id, devices) in update.new {
+            for (device_id, device) in devices {
+                if device.is_verified() {
+                    continue;
+                }
+
+                let name = device.display_name().unwrap_or("unnamed");
+
+                match mode {
+                    TrustMode::Tofu => info!(
+                        "Trusting unverified device {} ({}) of {} on first use",
+                        device_id, name, user_id
+                    ),
+                    TrustMode::Verified => info!(
+                        "Not sharing keys with unverified device {} ({}) of {}",
+                        device_id, name, user_id
+                    ),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use log::warn;
+use matrix_sdk::{
+    Room,
+    ruma::api::client::typing::create_typing_event::{self, v3::Typing},
+};
+use tokio::{task::JoinHandle, time::interval};
+
+/// How long each typing notice asks the homeserver to display us as typing.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the typing notice is renewed, comfortably inside
+/// [`TYPING_TIMEOUT`] so the indicator never lapses mid-generation.
+const TYPING_REFRESH: Duration = Duration::from_secs(25);
+
+/// Shows the bot as typing in a room for as long as this value is alive.
+///
+/// The notice is started when the guard is created and withdrawn when it is
+/// dropped, so whichever way a request leaves the generation pipeline
+/// (answered, failed, cancelled or discarded from the queue) the indicator is
+/// cleared.
+pub struct TypingNotice {
+    room: Room,
+    refresh: JoinHandle<()>,
+}
+
+async fn send_typing(room: &Room, typing: Typing) {
+    let request = create_typing_event::v3::Request::new(
+        room.own_user_id().to_owned(),
+        room.room_id().to_owned(),
+        typing,
+    );
+
+    if let Err(e) = room.client().send(request, None).await {
+        warn!("Failed to send typing notice to {}: {}", room.room_id(), e);
+    }
+}
+
+impl TypingNotice {
+    pub fn start(room: Room) -> Self {
+        let refresh = tokio::spawn({
+            let room = room.clone();
+            async move {
+                let mut ticker = interval(TYPING_REFRESH);
+
+                loop {
+                    ticker.tick().await;
+                    send_typing(&room, Typing::Yes(TYPING_TIMEOUT)).await;
+                }
+            }
+        });
+
+        Self { room, refresh }
+    }
+}
+
+impl Drop for TypingNotice {
+    fn drop(&mut self) {
+        self.refresh.abort();
+
+        let room = self.room.clone();
+        tokio::spawn(async move { send_typing(&room, Typing::No).await });
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use futures_util::StreamExt;
+use log::{info, warn};
+use matrix_sdk::{
+    Client,
+    config::SyncSettings,
+    encryption::verification::{
+        SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState,
+        format_emojis,
+    },
+    event_handler::Ctx,
+    ruma::{
+        OwnedRoomId, OwnedUserId, UserId,
+        events::{
+            key::verification::{VerificationMethod, request::ToDeviceKeyVerificationRequestEvent},
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+    },
+};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, BufReader},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{config::Live, dm};
+
+/// How long to wait for an admin to compare a verification's emoji before
+/// taking them not to match.
+const COMPARE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// What to do with incoming device verification requests.
+///
+/// Accepted requests go on to compare emoji. An admin's own are sent to them
+/// in a direct chat to check against their client; the emoji of anyone
+/// else's are posted to the admin room for an admin to compare.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum VerificationPolicy {
+    /// Reject every request.
+    Reject,
+    /// Accept requests from admins and reject everyone else's.
+    Admins,
+    /// Accept requests from admins and ask in the admin room about everyone
+    /// else's.
+    Prompt,
+}
+
+/// Applies the [`VerificationPolicy`] to verification requests as they
+/// arrive, keeping track of those waiting on an admin's decision.
+#[derive(Clone)]
+pub struct Verifier {
+    policy: VerificationPolicy,
+    config: Live,
+    admin_room: Option<OwnedRoomId>,
+    /// Whether direct chats started to compare an admin's emoji are encrypted.
+    encrypt_dms: bool,
+    /// Requests awaiting a decision, by flow ID.
+    pending: Arc<Mutex<HashMap<String, OwnedUserId>>>,
+    /// Verifications whose emoji are waiting to be compared by an admin, by
+    /// flow ID.
+    comparing: Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>,
+}
+
+impl Verifier {
+    pub fn new(
+        policy: VerificationPolicy,
+        config: Live,
+        admin_room: Option<OwnedRoomId>,
+        encrypt_dms: bool,
+    ) -> Self {
+        Self {
+            policy,
+            config,
+            admin_room,
+            encrypt_dms,
+            pending: Default::default(),
+            comparing: Default::default(),
+        }
+    }
+
+    async fn accept(&self, client: &Client, sender: &UserId, flow_id: &str, accept: bool) {
+        let Some(request) = client
+            .encryption()
+            .get_verification_request(sender, flow_id)
+            .await
+        else {
+            warn!("Verification request {} from {} has gone", flow_id, sender);
+            return;
+        };
+
+        let result = match accept {
+            true => {
+                request
+                    .accept_with_methods(vec![VerificationMethod::SasV1])
+                    .await
+            }
+            false => request.cancel().await,
+        };
+        let accepted = accept && result.is_ok();
+
+        match result {
+            Ok(()) if accept => info!("Accepted verification request from {}", sender),
+            Ok(()) => info!("Rejected verification request from {}", sender),
+            Err(e) => warn!(
+                "Failed to answer verification request from {}: {}",
+                sender, e
+            ),
+        }
+
+        if accepted {
+            tokio::spawn(self.clone().verify(client.clone(), request));
+        }
+    }
+
+    /// Carry an accepted request through to the end.
+    async fn verify(self, client: Client, request: VerificationRequest) {
+        let sender = request.other_user_id().to_owned();
+        let flow_id = request.flow_id().to_owned();
+        let admin = self.config.get().admins.contains(&sender);
+
+        let result = async {
+            let sas = sas_started(&request).await?;
+
+            compare_emoji(&sas, async |emoji| {
+                self.ask(&client, &sender, &flow_id, emoji, admin).await
+            })
+            .await
+        }
+        .await;
+
+        self.comparing.lock().unwrap().remove(&flow_id);
+
+        match result {
+            Ok(()) => info!("Verified the bot's device with {}", sender),
+            Err(e) => warn!("Failed to verify the bot's device with {}: {}", sender, e),
+        }
+    }
+
+    /// Post the emoji where they can be compared, to `sender` in a direct
+    /// chat if they're an `admin` themself and otherwise to the admin room,
+    /// then wait for an admin to say whether they match. No answer within
+    /// [`COMPARE_TIMEOUT`] is taken as no.
+    async fn ask(
+        &self,
+        client: &Client,
+        sender: &UserId,
+        flow_id: &str,
+        emoji: &str,
+        admin: bool,
+    ) -> bool {
+        let room = match admin {
+            true => match dm::dm_room(client, sender, self.encrypt_dms).await {
+                Ok(room) => Some(room),
+                Err(e) => {
+                    warn!("Failed to start a direct chat with {}: {}", sender, e);
+                    None
+                }
+            },
+            false => self
+                .admin_room
+                .as_ref()
+                .and_then(|room_id| client.get_room(room_id)),
+        };
+
+        let Some(room) = room else {
+            return false;
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.comparing
+            .lock()
+            .unwrap()
+            .insert(flow_id.to_owned(), tx);
+
+        let prompt = match admin {
+            true => format!(
+                "Check that your client shows these emoji:\n\n{}\n\nThen reply with \
+                 \"!llamaverify match {}\" or \"!llamaverify mismatch {}\".",
+                emoji, flow_id, flow_id
+            ),
+            false => format!(
+                "Ask {} whether their client shows these emoji:\n\n{}\n\nThen reply with \
+                 \"!llamaverify match {}\" or \"!llamaverify mismatch {}\".",
+                sender, emoji, flow_id, flow_id
+            ),
+        };
+
+        if let Err(e) = room
+            .send(RoomMessageEventContent::notice_plain(prompt))
+            .await
+        {
+            warn!("Failed to ask for the emoji to be compared: {}", e);
+            return false;
+        }
+
+        match timeout(COMPARE_TIMEOUT, rx).await {
+            Ok(answer) => answer.unwrap_or(false),
+            Err(_) => {
+                warn!("No one compared the emoji for {} in time", flow_id);
+                false
+            }
+        }
+    }
+
+    /// Pass on an admin's comparison of a verification's emoji, returning a
+    /// description of what was done.
+    pub fn compare(&self, flow_id: &str, matches: bool) -> String {
+        let Some(tx) = self.comparing.lock().unwrap().remove(flow_id) else {
+            return format!("There are no emoji to compare for {}", flow_id);
+        };
+
+        let _ = tx.send(matches);
+
+        match matches {
+            true => "Confirmed the emoji match".to_owned(),
+            false => "Told the other side the emoji don't match".to_owned(),
+        }
+    }
+
+    /// Decide what to do with a new verification request.
+    pub async fn on_request(&self, client: &Client, sender: &UserId, flow_id: &str) {
+        if self.config.get().admins.contains(sender) && self.policy != VerificationPolicy::Reject {
+            self.accept(client, sender, flow_id, true).await;
+            return;
+        }
+
+        let admin_room = match self.policy {
+            VerificationPolicy::Prompt => self
+                .admin_room
+                .as_ref()
+                .and_then(|room_id| client.get_room(room_id)),
+            _ => None,
+        };
+
+        let Some(admin_room) = admin_room else {
+            self.accept(client, sender, flow_id, false).await;
+            return;
+        };
+
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(flow_id.to_owned(), sender.to_owned());
+
+        let prompt = format!(
+            "{} has asked to verify the bot's device. Reply with \
+             \"!llamaverify accept {}\" or \"!llamaverify reject {}\".",
+            sender, flow_id, flow_id
+        );
+
+        if let Err(e) = admin_room
+            .send(RoomMessageEventContent::notice_plain(prompt))
+            .await
+        {
+            warn!("Failed to ask the admin room about verification: {}", e);
+        }
+    }
+
+    /// Carry out an admin's decision on a prompted request, returning a
+    /// description of what was done.
+    pub async fn decide(&self, client: &Client, flow_id: &str, accept: bool) -> String {
+        let Some(sender) = self.pending.lock().unwrap().remove(flow_id) else {
+            return format!("There is no pending verification request {}", flow_id);
+        };
+
+        self.accept(client, &sender, flow_id, accept).await;
+
+        match accept {
+            true => format!("Accepted the verification request from {}", sender),
+            false => format!("Rejected the verification request from {}", sender),
+        }
+    }
+}
+
+pub async fn on_to_device_request(
+    evt: ToDeviceKeyVerificationRequestEvent,
+    client: Client,
+    verifier: Ctx<Verifier>,
+) {
+    verifier
+        .on_request(&client, &evt.sender, evt.content.transaction_id.as_str())
+        .await;
+}
+
+/// Wait for the SAS verification that `request` leads to, once accepted, and
+/// accept it in turn.
+async fn sas_started(request: &VerificationRequest) -> Result<SasVerification> {
+    let mut changes = request.changes();
+    let mut state = request.state();
+
+    loop {
+        match state {
+            VerificationRequestState::Transitioned {
+                verification: Verification::SasV1(sas),
+                ..
+            } => {
+                if !sas.we_started() {
+                    sas.accept().await?;
+                }
+
+                return Ok(sas);
+            }
+            VerificationRequestState::Transitioned { .. } => {
+                request.cancel().await?;
+                bail!("Only verification by emoji is supported");
+            }
+            VerificationRequestState::Cancelled(info) => {
+                bail!("The verification was cancelled: {}", info.reason())
+            }
+            VerificationRequestState::Done => bail!("The verification ended without starting"),
+            _ => {}
+        }
+
+        let Some(next) = changes.next().await else {
+            bail!("The verification ended without starting");
+        };
+
+        state = next;
+    }
+}
+
+/// Follow `sas` through to the end, calling `compare` with the emoji to
+/// check once both sides have them. Whether they match, as `compare`
+/// decides, is sent to the other side.
+async fn compare_emoji<F>(sas: &SasVerification, compare: F) -> Result<()>
+where
+    F: AsyncFnOnce(&str) -> bool,
+{
+    let mut changes = sas.changes();
+    let mut compare = Some(compare);
+    let mut state = sas.state();
+
+    loop {
+        match state {
+            SasState::KeysExchanged {
+                emojis: Some(emojis),
+                ..
+            } => {
+                if let Some(compare) = compare.take() {
+                    match compare(&format_emojis(emojis.emojis)).await {
+                        true => sas.confirm().await?,
+                        false => {
+                            sas.mismatch().await?;
+                            bail!("The emoji didn't match");
+                        }
+                    }
+                }
+            }
+            SasState::KeysExchanged { emojis: None, .. } => {
+                sas.cancel().await?;
+                bail!("The other side can't show emoji to compare");
+            }
+            SasState::Done { .. } => return Ok(()),
+            SasState::Cancelled(info) => {
+                bail!("The verification was cancelled: {}", info.reason())
+            }
+            _ => {}
+        }
+
+        let Some(next) = changes.next().await else {
+            bail!("The verification ended unfinished");
+        };
+
+        state = next;
+    }
+}
+
+/// Ask a yes or no question on the terminal, taking anything but yes as no.
+async fn ask(question: &str) -> Result<bool> {
+    println!("{} [y/N]", question);
+
+    let mut answer = String::new();
+    BufReader::new(io::stdin()).read_line(&mut answer).await?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Verify the bot's device with whoever asks first from another client,
+/// for `llamatrix verify`. The request and the emoji are both checked with
+/// the person at the terminal.
+pub async fn verify_interactively(client: &Client) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let to_device = tx.clone();
+
+    client.add_event_handler(move |evt: ToDeviceKeyVerificationRequestEvent| {
+        let _ = to_device.send((evt.sender, evt.content.transaction_id.to_string()));
+        async {}
+    });
+    client.add_event_handler(move |evt: OriginalSyncRoomMessageEvent| {
+        if let MessageType::VerificationRequest(_) = &evt.content.msgtype {
+            let _ = tx.send((evt.sender, evt.event_id.to_string()));
+        }
+        async {}
+    });
+
+    let syncing = client.clone();
+    let sync = tokio::spawn(async move { syncing.sync(SyncSettings::default()).await });
+
+    println!(
+        "Waiting for a verification request. Verify {} from another client, its device is {}.",
+        client.user_id().unwrap(),
+        client.device_id().unwrap()
+    );
+
+    let result = loop {
+        let Some((sender, flow_id)) = rx.recv().await else {
+            break Ok(());
+        };
+
+        let Some(request) = client
+            .encryption()
+            .get_verification_request(&sender, &flow_id)
+            .await
+        else {
+            continue;
+        };
+
+        if !ask(&format!("{} has asked to verify the bot. Accept?", sender)).await? {
+            request.cancel().await?;
+            continue;
+        }
+
+        request.accept().await?;
+
+        println!("Waiting for {} to choose to compare emoji", sender);
+
+        let sas = sas_started(&request).await?;
+
+        break compare_emoji(&sas, async |emoji| {
+            println!(
+                "Check that the other client shows the same emoji:\n\n{}\n",
+                emoji
+            );
+            ask("Do they match?").await.unwrap_or(false)
+        })
+        .await;
+    };
+
+    sync.abort();
+    result?;
+
+    println!("The bot's device is verified");
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::{
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::time::sleep;
+
+use crate::Defaults;
+
+/// Loads the default model into ollama ahead of the prompts that need it, so
+/// that the first of them doesn't wait for it to load.
+#[derive(Clone)]
+pub struct Warmer {
+    defaults: Arc<RwLock<Defaults>>,
+    last_prompt: Arc<Mutex<Instant>>,
+    /// Set when an admin unloads the default model, so that it isn't loaded
+    /// again until a prompt needs it.
+    held: Arc<AtomicBool>,
+}
+
+impl Warmer {
+    pub fn new(defaults: Arc<RwLock<Defaults>>) -> Self {
+        Self {
+            defaults,
+            last_prompt: Arc::new(Mutex::new(Instant::now())),
+            held: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Note that a prompt has just come in.
+    pub fn touch(&self) {
+        *self.last_prompt.lock().unwrap() = Instant::now();
+        self.held.store(false, Ordering::Relaxed);
+    }
+
+    /// Note that `model` has been unloaded on purpose. If it's the default
+    /// model, it's left unloaded until the next prompt.
+    pub fn unloaded(&self, model: &str) {
+        if self.defaults.read().unwrap().model == model {
+            self.held.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn warm_up(&self) {
+        let defaults = self.defaults.read().unwrap().clone();
+        let started = Instant::now();
+
+        match defaults.backend.load(&defaults.model).await {
+            Ok(()) => info!(
+                "Loaded {} in {:.1}s",
+                defaults.model,
+                started.elapsed().as_secs_f64()
+            ),
+            Err(e) => warn!("Failed to load {}: {}", defaults.model, e),
+        }
+    }
+
+    /// Load the model now and, if `idle` is given, again whenever no prompt
+    /// has come in for that long, as ollama may have unloaded it since.
+    pub fn start(self, idle: Option<Duration>) {
+        tokio::spawn(async move {
+            self.warm_up().await;
+
+            let Some(idle) = idle else {
+                return;
+            };
+
+            loop {
+                let since = self.last_prompt.lock().unwrap().elapsed();
+
+                if since < idle {
+                    sleep(idle - since).await;
+                    continue;
+                }
+
+                if self.held.load(Ordering::Relaxed) {
+                    sleep(idle).await;
+                    continue;
+                }
+
+                self.warm_up().await;
+                self.touch();
+            }
+        });
+    }
+}
@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::tools::{Source, Tool, ToolOutput};
+
+/// How many results the model is given for each search.
+const RESULTS: usize = 5;
+
+/// How long a search may take, as the model, and the slot it holds, wait on
+/// it.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+const BRAVE_URL: &str = "https://api.search.brave.com/res/v1/web/search";
+
+/// Where searches are sent.
+pub enum Provider {
+    /// A SearxNG instance, with its JSON output format enabled.
+    Searxng(Url),
+    /// Brave's search API, with the given subscription token.
+    Brave(String),
+}
+
+/// A tool for the model to search the web with.
+pub struct WebSearch {
+    client: Client,
+    provider: Provider,
+}
+
+struct SearchResult {
+    title: String,
+    url: String,
+    snippet: String,
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    results: Vec<SearxngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResult {
+    title: String,
+    url: String,
+    #[serde(default)]
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct BraveResponse {
+    web: BraveResults,
+}
+
+#[derive(Deserialize)]
+struct BraveResults {
+    results: Vec<BraveResult>,
+}
+
+#[derive(Deserialize)]
+struct BraveResult {
+    title: String,
+    url: String,
+    #[serde(default)]
+    description: String,
+}
+
+impl WebSearch {
+    pub fn new(provider: Provider) -> Self {
+        Self {
+            client: Client::new(),
+            provider,
+        }
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        let results = match &self.provider {
+            Provider::Searxng(url) => self
+                .client
+                .get(url.join("/search").unwrap())
+                .query(&[("q", query), ("format", "json")])
+                .timeout(SEARCH_TIMEOUT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<SearxngResponse>()
+                .await?
+                .results
+                .into_iter()
+                .map(|r| SearchResult {
+                    title: r.title,
+                    url: r.url,
+                    snippet: r.content,
+                })
+                .collect::<Vec<_>>(),
+            Provider::Brave(key) => self
+                .client
+                .get(BRAVE_URL)
+                .query(&[("q", query)])
+                .header("X-Subscription-Token", key)
+                .header("Accept", "application/json")
+                .timeout(SEARCH_TIMEOUT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<BraveResponse>()
+                .await?
+                .web
+                .results
+                .into_iter()
+                .map(|r| SearchResult {
+                    title: r.title,
+                    url: r.url,
+                    snippet: r.description,
+                })
+                .collect(),
+        };
+
+        Ok(results.into_iter().take(RESULTS).collect())
+    }
+}
+
+impl Tool for WebSearch {
+    fn name(&self) -> &str {
+        "web_search"
+    }
+
+    fn description(&self) -> &str {
+        "Search the web, for questions about current events or anything else \
+         that may have changed since you were trained"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "What to search for" },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn call(&self, args: Value) -> BoxFuture<'_, Result<ToolOutput>> {
+        Box::pin(async move {
+            let query = args["query"].as_str().context("No query given")?;
+            let results = self.search(query).await?;
+
+            if results.is_empty() {
+                return Ok("The search found nothing".to_owned().into());
+            }
+
+            let content = results
+                .iter()
+                .enumerate()
+                .map(|(i, r)| format!("{}. {} ({}): {}", i + 1, r.title, r.url, r.snippet))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            Ok(ToolOutput {
+                content,
+                sources: results
+                    .into_iter()
+                    .map(|r| Source {
+                        title: r.title,
+                        url: r.url,
+                    })
+                    .collect(),
+            })
+        })
+    }
+}
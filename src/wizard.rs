@@ -0,0 +1,179 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+use crate::store::{RoomSettings, Trigger};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Model,
+    Persona,
+    Trigger,
+    Quota,
+}
+
+const STEPS: [Step; 4] = [Step::Model, Step::Persona, Step::Trigger, Step::Quota];
+
+/// A guided, question by question, configuration of a room's settings.
+pub struct Wizard {
+    /// The room being configured, which needn't be the one the questions are
+    /// asked in.
+    pub room_id: OwnedRoomId,
+    step: usize,
+    pub settings: RoomSettings,
+}
+
+/// What happened to a wizard after it was given an answer.
+pub enum Progress {
+    /// The answer was accepted or rejected; ask this next.
+    Ask(String),
+    /// Every question has been answered.
+    Done,
+}
+
+impl Wizard {
+    pub fn new(room_id: OwnedRoomId, settings: RoomSettings) -> Self {
+        Self {
+            room_id,
+            step: 0,
+            settings,
+        }
+    }
+
+    /// The current question, with the current value as a reminder.
+    pub fn question(&self, models: &HashSet<String>) -> String {
+        let n = self.step + 1;
+        let total = STEPS.len();
+
+        let question = match STEPS[self.step] {
+            Step::Model => {
+                let mut models: Vec<&str> = models.iter().map(String::as_str).collect();
+                models.sort();
+
+                format!(
+                    "Which model should answer in this room? Choose from: {}, or \"default\". \
+                     Currently: {}",
+                    models.join(", "),
+                    self.settings.model.as_deref().unwrap_or("default")
+                )
+            }
+            Step::Persona => format!(
+                "What persona should the bot take on? Describe it as instructions to the \
+                 model, or answer \"none\". Currently: {}",
+                self.settings.persona.as_deref().unwrap_or("none")
+            ),
+            Step::Trigger => format!(
+                "Should the bot answer only messages starting with !llama (\"prefix\"), or \
+                 every message (\"all\")? Currently: {}",
+                match self.settings.trigger {
+                    Trigger::Prefix => "prefix",
+                    Trigger::All => "all",
+                }
+            ),
+            Step::Quota => format!(
+                "How many prompts may each user send per hour? Answer with a number, or \
+                 \"none\" for no limit. Currently: {}",
+                self.settings
+                    .quota
+                    .map(|q| q.to_string())
+                    .unwrap_or_else(|| "none".to_owned())
+            ),
+        };
+
+        format!(
+            "({}/{}) {}\n\nAnswer \"skip\" to keep the current value, or \"cancel\" to stop.",
+            n, total, question
+        )
+    }
+
+    /// Apply the answer to the current question and move on.
+    pub fn answer(&mut self, answer: &str, models: &HashSet<String>) -> Progress {
+        let answer = answer.trim();
+
+        if answer != "skip" {
+            let accepted = match STEPS[self.step] {
+                Step::Model => match answer {
+                    "default" => {
+                        self.settings.model = None;
+                        true
+                    }
+                    model if models.contains(model) => {
+                        self.settings.model = Some(model.to_owned());
+                        true
+                    }
+                    _ => false,
+                },
+                Step::Persona => {
+                    self.settings.persona = match answer {
+                        "none" => None,
+                        persona => Some(persona.to_owned()),
+                    };
+                    self.settings.named_persona = None;
+                    true
+                }
+                Step::Trigger => match answer {
+                    "prefix" => {
+                        self.settings.trigger = Trigger::Prefix;
+                        true
+                    }
+                    "all" => {
+                        self.settings.trigger = Trigger::All;
+                        true
+                    }
+                    _ => false,
+                },
+                Step::Quota => match answer {
+                    "none" => {
+                        self.settings.quota = None;
+                        true
+                    }
+                    n => match n.parse() {
+                        Ok(quota) => {
+                            self.settings.quota = Some(quota);
+                            true
+                        }
+                        Err(_) => false,
+                    },
+                },
+            };
+
+            if !accepted {
+                return Progress::Ask(format!(
+                    "Sorry, I didn't understand that.\n\n{}",
+                    self.question(models)
+                ));
+            }
+        }
+
+        self.step += 1;
+
+        match self.step < STEPS.len() {
+            true => Progress::Ask(self.question(models)),
+            false => Progress::Done,
+        }
+    }
+}
+
+/// The wizards in progress, keyed by the room the questions are asked in and
+/// the user answering them.
+#[derive(Clone, Default)]
+pub struct Wizards(Arc<Mutex<HashMap<(OwnedRoomId, OwnedUserId), Wizard>>>);
+
+impl Wizards {
+    pub fn start(&self, dm: &RoomId, user: &UserId, wizard: Wizard) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert((dm.to_owned(), user.to_owned()), wizard);
+    }
+
+    pub fn take(&self, dm: &RoomId, user: &UserId) -> Option<Wizard> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&(dm.to_owned(), user.to_owned()))
+    }
+}